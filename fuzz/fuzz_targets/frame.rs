@@ -0,0 +1,16 @@
+#![no_main]
+
+// A single frame through the parser, as `Http2Context::receive` hands it
+// once `frame_len` says it is complete.
+
+use khttp::http2::frames;
+use kparser::http2::Frame;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(len) = frames::frame_len(data) {
+        if len <= data.len() {
+            let _ = <Frame as TryFrom<&[u8]>>::try_from(&data[..len]);
+        }
+    }
+});
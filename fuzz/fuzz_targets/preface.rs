@@ -0,0 +1,12 @@
+#![no_main]
+
+// Arbitrary bytes where the connection preface is expected.
+
+use khttp::http2::session::Http2Session;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut session = Http2Session::new();
+    let _ = session.receive(data);
+    let _ = session.take_outbound();
+});
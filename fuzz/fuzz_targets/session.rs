@@ -0,0 +1,41 @@
+#![no_main]
+
+// Frames after a valid preface, fed in chunks whose sizes come from the
+// input, with every request answered so that responses are encoded too.
+
+use http::Response;
+use khttp::http2::{
+    frames::PREFACE,
+    session::{Http2Session, SessionEvent},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (chunking, frames) = match data.split_first() {
+        Some((chunking, frames)) => (*chunking as usize + 1, frames),
+        None => return,
+    };
+    let mut session = Http2Session::new().stream_data(chunking % 2 == 0);
+    if session.receive(PREFACE).is_err() {
+        return;
+    }
+    for chunk in frames.chunks(chunking) {
+        let events = match session.receive(chunk) {
+            Ok(events) => events,
+            Err(_) => break,
+        };
+        for event in events {
+            if let SessionEvent::Request { stream_id, request } = event {
+                let response = Response::new(request.into_body());
+                if session.send_response(stream_id, response).is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = session.poll_bodies();
+        let _ = session.take_outbound();
+        if session.is_closed() {
+            break;
+        }
+    }
+});
@@ -1,8 +1,34 @@
+pub mod body;
+pub mod connection;
+#[cfg(any(feature = "gzip", feature = "deflate", feature = "brotli"))]
+pub mod compression;
 pub mod context;
+pub mod frames;
+pub mod grpc;
+pub mod keylog;
+pub mod limits;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
+pub mod proxy;
+pub mod session;
+pub mod sse;
+pub mod static_files;
 pub mod stream;
+pub mod transport;
+pub mod trace;
+pub mod tunnel;
+pub mod websocket;
 use http::{Request, Response};
 use kparser::u31::u31;
+pub use body::*;
+pub use connection::*;
+pub use listener::*;
+pub use middleware::*;
 pub use stream::*;
+pub use transport::*;
+pub use tunnel::*;
 
 use std::{
     collections::HashMap,
@@ -10,18 +36,30 @@ use std::{
     fmt::{Debug, Display},
     io::{self, Read, Write},
     net::{Shutdown, SocketAddr, ToSocketAddrs},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{self, Command},
     os::fd::{AsFd, AsRawFd, FromRawFd, IntoRawFd, RawFd},
     rc::Weak,
-    result, time,
+    result,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time,
 };
 
 use context::Http2Context;
+use limits::{Limiter, Limits, RateLimitAction};
+use logging::{log_debug, log_error, log_warn};
+use metrics::Metrics;
+use trace::FrameTracer;
 use id_pool::IdPool;
 use mio::{
     event::{Event, Source},
     net::{TcpListener, TcpStream, UnixStream},
     unix::SourceFd,
-    Events, Interest, Poll, Registry, Token,
+    Events, Interest, Poll, Registry, Token, Waker,
 };
 
 #[derive(Debug)]
@@ -36,120 +74,622 @@ impl From<std::io::Error> for Http2Error {
     }
 }
 
-const LISTENRE_TOKEN: Token = Token(0);
+/// Tokens below this one are those of the listeners.
+const MAX_LISTENERS: usize = 64;
+/// Each listener hands out the tokens of its connections and their sources
+/// from its own range, see `Http2Server::listener_index`.
+const TOKENS_PER_LISTENER: usize = (usize::MAX - MAX_LISTENERS) / MAX_LISTENERS;
+const TICK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// How long a refused connection is kept open to read what the client still
+/// sends, closing it with unread input would reset it before the GOAWAY is
+/// read.
+const LINGER_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+const MAX_LINGERING: usize = 1024;
+/// Token of the `Waker` of `ShutdownHandle`, past the range of the last
+/// listener.
+const SHUTDOWN: Token = Token(usize::MAX);
+
+struct ListenerEntry {
+    listener: Listener,
+    handler: Option<Box<dyn Handler>>,
+    id_pool: IdPool,
+}
 
 pub struct Http2Server {
-    listener: TcpListener,
-    connections: HashMap<Token, Http2Context>,
+    listeners: Vec<ListenerEntry>,
+    connections: HashMap<Token, Http2Context<Socket>>,
+    sources: HashMap<Token, (Token, u31)>,
+    metrics: Arc<Metrics>,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+    tracer: Option<Arc<dyn FrameTracer>>,
+    limiter: Limiter,
+    next_connection_id: u64,
+    lingering: Vec<(Socket, time::Instant)>,
+    shutdown: ShutdownHandle,
+}
+
+/// Stops a running `Http2Server` from another thread, see
+/// `Http2Server::shutdown_handle`.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl ShutdownHandle {
+    /// Makes `serve` send GOAWAY on every connection, close them and return.
+    /// Takes effect on the next call when the server isn't running.
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Ok(waker) = self.waker.lock() {
+            if let Some(waker) = waker.as_ref() {
+                let _ = waker.wake();
+            }
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    fn set_waker(&self, waker: Option<Waker>) {
+        if let Ok(mut current) = self.waker.lock() {
+            *current = waker;
+        }
+    }
 }
 
 impl Http2Server {
-    pub fn from_listener(listener: TcpListener) -> Result<Self, Http2Error> {
-        let name = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            .to_string();
-        let fd = mio::net::UnixStream::connect(format!("/tmp/{}", name))?;
-        Ok(Self {
-            listener,
+    pub fn from_listener<L: Into<Listener>>(listener: L) -> Result<Self, Http2Error> {
+        Self::from_listeners(vec![listener.into()])
+    }
+
+    /// Serves all of `listeners` from the same event loop.
+    pub fn from_listeners(listeners: Vec<Listener>) -> Result<Self, Http2Error> {
+        if listeners.is_empty() {
+            return Err(Http2Error::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No listener given",
+            )));
+        }
+        let mut server = Self {
+            listeners: Vec::new(),
             connections: HashMap::new(),
-        })
+            sources: HashMap::new(),
+            metrics: Metrics::new(),
+            hooks: None,
+            tracer: None,
+            limiter: Limiter::new(Limits::new()),
+            next_connection_id: 0,
+            lingering: Vec::new(),
+            shutdown: ShutdownHandle::default(),
+        };
+        for listener in listeners {
+            server.add_listener(listener)?;
+        }
+        Ok(server)
+    }
+
+    /// Binds every resolved address, e.g. both `127.0.0.1` and `::1` for
+    /// `localhost`.
+    pub fn bind_all<A: ToSocketAddrs>(address: A) -> Result<Self, Http2Error> {
+        let listeners = address
+            .to_socket_addrs()?
+            .map(|addr| Ok(Listener::Tcp(TcpListener::bind(addr)?)))
+            .collect::<Result<Vec<Listener>, Http2Error>>()?;
+        Self::from_listeners(listeners)
+    }
+
+    /// Serves one more listener with the handler given to `serve`, returns
+    /// its index.
+    pub fn add_listener<L: Into<Listener>>(&mut self, listener: L) -> Result<usize, Http2Error> {
+        self.push_listener(listener.into(), None)
+    }
+
+    /// Serves one more listener with its own handler, e.g. an admin port.
+    pub fn add_listener_with_handler<L: Into<Listener>, H: Handler + 'static>(
+        &mut self,
+        listener: L,
+        handler: H,
+    ) -> Result<usize, Http2Error> {
+        self.push_listener(listener.into(), Some(Box::new(handler)))
+    }
+
+    fn push_listener(
+        &mut self,
+        listener: Listener,
+        handler: Option<Box<dyn Handler>>,
+    ) -> Result<usize, Http2Error> {
+        let index = self.listeners.len();
+        if index >= MAX_LISTENERS {
+            return Err(Http2Error::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("At most {} listeners are supported", MAX_LISTENERS),
+            )));
+        }
+        let start = MAX_LISTENERS + index * TOKENS_PER_LISTENER;
+        self.listeners.push(ListenerEntry {
+            listener,
+            handler,
+            id_pool: IdPool::new_ranged(start..start + TOKENS_PER_LISTENER),
+        });
+        Ok(index)
+    }
+
+    /// The index of the listener that accepted the connection of `token`.
+    pub fn listener_index(token: Token) -> Option<usize> {
+        match token.0 {
+            id if id < MAX_LISTENERS => None,
+            id => Some((id - MAX_LISTENERS) / TOKENS_PER_LISTENER),
+        }
+    }
+
+    /// Serves connections on a Unix socket at `path`, handlers find the
+    /// client's `PeerCredentials` in the request extensions.
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> Result<Self, Http2Error> {
+        Self::from_listener(Listener::bind_unix(path)?)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_abstract(name: &[u8]) -> Result<Self, Http2Error> {
+        Self::from_listener(Listener::bind_abstract(name)?)
+    }
+
+    /// Adopts the sockets of systemd socket activation, see
+    /// `Listener::from_systemd`.
+    pub fn from_systemd() -> Result<Self, Http2Error> {
+        let listeners = Listener::from_systemd()?;
+        if listeners.is_empty() {
+            return Err(Http2Error::IOError(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No socket passed through LISTEN_FDS",
+            )));
+        }
+        Self::from_listeners(listeners)
+    }
+
+    /// The listening sockets, in the order they were given.
+    pub fn listener_fds(&self) -> Vec<RawFd> {
+        self.listeners
+            .iter()
+            .map(|entry| entry.listener.as_raw_fd())
+            .collect()
+    }
+
+    /// Replaces the process with `command`, passing it the listening sockets
+    /// the way systemd does so that `from_systemd` adopts them. Connections
+    /// waiting to be accepted are kept, open ones are closed.
+    ///
+    /// Only returns on failure.
+    pub fn exec_successor(&self, mut command: Command) -> io::Error {
+        let fds = self.listener_fds();
+        // Nothing may allocate between fork and exec.
+        let mut moved = vec![-1; fds.len()];
+        command
+            .env("LISTEN_PID", process::id().to_string())
+            .env("LISTEN_FDS", fds.len().to_string())
+            .env_remove("LISTEN_FDNAMES");
+        unsafe {
+            command.pre_exec(move || {
+                // Move the sockets out of the way first, the target numbers
+                // may already be taken by one of them.
+                for (i, fd) in fds.iter().enumerate() {
+                    let target = 3 + fds.len() as RawFd;
+                    let copy = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, target);
+                    if copy < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    moved[i] = copy;
+                }
+                // dup2 clears FD_CLOEXEC on the copies the successor inherits.
+                for (i, fd) in moved.iter().enumerate() {
+                    if libc::dup2(*fd, 3 + i as RawFd) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        command.exec()
+    }
+
+    /// Counters shared by all connections of this server, see
+    /// `metrics::PrometheusEndpoint` to serve them.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn set_hooks<H: ConnectionHooks + 'static>(&mut self, hooks: H) {
+        self.hooks = Some(Arc::new(hooks));
+    }
+
+    /// Traces the frames of the connections `tracer` is enabled for, see
+    /// `trace::VerboseTracer` and `trace::CaptureWriter`.
+    pub fn set_tracer<T: FrameTracer + 'static>(&mut self, tracer: T) {
+        self.tracer = Some(Arc::new(tracer));
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limiter = Limiter::new(limits);
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     pub fn listen(&mut self, on_message: fn(Token, Request<Vec<u8>>) -> Response<Vec<u8>>) -> Result<(), Http2Error> {
+        self.serve(on_message)
+    }
+
+    pub fn serve<H: Handler>(&mut self, handler: H) -> Result<(), Http2Error> {
         let mut poll = Poll::new()?;
-        let fd = self.listener.as_raw_fd();
-        let mut fd = SourceFd(&fd);
-        poll.registry()
-            .register(&mut fd, LISTENRE_TOKEN, Interest::READABLE)?;
+        for (i, entry) in self.listeners.iter().enumerate() {
+            let fd = entry.listener.as_raw_fd();
+            poll.registry()
+                .register(&mut SourceFd(&fd), Token(i), Interest::READABLE)?;
+        }
+        self.shutdown
+            .set_waker(Some(Waker::new(poll.registry(), SHUTDOWN)?));
 
-        let mut id_pool = IdPool::new();
+        let mut last_tick = time::Instant::now();
 
         loop {
+            if self.shutdown.is_requested() {
+                return self.stop(poll.registry());
+            }
             let mut events = Events::with_capacity(128);
-            poll.poll(&mut events, None)?;
+            poll.poll(&mut events, Some(TICK_INTERVAL))?;
             for event in &events {
                 match event.token() {
-                    LISTENRE_TOKEN => {
+                    SHUTDOWN => {}
+                    token if token.0 < self.listeners.len() => {
                         if event.is_readable() {
-                            let (mut tcp_stream, addr) = self.listener.accept()?;
-                            let id =
-                                match id_pool.request_id().ok_or(Http2Error::MaxActiveConnection) {
-                                    Ok(id) => id,
-                                    Err(e) => {
-                                        eprintln!("Max Active Connection Reached");
-                                        let _ = tcp_stream
-                                            .write("Max Active Connection Reached".as_bytes());
-                                        let _ = tcp_stream.shutdown(Shutdown::Both);
-                                        continue;
-                                    }
-                                };
-                            let token = Token(id);
-                            let mut context = Http2Context::new(tcp_stream, None, None);
-                            poll.registry().register(
-                                &mut context,
-                                token,
-                                Interest::READABLE | Interest::WRITABLE,
-                            )?;
-                            self.connections.insert(token, context);
+                            self.accept_connections(poll.registry(), token.0)?;
                         }
                     }
                     token => {
+                        if let Some((connection, stream_id)) = self.sources.get(&token).cloned() {
+                            let context = match self.connections.get_mut(&connection) {
+                                Some(context) => context,
+                                None => {
+                                    self.sources.remove(&token);
+                                    continue;
+                                }
+                            };
+                            let readable = event.is_readable() || event.is_read_closed();
+                            let writable = event.is_writable() || event.is_error();
+                            match context.handle_source_event(stream_id, readable, writable) {
+                                Ok(()) => self.sync_sources(poll.registry(), connection),
+                                Err(e) => {
+                                    log_error!("{}", e);
+                                    let reason = CloseReason::from(&e);
+                                    self.close_connection(poll.registry(), connection, reason)?;
+                                }
+                            }
+                            continue;
+                        }
+
                         let context = match self.connections.get_mut(&token) {
                             Some(context) => context,
                             None => {
                                 self.connections.remove(&token);
-                                eprintln!("Now Such Context Found");
+                                log_error!("No context found for {:?}", token);
                                 continue;
                             }
                         };
+                        let _span = logging::connection_span(token, context.peer_addr());
 
                         if event.is_readable() {
                             match context.handle_read(false) {
                                 Ok(streams) => {
+                                    // Rate limited as soon as the headers are in,
+                                    // before the body is received.
+                                    let mut refused = Vec::new();
+                                    for stream_id in context.opened_streams().to_vec() {
+                                        let retry_after = match self.limiter.allow_stream(token) {
+                                            Ok(()) => continue,
+                                            Err(retry_after) => retry_after,
+                                        };
+                                        log_debug!("Stream rate limit reached on {:?}", token);
+                                        let result = match self.limiter.action() {
+                                            RateLimitAction::Refuse => {
+                                                context.reset_stream(stream_id, frames::REFUSED_STREAM)
+                                            }
+                                            RateLimitAction::TooManyRequests => context
+                                                .reject_request(
+                                                    stream_id,
+                                                    too_many_requests(retry_after),
+                                                ),
+                                        };
+                                        if let Err(e) = result {
+                                            log_error!("{}", e);
+                                        }
+                                        refused.push(stream_id);
+                                    }
                                     for stream in streams {
-                                        on_message(token, stream.into());
+                                        let stream_id = stream.get_stream_id();
+                                        if refused.contains(&stream_id) {
+                                            continue;
+                                        }
+                                        let _span = logging::stream_span(stream_id.to_u32());
+                                        let mut request: Request<Vec<u8>> = stream.into();
+                                        if let Some(addr) = context.peer_addr() {
+                                            request.extensions_mut().insert(RemoteAddr(addr));
+                                        }
+                                        if let Some(credentials) = context.peer_credentials() {
+                                            request.extensions_mut().insert(credentials);
+                                        }
+                                        request
+                                            .extensions_mut()
+                                            .insert(context.connection_state().clone());
+                                        request
+                                            .extensions_mut()
+                                            .insert(StreamId(stream_id.to_u32()));
+                                        let start = time::Instant::now();
+                                        let handler: &dyn Handler = match Self::listener_index(token)
+                                            .and_then(|index| self.listeners.get(index))
+                                            .and_then(|entry| entry.handler.as_deref())
+                                        {
+                                            Some(handler) => handler,
+                                            None => &handler,
+                                        };
+                                        let response = handler.call(token, request);
+                                        self.metrics.request_handled(start.elapsed());
+                                        if let Err(e) = context.send_http_response(stream_id, response) {
+                                            log_error!("{}", e);
+                                        }
                                     }
                                 }
                                 Err(e) => {
-                                    eprintln!("Error : {}", e);
-                                    poll.registry().deregister(context)?;
-                                    self.connections.remove(&token);
+                                    log_error!("{}", e);
+                                    let reason = CloseReason::from(&e);
+                                    self.close_connection(poll.registry(), token, reason)?;
+                                    continue;
                                 }
                             }
                         }
 
-                        if event.is_writable() {}
+                        if event.is_writable() {
+                            if let Err(e) = context.pump_outgoing() {
+                                log_error!("{}", e);
+                                let reason = CloseReason::from(&e);
+                                self.close_connection(poll.registry(), token, reason)?;
+                                continue;
+                            }
+                        }
+
+                        self.sync_sources(poll.registry(), token);
                     }
                 }
             }
+
+            if last_tick.elapsed() >= TICK_INTERVAL {
+                last_tick = time::Instant::now();
+                self.tick(poll.registry())?;
+            }
         }
+    }
 
+    /// Sends GOAWAY on and closes every connection.
+    fn stop(&mut self, registry: &Registry) -> Result<(), Http2Error> {
+        self.shutdown.set_waker(None);
+        self.shutdown.requested.store(false, Ordering::SeqCst);
+        let tokens: Vec<Token> = self.connections.keys().cloned().collect();
+        for token in tokens {
+            if let Some(context) = self.connections.get_mut(&token) {
+                if let Err(e) = context.go_away(frames::NO_ERROR, b"server shutting down") {
+                    log_error!("{}", e);
+                }
+            }
+            self.close_connection(registry, token, CloseReason::Shutdown)?;
+        }
         Ok(())
     }
 
-    pub fn new<A: ToSocketAddrs>(address: A) -> Result<Self, Http2Error> {
-        for sock_addr in address.to_socket_addrs()? {
-            match TcpListener::bind(sock_addr) {
-                Ok(result) => {
-                    return Ok(Self {
-                        listener: result,
-                        connections: HashMap::new(),
-                    });
+    /// Accepts the connections waiting on the listener at `index`.
+    fn accept_connections(&mut self, registry: &Registry, index: usize) -> Result<(), Http2Error> {
+        loop {
+            let (socket, addr) = match self.listeners[index].listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log_error!("accept failed: {}", e);
+                    return Ok(());
                 }
-                Err(e) => return Err(Http2Error::IOError(e)),
             };
+            let ip = addr.map(|addr| addr.ip());
+            if !self.limiter.admit(ip) {
+                log_warn!("Connection limit reached, refusing {:?}", addr);
+                self.refuse_connection(socket, b"connection limit reached");
+                continue;
+            }
+            self.next_connection_id += 1;
+            let state = ConnectionState::new(self.next_connection_id, addr);
+            if let Some(hooks) = self.hooks.clone() {
+                if !hooks.on_connect(&state) {
+                    self.refuse_connection(socket, b"connection refused");
+                    let stats = ConnectionStats::default();
+                    hooks.on_close(&state, &CloseReason::Rejected, &stats);
+                    continue;
+                }
+            }
+            let id = match self.listeners[index].id_pool.request_id() {
+                Some(id) => id,
+                None => {
+                    log_warn!("Max Active Connection Reached, refusing {:?}", addr);
+                    self.refuse_connection(socket, b"connection limit reached");
+                    continue;
+                }
+            };
+            let token = Token(id);
+            let mut context = Http2Context::new(socket, None, None);
+            context.set_metrics(self.metrics.clone());
+            context.set_connection_state(state);
+            context.set_flood_limits(self.limiter.flood_limits().clone());
+            context.set_body_limits(self.limiter.body_limits().clone());
+            if let Some(hooks) = &self.hooks {
+                context.set_hooks(hooks.clone());
+            }
+            if let Some(tracer) = &self.tracer {
+                if tracer.enabled(context.connection_state()) {
+                    context.set_tracer(tracer.clone());
+                }
+            }
+            registry.register(&mut context, token, Interest::READABLE | Interest::WRITABLE)?;
+            self.connections.insert(token, context);
+            self.limiter.opened(token, ip);
+            log_debug!("accepted {:?} as {:?}", addr, token);
         }
-        Err(Http2Error::IOError(io::Error::new(
-            io::ErrorKind::AddrNotAvailable,
-            "No Valid Address where Found",
-        )))
     }
+
+    fn close_connection(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        reason: CloseReason,
+    ) -> Result<(), Http2Error> {
+        if let Some(mut context) = self.connections.remove(&token) {
+            if let Some(hooks) = &self.hooks {
+                hooks.on_close(context.connection_state(), &reason, &context.stats());
+            }
+            for source in context.source_tokens() {
+                self.sources.remove(&source);
+                release_token(&mut self.listeners, source);
+            }
+            registry.deregister(&mut context)?;
+            release_token(&mut self.listeners, token);
+            self.limiter.closed(token);
+            log_debug!("closed {:?}", token);
+        }
+        Ok(())
+    }
+
+    /// Pulls streamed bodies that wait on time rather than on a socket, such
+    /// as event stream heartbeats.
+    fn tick(&mut self, registry: &Registry) -> Result<(), Http2Error> {
+        self.limiter.prune();
+        self.drain_lingering();
+        let tokens: Vec<Token> = self.connections.keys().cloned().collect();
+        for token in tokens {
+            let result = match self.connections.get_mut(&token) {
+                Some(context) if context.has_outgoing() => context.pump_outgoing(),
+                _ => continue,
+            };
+            match result {
+                Ok(()) => self.sync_sources(registry, token),
+                Err(e) => {
+                    log_error!("{}", e);
+                    let reason = CloseReason::from(&e);
+                    self.close_connection(registry, token, reason)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a connection that won't be served with the server preface and a
+    /// GOAWAY, so the client knows none of its streams will be processed. The
+    /// socket lingers until the client is done sending, see `drain_lingering`.
+    fn refuse_connection(&mut self, mut socket: Socket, debug_data: &[u8]) {
+        let mut buffer = frames::encode_frame(frames::FRAME_SETTINGS, 0, 0, &[]);
+        buffer.extend(frames::encode_frame(
+            frames::FRAME_GOAWAY,
+            0,
+            0,
+            &frames::goaway_payload(0, frames::NO_ERROR, debug_data),
+        ));
+        if socket.write_all(&buffer).is_ok() {
+            self.metrics.frame_sent(&buffer[..9]);
+            self.metrics.frame_sent(&buffer[9..]);
+        }
+        let _ = socket.shutdown(Shutdown::Write);
+        if self.lingering.len() < MAX_LINGERING {
+            self.lingering.push((socket, time::Instant::now()));
+        }
+    }
+
+    /// Reads what refused clients still send, closing their sockets once they
+    /// are done or after `LINGER_TIMEOUT`.
+    fn drain_lingering(&mut self) {
+        let mut buffer = [0u8; 4096];
+        self.lingering.retain_mut(|(socket, since)| {
+            if since.elapsed() >= LINGER_TIMEOUT {
+                return false;
+            }
+            loop {
+                match socket.read(&mut buffer) {
+                    Ok(0) => return false,
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => return false,
+                }
+            }
+        });
+    }
+
+    /// Registers the sockets of bodies and tunnels opened by the last responses
+    /// of a connection and frees the tokens of the closed ones.
+    fn sync_sources(&mut self, registry: &Registry, token: Token) {
+        let context = match self.connections.get_mut(&token) {
+            Some(context) => context,
+            None => return,
+        };
+        let id_pool = match Self::listener_index(token).and_then(|index| self.listeners.get_mut(index)) {
+            Some(entry) => &mut entry.id_pool,
+            None => return,
+        };
+        for source in context.take_released_tokens() {
+            self.sources.remove(&source);
+            let _ = id_pool.return_id(source.0);
+        }
+        for stream_id in context.unregistered_sources() {
+            let id = match id_pool.request_id() {
+                Some(id) => id,
+                None => {
+                    let _ = context.reset_stream(stream_id, frames::REFUSED_STREAM);
+                    continue;
+                }
+            };
+            match context.register_source(stream_id, registry, Token(id)) {
+                Ok(()) => {
+                    self.sources.insert(Token(id), (token, stream_id));
+                }
+                Err(e) => {
+                    log_error!("{}", e);
+                    let _ = id_pool.return_id(id);
+                    let _ = context.reset_stream(stream_id, frames::CONNECT_ERROR);
+                }
+            }
+        }
+    }
+
+    pub fn new<A: ToSocketAddrs>(address: A) -> Result<Self, Http2Error> {
+        Self::from_listener(Listener::bind(address)?)
+    }
+}
+
+fn release_token(listeners: &mut [ListenerEntry], token: Token) {
+    if let Some(entry) = Http2Server::listener_index(token).and_then(|index| listeners.get_mut(index)) {
+        let _ = entry.id_pool.return_id(token.0);
+    }
+}
+
+fn too_many_requests(retry_after: time::Duration) -> Response<Vec<u8>> {
+    let seconds = u64::max(1, retry_after.as_secs_f64().ceil() as u64);
+    Response::builder()
+        .status(http::StatusCode::TOO_MANY_REQUESTS)
+        .header(http::header::RETRY_AFTER, seconds.to_string())
+        .body(Vec::new())
+        .unwrap()
 }
 
-impl From<RawFd> for Http2Server {
-    fn from(value: RawFd) -> Self {
-        todo!()
+/// Adopts an inherited listening socket, see `Listener::from_fd`.
+impl TryFrom<RawFd> for Http2Server {
+    type Error = Http2Error;
+
+    fn try_from(value: RawFd) -> Result<Self, Self::Error> {
+        Self::from_listener(Listener::from_fd(value)?)
     }
 }
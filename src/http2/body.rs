@@ -0,0 +1,390 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use http::{HeaderMap, HeaderName, HeaderValue, Response};
+use mio::{event::Source, unix::pipe};
+
+/// Bytes a queue holds at most for a client reading slower than they are sent.
+pub const MAX_QUEUED: usize = 1024 * 1024;
+
+/// One step of an outgoing response body.
+#[derive(Debug)]
+pub enum BodyChunk {
+    Data(Vec<u8>),
+    /// Nothing to send right now, the stream stays open.
+    Pending,
+    End,
+    /// Ends the stream with a trailing HEADERS frame.
+    Trailers(HeaderMap),
+}
+
+/// A response body that is produced piece by piece while the connection's and
+/// the stream's flow-control windows allow it.
+pub trait BodyStream: Send {
+    /// Returns at most `max_len` bytes.
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk>;
+
+    /// A socket the body is read from. It is registered in the server's `Poll`
+    /// so a `Pending` body is pulled again once the socket becomes readable.
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        None
+    }
+}
+
+/// In-memory body, used for plain `Response<Vec<u8>>` bodies.
+pub struct BytesBody {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl BytesBody {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data, position: 0 }
+    }
+}
+
+impl BodyStream for BytesBody {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        if self.position >= self.data.len() {
+            return Ok(BodyChunk::End);
+        }
+        let end = usize::min(self.position + max_len, self.data.len());
+        let chunk = self.data[self.position..end].to_vec();
+        self.position = end;
+        Ok(BodyChunk::Data(chunk))
+    }
+}
+
+/// Response extension carrying a streamed body.
+///
+/// A handler inserts it with `response.extensions_mut().insert(...)`; the server
+/// then sends the response headers and pulls DATA frames out of the stream
+/// instead of sending `response.body()`.
+#[derive(Clone)]
+pub struct StreamingBody(Arc<Mutex<Option<Box<dyn BodyStream>>>>);
+
+impl StreamingBody {
+    pub fn new<B: BodyStream + 'static>(body: B) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(body)))))
+    }
+
+    pub fn take(&self) -> Option<Box<dyn BodyStream>> {
+        match self.0.lock() {
+            Ok(mut body) => body.take(),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Debug for StreamingBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StreamingBody")
+    }
+}
+
+/// A response that isn't known yet when the handler returns, such as one
+/// relayed from another server. It is polled like a `Pending` body: when its
+/// `source` becomes ready and on every tick of the server.
+pub trait DeferredResponse: Send {
+    /// The response once it is ready, an error is answered with
+    /// `502 Bad Gateway`.
+    fn poll_response(&mut self) -> io::Result<Option<Response<Vec<u8>>>>;
+
+    /// A socket the response is read from. A streamed body of the returned
+    /// response keeps its registration, so it has to read from the same one.
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        None
+    }
+}
+
+/// Response extension standing for a `DeferredResponse`, the rest of the
+/// response it is inserted in is ignored.
+#[derive(Clone)]
+pub struct Deferred(Arc<Mutex<Option<Box<dyn DeferredResponse>>>>);
+
+impl Deferred {
+    pub fn new<D: DeferredResponse + 'static>(response: D) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(response)))))
+    }
+
+    pub fn take(&self) -> Option<Box<dyn DeferredResponse>> {
+        match self.0.lock() {
+            Ok(mut response) => response.take(),
+            Err(_) => None,
+        }
+    }
+
+    /// A response deferred to `response`.
+    pub fn into_response<D: DeferredResponse + 'static>(response: D) -> Response<Vec<u8>> {
+        let mut result = Response::new(Vec::new());
+        result.extensions_mut().insert(Self::new(response));
+        result
+    }
+}
+
+impl Debug for Deferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Deferred")
+    }
+}
+
+struct QueueState<E> {
+    items: VecDeque<Vec<u8>>,
+    len: usize,
+    end: Option<E>,
+    ended: bool,
+    disconnected: bool,
+}
+
+/// Sending half of a queue drained by a body on the server's poll loop, for
+/// use from any thread. Each send wakes the loop through a pipe, whose reading
+/// end is the `source` of the body.
+pub struct QueueSender<E> {
+    state: Arc<Mutex<QueueState<E>>>,
+    waker: Arc<pipe::Sender>,
+}
+
+impl<E> Clone for QueueSender<E> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+impl<E> QueueSender<E> {
+    /// Refused with `WouldBlock` while `MAX_QUEUED` would be exceeded, and with
+    /// `BrokenPipe` once the queue ended or its receiver was dropped.
+    pub fn send(&self, item: Vec<u8>) -> io::Result<()> {
+        self.push(Some(item), None, true)
+    }
+
+    /// Sends `item` and ends the queue with `end` at once.
+    pub fn send_last(&self, item: Vec<u8>, end: E) -> io::Result<()> {
+        self.push(Some(item), Some(end), true)
+    }
+
+    /// Ends the queue once everything sent so far was received, only the
+    /// first end counts.
+    pub fn end(&self, end: E) {
+        let _ = self.push(None, Some(end), false);
+    }
+
+    pub fn is_ended(&self) -> bool {
+        match self.state.lock() {
+            Ok(state) => state.ended,
+            Err(_) => true,
+        }
+    }
+
+    /// The receiver was dropped, e.g. its stream was reset.
+    pub fn is_disconnected(&self) -> bool {
+        match self.state.lock() {
+            Ok(state) => state.disconnected,
+            Err(_) => true,
+        }
+    }
+
+    /// Like `send` without the size limit, for callbacks running on the poll
+    /// loop, which drains the queue as soon as they return.
+    pub(crate) fn send_unbounded(&self, item: Vec<u8>, end: Option<E>) -> io::Result<()> {
+        self.push(Some(item), end, false)
+    }
+
+    fn push(&self, item: Option<Vec<u8>>, end: Option<E>, bounded: bool) -> io::Result<()> {
+        {
+            let mut state = self
+                .state
+                .lock()
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            if state.disconnected || state.ended {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if let Some(item) = item {
+                // The client reads slower than items are sent.
+                if bounded && state.len + item.len() > MAX_QUEUED {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                state.len += item.len();
+                state.items.push_back(item);
+            }
+            if end.is_some() {
+                state.end = end;
+                state.ended = true;
+            }
+        }
+        self.wake()
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        match (&*self.waker).write(&[1]) {
+            Ok(_) => Ok(()),
+            // The pipe is full, the body is already due to be pulled.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// One step of a `QueueReceiver`.
+#[derive(Debug)]
+pub enum Queued<E> {
+    Data(Vec<u8>),
+    /// Nothing was sent since the last call.
+    Pending,
+    /// Returned once everything sent was received. `None` when every sender
+    /// was dropped without ending the queue.
+    End(Option<E>),
+}
+
+/// Receiving half of a queue, see `QueueSender`. Dropping it disconnects the
+/// senders.
+pub struct QueueReceiver<E> {
+    state: Arc<Mutex<QueueState<E>>>,
+    receiver: pipe::Receiver,
+    senders_dropped: bool,
+    finished: bool,
+}
+
+impl<E> QueueReceiver<E> {
+    pub fn new() -> io::Result<(QueueSender<E>, QueueReceiver<E>)> {
+        let (waker, receiver) = pipe::new()?;
+        let state = Arc::new(Mutex::new(QueueState {
+            items: VecDeque::new(),
+            len: 0,
+            end: None,
+            ended: false,
+            disconnected: false,
+        }));
+        let sender = QueueSender {
+            state: state.clone(),
+            waker: Arc::new(waker),
+        };
+        let receiver = QueueReceiver {
+            state,
+            receiver,
+            senders_dropped: false,
+            finished: false,
+        };
+        Ok((sender, receiver))
+    }
+
+    /// The next item, as it was sent.
+    pub fn next_item(&mut self) -> io::Result<Queued<E>> {
+        self.next(None)
+    }
+
+    /// Up to `max_len` bytes, items are split and joined as needed.
+    pub fn read(&mut self, max_len: usize) -> io::Result<Queued<E>> {
+        self.next(Some(max_len))
+    }
+
+    /// Reads the wake-ups, so that the pipe signals the next send again.
+    pub fn drain(&mut self) -> io::Result<()> {
+        let mut wakes = [0u8; 64];
+        loop {
+            match self.receiver.read(&mut wakes) {
+                // Every sender was dropped once the pipe is at its end.
+                Ok(0) => {
+                    self.senders_dropped = true;
+                    return Ok(());
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn source(&mut self) -> &mut pipe::Receiver {
+        &mut self.receiver
+    }
+
+    fn next(&mut self, max_len: Option<usize>) -> io::Result<Queued<E>> {
+        self.drain()?;
+        let mut guard = self
+            .state
+            .lock()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let state = &mut *guard;
+        match max_len {
+            None => {
+                if let Some(item) = state.items.pop_front() {
+                    state.len -= item.len();
+                    return Ok(Queued::Data(item));
+                }
+            }
+            Some(max_len) if max_len > 0 && !state.items.is_empty() => {
+                let mut data = Vec::new();
+                while data.len() < max_len {
+                    let item = match state.items.front_mut() {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    let len = usize::min(max_len - data.len(), item.len());
+                    data.extend(item.drain(..len));
+                    if item.is_empty() {
+                        state.items.pop_front();
+                    }
+                }
+                state.len -= data.len();
+                return Ok(Queued::Data(data));
+            }
+            Some(_) => {}
+        }
+        if self.finished || !state.items.is_empty() {
+            return Ok(Queued::Pending);
+        }
+        if state.ended || self.senders_dropped {
+            self.finished = true;
+            return Ok(Queued::End(state.end.take()));
+        }
+        Ok(Queued::Pending)
+    }
+}
+
+impl<E> Drop for QueueReceiver<E> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.disconnected = true;
+        }
+    }
+}
+
+/// Trailing headers, sent or received in a HEADERS frame after the body.
+///
+/// Received trailers are found in the request's extensions. Inserting them in a
+/// response's extensions sends them after the response body.
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(pub HeaderMap);
+
+impl Trailers {
+    /// Builds trailers out of decoded header pairs, skipping invalid entries
+    /// and pseudo-headers, which are not allowed in trailers.
+    pub fn from_list(list: &[(Vec<u8>, Vec<u8>)]) -> Self {
+        let mut map = HeaderMap::new();
+        for (key, value) in list {
+            let key = match HeaderName::from_bytes(key) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if let Ok(value) = HeaderValue::from_bytes(value) {
+                map.append(key, value);
+            }
+        }
+        Self(map)
+    }
+
+    pub fn to_list(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str().as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+}
@@ -0,0 +1,578 @@
+use std::io::{self, Write};
+#[cfg(feature = "gzip")]
+use std::io::Read;
+
+use http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode};
+use mio::{event::Source, Token};
+
+use super::{BodyChunk, BodyStream, Handler, Middleware, StreamingBody};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => "deflate",
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// Encodings compiled in, in order of preference.
+    fn supported() -> Vec<Encoding> {
+        vec![
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli,
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip,
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate,
+        ]
+    }
+}
+
+/// Picks the best supported encoding out of an `accept-encoding` value.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut accepted: Vec<(&str, f32)> = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let mut quality = 1.0;
+        for param in parts {
+            if let Some(value) = param.trim().strip_prefix("q=") {
+                quality = value.trim().parse().unwrap_or(0.0);
+            }
+        }
+        if name == "*" {
+            wildcard = Some(quality);
+        } else {
+            accepted.push((name, quality));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in Encoding::supported() {
+        let quality = accepted
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.as_str()))
+            .map(|(_, quality)| *quality)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+enum Encoder {
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    #[cfg(feature = "deflate")]
+    Deflate(flate2::write::ZlibEncoder<Vec<u8>>),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, level: u32) -> Self {
+        match encoding {
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level),
+            )),
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => Encoder::Deflate(flate2::write::ZlibEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level),
+            )),
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                level,
+                22,
+            ))),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(encoder) => encoder.write_all(data),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Compresses what was written so far, ending on a byte boundary the
+    /// client can decode up to.
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(encoder) => encoder.flush(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+
+    /// Takes whatever compressed output is available so far.
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(encoder) => std::mem::take(encoder.get_mut()),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "deflate")]
+            Encoder::Deflate(encoder) => encoder.finish(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+/// Compresses a streamed body on the fly.
+struct CompressedBody {
+    inner: Box<dyn BodyStream>,
+    encoder: Option<Encoder>,
+    pending: Vec<u8>,
+    trailers: Option<HeaderMap>,
+    /// Whether data was written since the encoder was last flushed.
+    unflushed: bool,
+}
+
+impl BodyStream for CompressedBody {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        while self.pending.is_empty() {
+            let encoder = match self.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => {
+                    return Ok(match self.trailers.take() {
+                        Some(trailers) => BodyChunk::Trailers(trailers),
+                        None => BodyChunk::End,
+                    })
+                }
+            };
+            match self.inner.next_chunk(max_len)? {
+                BodyChunk::Data(data) => {
+                    encoder.write(&data)?;
+                    self.unflushed |= !data.is_empty();
+                    self.pending = encoder.take_output();
+                }
+                BodyChunk::Pending => {
+                    // What the inner body produced so far is sent before
+                    // waiting for more, server-sent events would otherwise
+                    // sit in the encoder.
+                    if self.unflushed {
+                        encoder.flush()?;
+                        self.unflushed = false;
+                        self.pending = encoder.take_output();
+                    }
+                    if self.pending.is_empty() {
+                        return Ok(BodyChunk::Pending);
+                    }
+                }
+                BodyChunk::End => {
+                    self.pending = self.encoder.take().unwrap().finish()?;
+                }
+                BodyChunk::Trailers(trailers) => {
+                    self.pending = self.encoder.take().unwrap().finish()?;
+                    self.trailers = Some(trailers);
+                }
+            }
+        }
+        let len = usize::min(max_len, self.pending.len());
+        Ok(BodyChunk::Data(self.pending.drain(0..len).collect()))
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        self.inner.source()
+    }
+}
+
+/// Middleware compressing responses according to the request's
+/// `accept-encoding`, and decompressing gzip request bodies when enabled.
+pub struct Compression {
+    min_size: usize,
+    level: u32,
+    content_types: Vec<String>,
+    excluded_content_types: Vec<String>,
+    decompress_requests: bool,
+    max_decompressed_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {
+            min_size: 1024,
+            level: 6,
+            content_types: vec![
+                "text/".to_string(),
+                "application/json".to_string(),
+                "application/javascript".to_string(),
+                "application/xml".to_string(),
+                "image/svg+xml".to_string(),
+                "application/wasm".to_string(),
+            ],
+            excluded_content_types: vec!["text/event-stream".to_string()],
+            decompress_requests: false,
+            max_decompressed_size: 16 * 1024 * 1024,
+        }
+    }
+
+    /// Bodies smaller than this are sent as they are. Streamed bodies have no
+    /// known size and are always compressed.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Content type prefixes eligible for compression.
+    pub fn content_types(mut self, content_types: &[&str]) -> Self {
+        self.content_types = content_types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    /// Content type prefixes never compressed, even when `content_types`
+    /// matches. `text/event-stream` by default.
+    pub fn exclude_content_types(mut self, content_types: &[&str]) -> Self {
+        self.excluded_content_types = content_types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    pub fn decompress_requests(mut self, max_decompressed_size: usize) -> Self {
+        self.decompress_requests = true;
+        self.max_decompressed_size = max_decompressed_size;
+        self
+    }
+
+    fn is_eligible(&self, response: &Response<Vec<u8>>) -> bool {
+        if response.headers().contains_key(header::CONTENT_ENCODING)
+            || response.headers().contains_key(header::CONTENT_RANGE)
+            || response.status() == StatusCode::NO_CONTENT
+            || response.status() == StatusCode::NOT_MODIFIED
+        {
+            return false;
+        }
+        let content_type = match response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(content_type) => content_type.to_ascii_lowercase(),
+            None => return false,
+        };
+        let matches = |prefixes: &[String]| {
+            prefixes
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix.as_str()))
+        };
+        matches(&self.content_types) && !matches(&self.excluded_content_types)
+    }
+
+    /// The request with its body decoded, or the status to refuse it with.
+    fn decompress_request(
+        &self,
+        request: Request<Vec<u8>>,
+    ) -> Result<Request<Vec<u8>>, StatusCode> {
+        let is_gzip = request
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("gzip"));
+        if !is_gzip {
+            return Ok(request);
+        }
+
+        #[cfg(feature = "gzip")]
+        {
+            let mut request = request;
+            let mut body = Vec::new();
+            let limit = self.max_decompressed_size as u64 + 1;
+            flate2::read::GzDecoder::new(request.body().as_slice())
+                .take(limit)
+                .read_to_end(&mut body)
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            if body.len() > self.max_decompressed_size {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            request.headers_mut().remove(header::CONTENT_ENCODING);
+            request.headers_mut().remove(header::CONTENT_LENGTH);
+            *request.body_mut() = body;
+            Ok(request)
+        }
+
+        #[cfg(not(feature = "gzip"))]
+        {
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        }
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(
+        &self,
+        token: Token,
+        mut request: Request<Vec<u8>>,
+        next: &dyn Handler,
+    ) -> Response<Vec<u8>> {
+        if self.decompress_requests {
+            request = match self.decompress_request(request) {
+                Ok(request) => request,
+                Err(status) => return status_response(status),
+            };
+        }
+
+        let encoding = request
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .and_then(negotiate);
+        let is_head = request.method() == http::Method::HEAD;
+
+        let mut response = next.call(token, request);
+        if !self.is_eligible(&response) {
+            return response;
+        }
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+        let encoding = match encoding {
+            Some(encoding) if !is_head => encoding,
+            _ => return response,
+        };
+
+        let streaming = response.extensions_mut().remove::<StreamingBody>();
+        match streaming.and_then(|body| body.take()) {
+            Some(inner) => {
+                response.extensions_mut().insert(StreamingBody::new(CompressedBody {
+                    inner,
+                    encoder: Some(Encoder::new(encoding, self.level)),
+                    pending: Vec::new(),
+                    trailers: None,
+                    unflushed: false,
+                }));
+            }
+            None => {
+                if response.body().len() < self.min_size {
+                    return response;
+                }
+                let mut encoder = Encoder::new(encoding, self.level);
+                let compressed = encoder
+                    .write(response.body())
+                    .and_then(|_| encoder.finish());
+                match compressed {
+                    Ok(compressed) => *response.body_mut() = compressed,
+                    Err(_) => return response,
+                }
+            }
+        }
+
+        response.headers_mut().remove(header::CONTENT_LENGTH);
+        weaken_etag(response.headers_mut());
+        response.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        response
+    }
+}
+
+/// The compressed and the identity representations are no longer the same
+/// bytes, so they can't share a strong validator. Weak comparison still lets
+/// `If-None-Match` match, while `If-Range` never does.
+fn weaken_etag(headers: &mut HeaderMap) {
+    let weak = match headers.get(header::ETAG).and_then(|value| value.to_str().ok()) {
+        Some(etag) if !etag.starts_with("W/") => format!("W/{}", etag),
+        _ => return,
+    };
+    if let Ok(weak) = HeaderValue::from_str(&weak) {
+        headers.insert(header::ETAG, weak);
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .body(Vec::new())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays back `chunks`, then stays pending.
+    struct Scripted(Vec<BodyChunk>);
+
+    impl BodyStream for Scripted {
+        fn next_chunk(&mut self, _max_len: usize) -> io::Result<BodyChunk> {
+            if self.0.is_empty() {
+                return Ok(BodyChunk::Pending);
+            }
+            Ok(self.0.remove(0))
+        }
+    }
+
+    fn response(content_type: &str) -> Response<Vec<u8>> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(all(feature = "gzip", feature = "deflate", feature = "brotli"))]
+    fn negotiate_follows_q_values() {
+        assert_eq!(negotiate("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, GZIP;q=0.9"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*;q=0.5, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip;q=high"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn event_streams_are_not_compressed() {
+        let compression = Compression::new();
+        assert!(compression.is_eligible(&response("text/html; charset=utf-8")));
+        assert!(!compression.is_eligible(&response("text/event-stream")));
+        assert!(!compression.is_eligible(&response("image/png")));
+        let compression = compression.exclude_content_types(&[]);
+        assert!(compression.is_eligible(&response("text/event-stream")));
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn pending_bodies_are_flushed() {
+        let mut body = CompressedBody {
+            inner: Box::new(Scripted(vec![BodyChunk::Data(b"data: 1\n\n".to_vec())])),
+            encoder: Some(Encoder::new(Encoding::Gzip, 6)),
+            pending: Vec::new(),
+            trailers: None,
+            unflushed: false,
+        };
+        let mut compressed = Vec::new();
+        while let BodyChunk::Data(data) = body.next_chunk(16384).unwrap() {
+            compressed.extend(data);
+        }
+        // Nothing more to flush, the body waits.
+        assert!(matches!(
+            body.next_chunk(16384).unwrap(),
+            BodyChunk::Pending
+        ));
+
+        let mut decoded = [0u8; 9];
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_exact(&mut decoded)
+            .unwrap();
+        assert_eq!(&decoded, b"data: 1\n\n");
+    }
+
+    #[cfg(feature = "gzip")]
+    fn gzip_request(data: &[u8]) -> Request<Vec<u8>> {
+        let mut encoder = Encoder::new(Encoding::Gzip, 6);
+        encoder.write(data).unwrap();
+        Request::builder()
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(encoder.finish().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn request_bodies_are_decompressed_up_to_the_limit() {
+        let compression = Compression::new().decompress_requests(100);
+        let request = compression
+            .decompress_request(gzip_request(&[b'a'; 100]))
+            .unwrap();
+        assert_eq!(request.body().as_slice(), &[b'a'; 100]);
+        assert!(!request.headers().contains_key(header::CONTENT_ENCODING));
+
+        let too_large = compression
+            .decompress_request(gzip_request(&[b'a'; 101]))
+            .unwrap_err();
+        assert_eq!(too_large, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let mut garbage = gzip_request(b"");
+        *garbage.body_mut() = b"not gzip".to_vec();
+        let garbage = compression.decompress_request(garbage).unwrap_err();
+        assert_eq!(garbage, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn compressed_responses_get_a_weak_etag() {
+        let buffered = |_token: Token, _request: Request<Vec<u8>>| {
+            let mut response = response("text/plain");
+            response.headers_mut().insert(header::ETAG, HeaderValue::from_static("\"a-1\""));
+            *response.body_mut() = vec![b'a'; 4096];
+            response
+        };
+        let streamed = |token: Token, request: Request<Vec<u8>>| {
+            let mut response = buffered(token, request);
+            response.body_mut().clear();
+            response.extensions_mut().insert(StreamingBody::new(Scripted(Vec::new())));
+            response
+        };
+        let request = |encoding: &str| {
+            Request::builder()
+                .header(header::ACCEPT_ENCODING, encoding)
+                .body(Vec::new())
+                .unwrap()
+        };
+
+        let compression = Compression::new();
+        for handler in [&buffered as &dyn Handler, &streamed] {
+            let response = compression.handle(Token(0), request("gzip"), handler);
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+            assert_eq!(response.headers()[header::ETAG], "W/\"a-1\"");
+
+            let response = compression.handle(Token(0), request("identity"), handler);
+            assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+            assert_eq!(response.headers()[header::ETAG], "\"a-1\"");
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::Extensions;
+
+use super::context::ContextError;
+
+/// Identity and typed state of one connection, shared by the requests of all
+/// of its streams. Requests carry it in their extensions.
+///
+/// Unlike the `mio::Token`, the id is never reused by the server.
+#[derive(Clone)]
+pub struct ConnectionState {
+    id: u64,
+    peer: Option<SocketAddr>,
+    opened_at: Instant,
+    values: Arc<Mutex<Extensions>>,
+}
+
+impl ConnectionState {
+    pub fn new(id: u64, peer: Option<SocketAddr>) -> Self {
+        Self {
+            id,
+            peer,
+            opened_at: Instant::now(),
+            values: Arc::new(Mutex::new(Extensions::new())),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn opened_at(&self) -> Instant {
+        self.opened_at
+    }
+
+    /// Stores a value for the lifetime of the connection, returning the
+    /// previous one of the same type.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&self, value: T) -> Option<T> {
+        self.values.lock().ok()?.insert(value)
+    }
+
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.values.lock().ok()?.get::<T>().cloned()
+    }
+
+    pub fn remove<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.values.lock().ok()?.remove::<T>()
+    }
+
+    /// Runs `f` on the stored value in place, e.g. to update a counter.
+    pub fn with<T: Clone + Send + Sync + 'static, R>(
+        &self,
+        f: impl FnOnce(Option<&mut T>) -> R,
+    ) -> R {
+        match self.values.lock() {
+            Ok(mut values) => f(values.get_mut::<T>()),
+            Err(_) => f(None),
+        }
+    }
+}
+
+impl std::fmt::Debug for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionState")
+            .field("id", &self.id)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    pub streams: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum CloseReason {
+    /// The peer closed the socket or sent GOAWAY.
+    PeerClosed,
+    /// `ConnectionHooks::on_connect` refused the connection.
+    Rejected,
+    /// The server was stopped through its `ShutdownHandle`.
+    Shutdown,
+    Error(String),
+}
+
+impl From<&ContextError> for CloseReason {
+    fn from(value: &ContextError) -> Self {
+        match value {
+            ContextError::ClientDisconnected => CloseReason::PeerClosed,
+            e => CloseReason::Error(e.to_string()),
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::PeerClosed => f.write_str("closed by the peer"),
+            CloseReason::Rejected => f.write_str("refused on connect"),
+            CloseReason::Shutdown => f.write_str("server shutting down"),
+            CloseReason::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// Callbacks on the lifecycle of the server's connections.
+pub trait ConnectionHooks: Send + Sync {
+    /// Called once a connection is accepted, returning false closes it.
+    fn on_connect(&self, _connection: &ConnectionState) -> bool {
+        true
+    }
+
+    /// The peer's SETTINGS, as received.
+    fn on_settings(&self, _connection: &ConnectionState, _settings: &[(u16, u32)]) {}
+
+    fn on_close(
+        &self,
+        _connection: &ConnectionState,
+        _reason: &CloseReason,
+        _stats: &ConnectionStats,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_shared_by_clones() {
+        let state = ConnectionState::new(7, None);
+        let clone = state.clone();
+        assert_eq!(state.insert(1u32), None);
+        assert_eq!(clone.get::<u32>(), Some(1));
+        clone.with(|count: Option<&mut u32>| *count.unwrap() += 1);
+        assert_eq!(state.insert(5u32), Some(2));
+        assert_eq!(clone.remove::<u32>(), Some(5));
+        assert_eq!(state.get::<u32>(), None);
+        assert_eq!(clone.id(), 7);
+    }
+
+    #[test]
+    fn close_reasons_read_as_text() {
+        assert_eq!(
+            CloseReason::from(&ContextError::ClientDisconnected).to_string(),
+            "closed by the peer"
+        );
+        assert_eq!(CloseReason::Rejected.to_string(), "refused on connect");
+        assert_eq!(CloseReason::Shutdown.to_string(), "server shutting down");
+        assert_eq!(
+            CloseReason::Error("frame too large".to_string()).to_string(),
+            "error: frame too large"
+        );
+    }
+}
@@ -3,6 +3,7 @@ use std::{
     collections::HashMap,
     fmt::Display,
     io::{self, Read, Write},
+    net::SocketAddr,
     ptr::read,
     rc::Rc,
     result,
@@ -13,17 +14,38 @@ use http::{request, Request, Response};
 use kparser::{
     http2::{
         frame, hpack, ContinuationPayloadFlag, DataPayload, DataPayloadFlag, Frame,
-        FrameParseError, HeadersPayload, HeadersPayloadFlag, Hpack, HpackContext, HpackError, Len,
-        Payload, PingPayload, SETTINGS_HEADER_TABLE_SIZE,
+        FrameParseError, HeadersPayload, HeadersPayloadFlag, Hpack, HpackContext, HpackError,
+        Payload, PingPayload, SETTINGS_ENABLE_PUSH, SETTINGS_HEADER_TABLE_SIZE,
+        SETTINGS_INITIAL_WINDOW_SIZE, SETTINGS_MAX_CONCURRENT_STREAMS, SETTINGS_MAX_FRAME_SIZE,
+        SETTINGS_MAX_HEADER_LIST_SIZE,
     },
     u31::u31,
     Http2Pri,
 };
-use mio::event::Source;
+use mio::{event::Source, Interest, Registry, Token};
 
 use crate::BUFFER_SIZE;
 
-use super::{stream, Http2Stream, StreamState, TcpStream};
+// https://datatracker.ietf.org/doc/html/rfc9113#name-defined-settings
+const DEFAULT_HEADER_TABLE_SIZE: u32 = 4096;
+
+use super::{
+    connection::{ConnectionHooks, ConnectionState, ConnectionStats},
+    frames::{
+        self, encode_frame, FLAG_ACK, FLAG_END_HEADERS, FLAG_END_STREAM, FRAME_CONTINUATION,
+        FRAME_DATA, FRAME_HEADERS, FRAME_PING, FRAME_RST_STREAM, FRAME_SETTINGS,
+        FRAME_WINDOW_UPDATE,
+    },
+    limits::{BodyLimitAction, BodyLimits, FloodGuard, FloodLimits},
+    logging::log_error,
+    metrics::Metrics,
+    session::SessionEvent,
+    stream,
+    trace::{Direction, FrameTracer, TracedFrame},
+    transport::{PeerCredentials, Transport},
+    BodyChunk, BodyStream, BytesBody, Deferred, DeferredResponse, Http2Stream, StreamState,
+    StreamingBody, TcpStream, Trailers, Tunnel, Upgrade,
+};
 
 #[derive(Debug)]
 pub enum ContextError {
@@ -35,6 +57,10 @@ pub enum ContextError {
     NoDataReady,
     InvalidStream,
     MaxHeaderLenExceeded,
+    /// The peer exceeded a `FloodLimits` budget, GOAWAY was sent.
+    EnhanceYourCalm(&'static str),
+    /// A connection error, GOAWAY with this error code was sent.
+    ConnectionError(u32, &'static str),
 }
 
 impl From<io::Error> for ContextError {
@@ -71,14 +97,67 @@ impl Display for ContextError {
             ContextError::NoDataReady => f.write_str("ContextError::NoDataReady"),
             ContextError::InvalidStream => f.write_str("ContextError::InvalidStream"),
             ContextError::MaxHeaderLenExceeded => f.write_str("ContextError::MaxHeaderLenExceeded"),
+            ContextError::EnhanceYourCalm(reason) => {
+                write!(f, "ContextError::EnhanceYourCalm({})", reason)
+            }
+            ContextError::ConnectionError(code, reason) => {
+                write!(f, "ContextError::ConnectionError({:#x}, {})", code, reason)
+            }
+        }
+    }
+}
+
+enum OutgoingSource {
+    Body(Box<dyn BodyStream>),
+    Tunnel(Box<dyn Tunnel>),
+    /// Nothing was sent on the stream yet, see `resolve_deferred`.
+    Deferred(Box<dyn DeferredResponse>),
+}
+
+impl OutgoingSource {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        match self {
+            OutgoingSource::Body(body) => body.next_chunk(max_len),
+            OutgoingSource::Tunnel(tunnel) => tunnel.next_chunk(max_len),
+            OutgoingSource::Deferred(_) => Ok(BodyChunk::Pending),
+        }
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        match self {
+            OutgoingSource::Body(body) => body.source(),
+            OutgoingSource::Tunnel(tunnel) => tunnel.source(),
+            OutgoingSource::Deferred(response) => response.source(),
+        }
+    }
+}
+
+struct OutgoingBody {
+    source: OutgoingSource,
+    window: i64,
+    trailers: Option<Trailers>,
+    source_token: Option<Token>,
+    local_ended: bool,
+    remote_ended: bool,
+}
+
+impl OutgoingBody {
+    fn new(source: OutgoingSource, window: i64, trailers: Option<Trailers>) -> Self {
+        Self {
+            source,
+            window,
+            trailers,
+            source_token: None,
+            local_ended: false,
+            remote_ended: false,
         }
     }
 }
 
-pub struct Http2Context {
+pub struct Http2Context<T: Transport = TcpStream> {
     handshaked: bool,
     buffer_size: usize,
-    connection: TcpStream,
+    connection: T,
     hpack_context: HpackContext,
     streams: HashMap<u31, Http2Stream>,
     read_buffer: Vec<u8>,
@@ -87,9 +166,41 @@ pub struct Http2Context {
     nax_window_size: u128,
     max_frame_size: u32,
     max_headers_len: u32,
+    write_buffer: Vec<u8>,
+    send_window: i64,
+    outgoing: HashMap<u31, OutgoingBody>,
+    /// DATA received on CONNECT streams handed out but not turned into a
+    /// tunnel yet, replayed by `open_tunnel`.
+    tunnel_backlog: HashMap<u31, (Vec<u8>, bool)>,
+    enable_connect_protocol: bool,
+    released_tokens: Vec<Token>,
+    metrics: Arc<Metrics>,
+    header_table_size: u32,
+    state: ConnectionState,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+    stats: ConnectionStats,
+    flood: FloodGuard,
+    body_limits: BodyLimits,
+    last_stream_id: u32,
+    opened_streams: Vec<u31>,
+    events: Option<Vec<SessionEvent>>,
+    tracer: Option<Arc<dyn FrameTracer>>,
+}
+
+/// Decoded header fields with the size of their block.
+type DecodedHeaders = (Vec<(Vec<u8>, Vec<u8>)>, usize);
+
+impl<T: Transport> Drop for Http2Context<T> {
+    fn drop(&mut self) {
+        for _ in self.outgoing.iter() {
+            self.metrics.response_finished();
+        }
+        self.metrics.hpack_table_resized(self.header_table_size, 0);
+        self.metrics.connection_closed();
+    }
 }
 
-impl Source for Http2Context {
+impl<T: Transport> Source for Http2Context<T> {
     fn register(
         &mut self,
         registry: &mio::Registry,
@@ -113,18 +224,18 @@ impl Source for Http2Context {
     }
 }
 
-impl Http2Context {
-    pub fn new(
-        stream: TcpStream,
-        mut max_header: Option<usize>,
-        mut buffer_size: Option<usize>,
-    ) -> Self {
+impl<T: Transport> Http2Context<T> {
+    pub fn new(stream: T, mut max_header: Option<usize>, mut buffer_size: Option<usize>) -> Self {
         if max_header.is_none() {
             max_header = Some(128);
         }
         if buffer_size.is_none() {
             buffer_size = Some(4096);
         }
+        let state = ConnectionState::new(0, stream.peer_addr());
+        let metrics = Metrics::new();
+        metrics.connection_opened();
+        metrics.hpack_table_resized(0, DEFAULT_HEADER_TABLE_SIZE);
 
         Self {
             handshaked: false,
@@ -138,6 +249,23 @@ impl Http2Context {
             nax_window_size: 65535,
             max_frame_size: 16384,
             max_headers_len: 0,
+            write_buffer: Vec::new(),
+            send_window: frames::DEFAULT_WINDOW_SIZE,
+            outgoing: HashMap::new(),
+            tunnel_backlog: HashMap::new(),
+            enable_connect_protocol: true,
+            released_tokens: Vec::new(),
+            metrics,
+            header_table_size: DEFAULT_HEADER_TABLE_SIZE,
+            state,
+            hooks: None,
+            stats: ConnectionStats::default(),
+            flood: FloodGuard::new(FloodLimits::new()),
+            body_limits: BodyLimits::new(),
+            last_stream_id: 0,
+            opened_streams: Vec::new(),
+            events: None,
+            tracer: None,
         }
     }
 
@@ -146,69 +274,12 @@ impl Http2Context {
         read_data_stream: bool,
     ) -> Result<Vec<Http2Stream>, ContextError> {
         let mut buffer = vec![0u8; self.buffer_size];
-        let mut result = Vec::new();
         match self.connection.read(&mut buffer) {
             Ok(read_size) => {
                 if read_size == 0 {
                     return Err(ContextError::ClientDisconnected);
                 }
-                self.read_buffer.extend(&buffer[0..read_size]);
-
-                if !self.handshaked {
-                    if let Err(e) = Http2Pri::read_and_remove(&mut self.read_buffer) {
-                        return Err(ContextError::NotHttp2);
-                    }
-                    self.handshaked = true;
-                }
-
-                loop {
-                    if self.read_buffer.len() == 0 {
-                        break;
-                    }
-                    let (mut frame_size, mut frame) = self.read_frame(&self.read_buffer)?;
-                    self.read_buffer.drain(0..frame_size);
-                    let stream_id = self.handle_frame(&mut frame)?;
-                    let stream = self.streams.get_mut(&stream_id).unwrap();
-                    match stream.state {
-                        StreamState::FillingData => {
-                            if read_data_stream {
-                                result.push(stream.clone_reset_data());
-                            }
-                        }
-                        StreamState::Completed => {
-                            result.push(stream.clone());
-                            self.streams.remove(&stream_id);
-                        }
-                        StreamState::Ping => {
-                            let pong = PingPayload {
-                                OpaqueData: stream.ping_opaque,
-                            };
-                            let payload = Payload::Ping(pong);
-                            self.connection
-                                .write(&<Payload as Into<Vec<u8>>>::into(payload))?;
-                        }
-                        _ => {}
-                    }
-
-                    // match stream {
-                    //     Some(stream) => match stream.state {
-                    //         StreamState::FillingData => {
-                    //             if read_data_stream {
-                    //                 result.push(stream.clone_reset_data());
-                    //             }
-                    //         }
-                    //         StreamState::Completed => {
-                    //             result.push(stream.clone());
-                    //             self.streams.remove(&stream_id);
-                    //         }
-                    //         StreamState::Ping => {
-                    //             self.send_pong(stream.clone())?;
-                    //         }
-                    //         _ => {}
-                    //     },
-                    //     None => {}
-                    // };
-                }
+                self.receive(&buffer[0..read_size], read_data_stream)
             }
             Err(e) => match e.kind() {
                 io::ErrorKind::ConnectionRefused
@@ -216,8 +287,8 @@ impl Http2Context {
                 | io::ErrorKind::ConnectionAborted
                 | io::ErrorKind::NotConnected
                 | io::ErrorKind::UnexpectedEof
-                | io::ErrorKind::BrokenPipe => return Err(ContextError::ClientDisconnected),
-                io::ErrorKind::WouldBlock => return Err(ContextError::NoDataReady),
+                | io::ErrorKind::BrokenPipe => Err(ContextError::ClientDisconnected),
+                io::ErrorKind::WouldBlock => Err(ContextError::NoDataReady),
                 io::ErrorKind::PermissionDenied
                 | io::ErrorKind::AddrInUse
                 | io::ErrorKind::AddrNotAvailable
@@ -229,85 +300,557 @@ impl Http2Context {
                 | io::ErrorKind::Unsupported
                 | io::ErrorKind::OutOfMemory
                 | io::ErrorKind::Other
-                | _ => return Err(ContextError::IOError(e)),
+                | _ => Err(ContextError::IOError(e)),
             },
         }
-        return Ok(result);
     }
 
-    fn read_frame(&self, buf: &Vec<u8>) -> Result<(usize, Frame), ContextError> {
-        let mut frame = <Frame as TryFrom<&[u8]>>::try_from(&buf)?;
-        let len = <Frame as Len>::binary_len(&frame);
-        Ok((len, frame))
+    /// Runs the protocol on bytes received from the peer, whatever transport
+    /// they came from. A frame split across calls is kept until it is whole.
+    pub fn receive(
+        &mut self,
+        data: &[u8],
+        read_data_stream: bool,
+    ) -> Result<Vec<Http2Stream>, ContextError> {
+        let mut result = Vec::new();
+        self.opened_streams.clear();
+        self.read_buffer.extend(data);
+        self.metrics.read(data.len());
+        self.stats.bytes_in += data.len() as u64;
+
+        if !self.handshaked {
+            if self.read_buffer.len() < frames::PREFACE.len()
+                && frames::PREFACE.starts_with(&self.read_buffer)
+            {
+                return Ok(result);
+            }
+            if let Err(e) = Http2Pri::read_and_remove(&mut self.read_buffer) {
+                return Err(ContextError::NotHttp2);
+            }
+            self.handshaked = true;
+            let mut settings = Vec::new();
+            if self.enable_connect_protocol {
+                settings.push((frames::SETTINGS_ENABLE_CONNECT_PROTOCOL, 1));
+            }
+            self.queue_frame(FRAME_SETTINGS, 0, 0, &frames::settings_payload(&settings));
+        }
+
+        loop {
+            let frame_size = match frames::frame_len(&self.read_buffer) {
+                Some(len) if len - 9 > frames::DEFAULT_MAX_FRAME_SIZE => {
+                    return Err(self.connection_error(frames::FRAME_SIZE_ERROR, "frame too large"));
+                }
+                Some(len) if len <= self.read_buffer.len() => len,
+                _ => break,
+            };
+            let mut frame = self.read_frame(&self.read_buffer[0..frame_size])?;
+            self.metrics
+                .frame_received(&self.read_buffer[0..frame_size]);
+            let decoded = self.decode_header_block(&frame)?;
+            if let Some(tracer) = &self.tracer {
+                let headers = decoded.as_ref().map(|(headers, _)| headers.as_slice());
+                if let Some(traced) = TracedFrame::parse(
+                    Direction::Inbound,
+                    &self.read_buffer[0..frame_size],
+                    headers,
+                ) {
+                    tracer.on_frame(&self.state, &traced);
+                }
+            }
+            if let Err(reason) = self.flood.record(&self.read_buffer[0..frame_size]) {
+                return Err(self.enhance_your_calm(reason));
+            }
+            if let Some(events) = self.events.as_mut() {
+                events.extend(SessionEvent::from_frame(
+                    &self.read_buffer[0..frame_size],
+                    frame.stream_id,
+                ));
+            }
+            self.read_buffer.drain(0..frame_size);
+            let stream_id = self.handle_frame(&mut frame, decoded)?;
+            self.check_body(stream_id)?;
+            let stream = match self.streams.get_mut(&stream_id) {
+                Some(stream) => stream,
+                None => continue,
+            };
+            match stream.state {
+                StreamState::FillingData => {
+                    if read_data_stream {
+                        let chunk = stream.clone_reset_data();
+                        if let Some(events) = self.events.as_mut() {
+                            events.push(SessionEvent::Data {
+                                stream_id,
+                                data: chunk.read_data().unwrap_or_default(),
+                            });
+                        }
+                        result.push(chunk);
+                    }
+                }
+                StreamState::Completed => {
+                    // DATA may follow in the same read, before the handler
+                    // answers with a tunnel.
+                    if stream.is_connect() {
+                        self.tunnel_backlog.insert(stream_id, (Vec::new(), false));
+                    }
+                    if let Some(events) = self.events.as_mut() {
+                        events.push(SessionEvent::Request {
+                            stream_id,
+                            request: stream.clone().into(),
+                        });
+                    }
+                    result.push(stream.clone());
+                    self.streams.remove(&stream_id);
+                }
+                StreamState::Ping => {
+                    stream.state = StreamState::Initiate;
+                    let opaque = stream.ping_opaque.to_be_bytes();
+                    self.queue_frame(FRAME_PING, FLAG_ACK, 0, &opaque);
+                }
+                _ => {}
+            }
+        }
+        self.pump_outgoing()?;
+        Ok(result)
+    }
+
+    fn read_frame(&self, buf: &[u8]) -> Result<Frame, ContextError> {
+        Ok(<Frame as TryFrom<&[u8]>>::try_from(buf)?)
+    }
+
+    /// Decodes the fields of HEADERS and CONTINUATION frames, with the size
+    /// of the block.
+    fn decode_header_block(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Option<DecodedHeaders>, ContextError> {
+        let block = match &frame.payload {
+            Payload::Headers(payload) => &payload.HeaderBlockFragment,
+            Payload::Continuation(payload) => &payload.HeaderBlockFragment,
+            _ => return Ok(None),
+        };
+        let (headers, size) = block.decode(&mut self.hpack_context)?;
+        self.metrics.headers_decoded(size);
+        Ok(Some((headers, size)))
+    }
+
+    /// Reports this connection to `metrics`, shared with the server and its
+    /// other connections, instead of a private instance.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics.connection_closed();
+        self.metrics.hpack_table_resized(self.header_table_size, 0);
+        metrics.connection_opened();
+        metrics.hpack_table_resized(0, self.header_table_size);
+        for _ in self.outgoing.iter() {
+            self.metrics.response_finished();
+            metrics.response_started();
+        }
+        self.metrics = metrics;
+    }
+
+    pub fn set_connection_state(&mut self, state: ConnectionState) {
+        self.state = state;
+    }
+
+    pub fn connection_state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    pub fn set_hooks(&mut self, hooks: Arc<dyn ConnectionHooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Hands every frame read or queued on this connection to `tracer`.
+    pub fn set_tracer(&mut self, tracer: Arc<dyn FrameTracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            duration: self.state.opened_at().elapsed(),
+            ..self.stats.clone()
+        }
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.connection.peer_addr()
+    }
+
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.connection.peer_credentials()
+    }
+
+    /// Starts collecting `SessionEvent`s, see `take_events`.
+    pub fn record_events(&mut self) {
+        self.events.get_or_insert_with(Vec::new);
+    }
+
+    pub fn take_events(&mut self) -> Vec<SessionEvent> {
+        match self.events.as_mut() {
+            Some(events) => std::mem::take(events),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.connection
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.connection
+    }
+
+    pub fn set_flood_limits(&mut self, limits: FloodLimits) {
+        self.flood = FloodGuard::new(limits);
+    }
+
+    pub fn set_body_limits(&mut self, limits: BodyLimits) {
+        self.body_limits = limits;
+    }
+
+    /// Sends GOAWAY naming the last stream received, no new stream will be
+    /// processed.
+    pub fn go_away(&mut self, error_code: u32, debug_data: &[u8]) -> Result<(), ContextError> {
+        self.queue_frame(
+            frames::FRAME_GOAWAY,
+            0,
+            0,
+            &frames::goaway_payload(self.last_stream_id, error_code, debug_data),
+        );
+        self.flush()
+    }
+
+    /// Sends GOAWAY with ENHANCE_YOUR_CALM, the connection is to be closed.
+    fn enhance_your_calm(&mut self, reason: &'static str) -> ContextError {
+        let _ = self.go_away(frames::ENHANCE_YOUR_CALM, reason.as_bytes());
+        ContextError::EnhanceYourCalm(reason)
+    }
+
+    /// Sends GOAWAY with `error_code`, the connection is to be closed.
+    fn connection_error(&mut self, error_code: u32, reason: &'static str) -> ContextError {
+        let _ = self.go_away(error_code, reason.as_bytes());
+        ContextError::ConnectionError(error_code, reason)
+    }
+
+    /// Advertises SETTINGS_ENABLE_CONNECT_PROTOCOL (RFC 8441), must be called
+    /// before the connection preface is handled.
+    pub fn set_enable_connect_protocol(&mut self, enable: bool) {
+        self.enable_connect_protocol = enable;
+    }
+
+    /// Hands DATA on a tunneled stream to its `Tunnel`, or keeps it until the
+    /// tunnel is opened. Returns false for regular streams.
+    fn handle_tunnel_data(&mut self, frame: &Frame) -> Result<bool, ContextError> {
+        let data_payload = match &frame.payload {
+            kparser::http2::Payload::Data(data_payload) => data_payload,
+            _ => return Ok(false),
+        };
+        let end_stream = frame.flags & DataPayloadFlag::END_STREAM == DataPayloadFlag::END_STREAM;
+        if let Some((backlog, ended)) = self.tunnel_backlog.get_mut(&frame.stream_id) {
+            backlog.extend_from_slice(&data_payload.data);
+            *ended |= end_stream;
+            // Nothing reopened the stream window yet, the peer can't send more.
+            // https://datatracker.ietf.org/doc/html/rfc9113#section-6.9.1
+            if backlog.len() > self.nax_window_size as usize {
+                return Err(self.connection_error(
+                    frames::FLOW_CONTROL_ERROR,
+                    "stream window exceeded",
+                ));
+            }
+        } else {
+            match self.outgoing.get(&frame.stream_id) {
+                Some(outgoing) if matches!(outgoing.source, OutgoingSource::Tunnel(_)) => {}
+                _ => return Ok(false),
+            }
+        }
+
+        // The connection window is reopened right away, the stream window only
+        // by what the tunnel consumed so a slow upstream pushes back.
+        let received = data_payload.data.len() as u32;
+        if received > 0 {
+            let increment = frames::window_update_payload(received);
+            self.queue_frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+        }
+        if !self.tunnel_backlog.contains_key(&frame.stream_id) {
+            self.feed_tunnel(frame.stream_id, &data_payload.data, end_stream);
+        }
+        Ok(true)
     }
 
-    fn handle_frame(&mut self, frame: &mut Frame) -> Result<u31, ContextError> {
-        let stream = match self.streams.get_mut(&frame.stream_id) {
-            Some(stream) => stream,
-            None => {
-                let mut stream = Http2Stream::new(frame.stream_id);
-                self.streams.insert(frame.stream_id, stream);
-                self.streams.get_mut(&frame.stream_id).unwrap()
+    fn feed_tunnel(&mut self, stream_id: u31, data: &[u8], end_stream: bool) {
+        let outgoing = match self.outgoing.get_mut(&stream_id) {
+            Some(outgoing) => outgoing,
+            None => return,
+        };
+        let tunnel = match &mut outgoing.source {
+            OutgoingSource::Tunnel(tunnel) => tunnel,
+            _ => return,
+        };
+
+        let consumed = match tunnel.on_data(data, end_stream) {
+            Ok(consumed) => consumed as u32,
+            Err(e) => {
+                log_error!("{}", e);
+                tunnel.on_reset();
+                self.remove_outgoing(&stream_id);
+                self.queue_frame(
+                    FRAME_RST_STREAM,
+                    0,
+                    stream_id.to_u32(),
+                    &frames::rst_stream_payload(frames::CONNECT_ERROR),
+                );
+                return;
             }
         };
+        outgoing.remote_ended |= end_stream;
+        let closed = outgoing.remote_ended && outgoing.local_ended;
+
+        if consumed > 0 && !end_stream {
+            let increment = frames::window_update_payload(consumed);
+            self.queue_frame(FRAME_WINDOW_UPDATE, 0, stream_id.to_u32(), &increment);
+        }
+        if closed {
+            self.remove_outgoing(&stream_id);
+        }
+    }
+
+    fn insert_outgoing(&mut self, stream_id: u31, outgoing: OutgoingBody) {
+        if self.outgoing.insert(stream_id, outgoing).is_none() {
+            self.metrics.response_started();
+        }
+    }
+
+    fn remove_outgoing(&mut self, stream_id: &u31) -> Option<OutgoingBody> {
+        let outgoing = self.outgoing.remove(stream_id)?;
+        self.metrics.response_finished();
+        if let Some(token) = outgoing.source_token {
+            self.released_tokens.push(token);
+        }
+        Some(outgoing)
+    }
+
+    /// Bodies and tunnels reading from a socket which isn't registered in the
+    /// `Poll` yet.
+    pub fn unregistered_sources(&mut self) -> Vec<u31> {
+        self.outgoing
+            .iter_mut()
+            .filter_map(|(stream_id, outgoing)| match outgoing.source_token {
+                Some(_) => None,
+                None => outgoing.source.source().map(|_| *stream_id),
+            })
+            .collect()
+    }
+
+    pub fn register_source(
+        &mut self,
+        stream_id: u31,
+        registry: &Registry,
+        token: Token,
+    ) -> Result<(), ContextError> {
+        let outgoing = match self.outgoing.get_mut(&stream_id) {
+            Some(outgoing) => outgoing,
+            None => return Err(ContextError::InvalidStream),
+        };
+        match outgoing.source.source() {
+            Some(source) => {
+                registry.register(source, token, Interest::READABLE | Interest::WRITABLE)?;
+                outgoing.source_token = Some(token);
+                Ok(())
+            }
+            None => Err(ContextError::InvalidStream),
+        }
+    }
+
+    /// Readiness of a socket registered with `register_source`.
+    pub fn handle_source_event(
+        &mut self,
+        stream_id: u31,
+        readable: bool,
+        writable: bool,
+    ) -> Result<(), ContextError> {
+        let outgoing = match self.outgoing.get_mut(&stream_id) {
+            Some(outgoing) => outgoing,
+            None => return Ok(()),
+        };
+        let result = match &mut outgoing.source {
+            OutgoingSource::Tunnel(tunnel) => tunnel.on_ready(readable, writable),
+            _ => Ok(0),
+        };
+        match result {
+            Ok(consumed) => {
+                if consumed > 0 && !outgoing.remote_ended {
+                    let increment = frames::window_update_payload(consumed as u32);
+                    self.queue_frame(FRAME_WINDOW_UPDATE, 0, stream_id.to_u32(), &increment);
+                }
+            }
+            Err(e) => {
+                log_error!("{}", e);
+                self.remove_outgoing(&stream_id);
+                self.queue_frame(
+                    FRAME_RST_STREAM,
+                    0,
+                    stream_id.to_u32(),
+                    &frames::rst_stream_payload(frames::CONNECT_ERROR),
+                );
+            }
+        }
+        self.pump_outgoing()
+    }
+
+    pub fn has_outgoing(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Streams whose request headers completed during the last `receive`,
+    /// whether or not the rest of the request followed.
+    pub fn opened_streams(&self) -> &[u31] {
+        &self.opened_streams
+    }
+
+    /// Tokens of body and tunnel sockets that were closed since the last call.
+    pub fn take_released_tokens(&mut self) -> Vec<Token> {
+        std::mem::take(&mut self.released_tokens)
+    }
+
+    /// Tokens of every body and tunnel socket still open, for when the
+    /// connection itself goes away.
+    pub fn source_tokens(&self) -> Vec<Token> {
+        self.outgoing
+            .values()
+            .filter_map(|outgoing| outgoing.source_token)
+            .collect()
+    }
+
+    fn handle_frame(
+        &mut self,
+        frame: &mut Frame,
+        decoded: Option<DecodedHeaders>,
+    ) -> Result<u31, ContextError> {
+        if self.handle_tunnel_data(frame)? {
+            return Ok(frame.stream_id);
+        }
+
+        // Only HEADERS opens a stream, with a new id.
+        // https://datatracker.ietf.org/doc/html/rfc9113#section-5.1
+        if frame.stream_id.to_u32() != 0 && !self.streams.contains_key(&frame.stream_id) {
+            if !matches!(frame.payload, Payload::Headers(_))
+                || frame.stream_id.to_u32() <= self.last_stream_id
+            {
+                return self.handle_unopened_frame(frame);
+            }
+            self.metrics.stream_opened();
+            self.stats.streams += 1;
+            self.last_stream_id = u32::max(self.last_stream_id, frame.stream_id.to_u32());
+        }
+        let stream = self
+            .streams
+            .entry(frame.stream_id)
+            .or_insert_with(|| Http2Stream::new(frame.stream_id));
 
         match &mut frame.payload {
             kparser::http2::Payload::Settings(settings_payload) => {
+                if frame.flags & FLAG_ACK == FLAG_ACK {
+                    return Ok(stream.get_stream_id());
+                }
                 for (id, value) in settings_payload.settings.iter() {
                     match id {
                         &SETTINGS_HEADER_TABLE_SIZE => {
                             self.hpack_context.resize(value.clone() as usize);
+                            self.metrics
+                                .hpack_table_resized(self.header_table_size, *value);
+                            self.header_table_size = *value;
                         }
-                        SETTINGS_ENABLE_PUSH => {
+                        &SETTINGS_ENABLE_PUSH => {
                             self.enable_push = (value.clone() != 0);
                         }
-                        SETTINGS_MAX_CONCURRENT_STREAMS => self.max_streams = value.clone(),
-                        SETTINGS_INITIAL_WINDOW_SIZE => {
-                            if (frame.stream_id.to_u32() == 0) {
-                                self.nax_window_size = value.clone() as u128;
-                            } else {
-                                stream.set_window_frame_size(value.clone())
+                        &SETTINGS_MAX_CONCURRENT_STREAMS => self.max_streams = value.clone(),
+                        &SETTINGS_INITIAL_WINDOW_SIZE => {
+                            // The new initial window applies to every open stream,
+                            // adjusted by what was already consumed.
+                            let delta = value.clone() as i64 - self.nax_window_size as i64;
+                            for outgoing in self.outgoing.values_mut() {
+                                outgoing.window += delta;
                             }
+                            self.nax_window_size = value.clone() as u128;
                         }
-                        SETTINGS_MAX_FRAME_SIZE => {
+                        &SETTINGS_MAX_FRAME_SIZE => {
                             self.max_frame_size = value.clone();
                         }
-                        SETTINGS_MAX_HEADER_LIST_SIZE => {
+                        &SETTINGS_MAX_HEADER_LIST_SIZE => {
                             self.max_headers_len = value.clone();
                         }
                         _ => {}
                     }
                 }
+                if let Some(hooks) = &self.hooks {
+                    hooks.on_settings(&self.state, &settings_payload.settings);
+                }
                 stream.state = StreamState::Initiate;
+                self.queue_frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]);
             }
             kparser::http2::Payload::Data(data_payload) => {
                 stream.write_data(data_payload);
-                if frame.flags & DataPayloadFlag::END_STREAM == DataPayloadFlag::END_STREAM {
+                let end_stream =
+                    frame.flags & DataPayloadFlag::END_STREAM == DataPayloadFlag::END_STREAM;
+                if end_stream {
                     stream.state = StreamState::Completed;
                 } else {
                     stream.state = StreamState::FillingData;
                 }
+                // Hand the consumed bytes back to the peer right away, request
+                // bodies are buffered in the stream anyway.
+                let consumed = data_payload.data.len() as u32;
+                if consumed > 0 {
+                    let increment = frames::window_update_payload(consumed);
+                    self.queue_frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+                    if !end_stream {
+                        self.queue_frame(
+                            FRAME_WINDOW_UPDATE,
+                            0,
+                            frame.stream_id.to_u32(),
+                            &increment,
+                        );
+                    }
+                }
             }
-            kparser::http2::Payload::Headers(headers_payload) => {
-                let (headers, headers_size) = headers_payload
-                    .HeaderBlockFragment
-                    .decode(&mut self.hpack_context)?;
+            kparser::http2::Payload::Headers(_) => {
+                let (headers, headers_size) = decoded.unwrap_or_default();
 
-                if (stream.get_headers_len() + headers_size as u32 > self.max_headers_len)
+                if (stream.get_headers_len().saturating_add(headers_size as u32)
+                    > self.max_headers_len)
                     && (self.max_headers_len != 0)
                 {
                     return Err(ContextError::MaxHeaderLenExceeded);
                 }
 
-                stream.add_headers(headers, headers_size as u32);
-                if frame.flags & HeadersPayloadFlag::END_STREAM == HeadersPayloadFlag::END_STREAM {
-                    if frame.flags & HeadersPayloadFlag::END_HEADERS
-                        == HeadersPayloadFlag::END_HEADERS
-                    {
-                        stream.state = StreamState::Completed;
-                    } else {
-                        stream.state = StreamState::FillingHeaders;
+                let end_stream =
+                    frame.flags & HeadersPayloadFlag::END_STREAM == HeadersPayloadFlag::END_STREAM;
+                let end_headers =
+                    frame.flags & HeadersPayloadFlag::END_HEADERS == HeadersPayloadFlag::END_HEADERS;
+
+                // A second HEADERS block carries trailers and has to end the stream.
+                // https://datatracker.ietf.org/doc/html/rfc9113#name-http-message-framing
+                if stream.headers_received() {
+                    if !end_stream {
+                        return Err(ContextError::InvalidStream);
                     }
+                    stream.add_trailers(headers, headers_size as u32);
+                } else {
+                    stream.add_headers(headers, headers_size as u32);
+                    if end_headers {
+                        self.opened_streams.push(frame.stream_id);
+                    }
+                }
+                if end_stream {
+                    stream.set_end_stream_received();
+                }
+                if end_headers {
+                    stream.set_headers_received();
+                }
+
+                // CONNECT requests are handed out as soon as their headers are
+                // complete, the stream then stays open as a tunnel.
+                if end_headers && (end_stream || stream.is_connect()) {
+                    stream.state = StreamState::Completed;
                 } else {
                     stream.state = StreamState::FillingHeaders;
                 }
@@ -316,37 +859,490 @@ impl Http2Context {
                 // The PRIORITY frame (type=0x02) is deprecated;
                 // https://datatracker.ietf.org/doc/html/rfc9113#name-priority
             }
-            kparser::http2::Payload::RstStream(rst_payload) => {}
-            kparser::http2::Payload::PushPromise(_) => todo!(),
+            kparser::http2::Payload::RstStream(rst_payload) => {
+                self.on_reset(&frame.stream_id);
+                return Ok(frame.stream_id);
+            }
+            // Only servers push.
+            // https://datatracker.ietf.org/doc/html/rfc9113#section-8.4
+            kparser::http2::Payload::PushPromise(_) => return Err(ContextError::InvalidStream),
             kparser::http2::Payload::Ping(ping_payload) => {
-                stream.ping_opaque = ping_payload.OpaqueData
+                if frame.flags & FLAG_ACK != FLAG_ACK {
+                    stream.ping_opaque = ping_payload.OpaqueData;
+                    stream.state = StreamState::Ping;
+                }
             }
             kparser::http2::Payload::GoAway(goaway_payload) => {
                 return Err(ContextError::ClientDisconnected);
             }
             kparser::http2::Payload::WindowUpdate(window_update_payload) => {
+                let increment = window_update_payload.WindowSizeIncrement as i64;
+                if frame.stream_id.to_u32() == 0 {
+                    self.send_window += increment;
+                } else if let Some(outgoing) = self.outgoing.get_mut(&frame.stream_id) {
+                    outgoing.window += increment;
+                }
                 stream.window_frame_size_increament(window_update_payload.WindowSizeIncrement);
             }
-            kparser::http2::Payload::Continuation(continuation_payload) => {
-                let (headers, headers_size) = continuation_payload
-                    .HeaderBlockFragment
-                    .decode(&mut self.hpack_context)?;
+            kparser::http2::Payload::Continuation(_) => {
+                let (headers, headers_size) = decoded.unwrap_or_default();
 
-                if stream.get_headers_len() + headers_size as u32 > self.max_headers_len {
+                if (stream.get_headers_len().saturating_add(headers_size as u32)
+                    > self.max_headers_len)
+                    && (self.max_headers_len != 0)
+                {
                     return Err(ContextError::MaxHeaderLenExceeded);
                 }
 
-                stream.add_headers(headers, headers_size as u32);
+                if stream.is_receiving_trailers() {
+                    stream.add_trailers(headers, headers_size as u32);
+                } else {
+                    stream.add_headers(headers, headers_size as u32);
+                }
                 if frame.flags & ContinuationPayloadFlag::END_HEADERS
                     == ContinuationPayloadFlag::END_HEADERS
                 {
-                    stream.state = StreamState::Completed;
+                    if !stream.headers_received() {
+                        self.opened_streams.push(frame.stream_id);
+                    }
+                    stream.set_headers_received();
+                    if stream.end_stream_received() || stream.is_connect() {
+                        stream.state = StreamState::Completed;
+                    } else {
+                        stream.state = StreamState::FillingHeaders;
+                    }
                 } else {
                     stream.state = StreamState::FillingHeaders;
                 }
             }
         }
-        Ok(stream.get_stream_id())
+        Ok(frame.stream_id)
+    }
+
+    /// Frames on a stream which isn't open. Idle streams only accept
+    /// PRIORITY, closed ones may still see what the peer sent before learning
+    /// the stream was done with, a refused request's remaining body and
+    /// trailers for example.
+    fn handle_unopened_frame(&mut self, frame: &Frame) -> Result<u31, ContextError> {
+        let closed = frame.stream_id.to_u32() <= self.last_stream_id;
+        match &frame.payload {
+            Payload::Priority(_) => {}
+            // Already decoded, the HPACK context stays in sync.
+            Payload::Headers(_) | Payload::Continuation(_) if closed => {}
+            Payload::Data(data_payload) if closed => {
+                // Still counts against the connection window.
+                let consumed = data_payload.data.len() as u32;
+                if consumed > 0 {
+                    let increment = frames::window_update_payload(consumed);
+                    self.queue_frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+                }
+            }
+            Payload::WindowUpdate(window_update_payload) if closed => {
+                if let Some(outgoing) = self.outgoing.get_mut(&frame.stream_id) {
+                    outgoing.window += window_update_payload.WindowSizeIncrement as i64;
+                }
+            }
+            Payload::RstStream(_) if closed => self.on_reset(&frame.stream_id),
+            _ => return Err(self.connection_error(frames::PROTOCOL_ERROR, "frame on idle stream")),
+        }
+        Ok(frame.stream_id)
+    }
+
+    /// The peer reset `stream_id`, whatever is still sent on it is dropped.
+    fn on_reset(&mut self, stream_id: &u31) {
+        self.tunnel_backlog.remove(stream_id);
+        if let Some(mut outgoing) = self.remove_outgoing(stream_id) {
+            if let OutgoingSource::Tunnel(tunnel) = &mut outgoing.source {
+                tunnel.on_reset();
+            }
+        }
+        self.streams.remove(stream_id);
+    }
+
+    /// Holds the body of a request to its `content-length` and to its
+    /// `BodyLimits` cap, refusing the stream once it breaks either.
+    fn check_body(&mut self, stream_id: u31) -> Result<(), ContextError> {
+        let stream = match self.streams.get(&stream_id) {
+            Some(stream) if stream.headers_received() && !stream.is_connect() => stream,
+            _ => return Ok(()),
+        };
+        let ended = match stream.state {
+            StreamState::Completed => true,
+            StreamState::FillingHeaders | StreamState::FillingData => false,
+            _ => return Ok(()),
+        };
+        let received = stream.body_len();
+        // A body that doesn't match its content-length is malformed, as are
+        // differing content-length values.
+        // https://datatracker.ietf.org/doc/html/rfc9113#section-8.1.1
+        let lengths: Vec<Option<usize>> = stream
+            .get_header_values(b"content-length")
+            .map(|value| std::str::from_utf8(value).ok()?.parse().ok())
+            .collect();
+        let content_length = match lengths.split_first() {
+            None => None,
+            Some((&Some(len), others))
+                if others.iter().all(|&other| other == Some(len))
+                    && received <= len
+                    && (!ended || received == len) =>
+            {
+                Some(len)
+            }
+            Some(_) => {
+                self.refuse_stream(stream_id);
+                self.queue_reset(stream_id, frames::PROTOCOL_ERROR);
+                return Ok(());
+            }
+        };
+        let path = stream.get_header(b":path").map(Vec::as_slice);
+        let max = match self.body_limits.limit(path.unwrap_or_default()) {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        if usize::max(received, content_length.unwrap_or(0)) <= max {
+            return Ok(());
+        }
+        match self.body_limits.exceeded_action() {
+            BodyLimitAction::PayloadTooLarge => self.reject_request(stream_id, payload_too_large()),
+            BodyLimitAction::Reset => {
+                self.refuse_stream(stream_id);
+                self.queue_reset(stream_id, frames::CANCEL);
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers the request of `stream_id` with `response` without waiting for
+    /// its body, whatever is left of it is dropped.
+    pub fn reject_request(
+        &mut self,
+        stream_id: u31,
+        response: Response<Vec<u8>>,
+    ) -> Result<(), ContextError> {
+        let ended = match self.streams.get(&stream_id) {
+            Some(stream) => matches!(stream.state, StreamState::Completed),
+            None => true,
+        };
+        self.refuse_stream(stream_id);
+        self.send_http_response(stream_id, response)?;
+        // Tells the client to stop sending the body.
+        // https://datatracker.ietf.org/doc/html/rfc9113#section-8.1
+        if !ended {
+            self.queue_reset(stream_id, frames::NO_ERROR);
+        }
+        Ok(())
+    }
+
+    /// Drops the request of `stream_id`. Its id stays below
+    /// `last_stream_id`, whatever the peer still sends on it only credits
+    /// the connection window.
+    fn refuse_stream(&mut self, stream_id: u31) {
+        self.streams.remove(&stream_id);
+    }
+
+    fn queue_reset(&mut self, stream_id: u31, error_code: u32) {
+        self.queue_frame(
+            FRAME_RST_STREAM,
+            0,
+            stream_id.to_u32(),
+            &frames::rst_stream_payload(error_code),
+        );
+    }
+
+    fn queue_frame(&mut self, frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        self.queue_traced_frame(frame_type, flags, stream_id, payload, None);
+    }
+
+    /// Queues a frame, handing it to the tracer with the `headers` it encodes.
+    fn queue_traced_frame(
+        &mut self,
+        frame_type: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+        headers: Option<&[(Vec<u8>, Vec<u8>)]>,
+    ) {
+        let frame = encode_frame(frame_type, flags, stream_id, payload);
+        self.metrics.frame_sent(&frame);
+        if let Some(tracer) = &self.tracer {
+            if let Some(traced) = TracedFrame::parse(Direction::Outbound, &frame, headers) {
+                tracer.on_frame(&self.state, &traced);
+            }
+        }
+        self.write_buffer.extend(frame);
+    }
+
+    /// Writes as much of the pending output as the socket accepts.
+    pub fn flush(&mut self) -> Result<(), ContextError> {
+        while !self.write_buffer.is_empty() {
+            match self.connection.write(&self.write_buffer) {
+                Ok(0) => return Err(ContextError::ClientDisconnected),
+                Ok(written) => {
+                    self.metrics.written(written);
+                    self.stats.bytes_out += written as u64;
+                    self.write_buffer.drain(0..written);
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(ContextError::IOError(e)),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues an encoded block as HEADERS and CONTINUATION frames, `headers`
+    /// being traced with the first.
+    fn queue_header_block(
+        &mut self,
+        stream_id: u31,
+        block: Vec<u8>,
+        end_stream: bool,
+        mut headers: Option<&[(Vec<u8>, Vec<u8>)]>,
+    ) {
+        let max_frame_size = usize::max(self.max_frame_size as usize, 1);
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut frame_type = FRAME_HEADERS;
+        if chunks.peek().is_none() {
+            let flags = FLAG_END_HEADERS | if end_stream { FLAG_END_STREAM } else { 0 };
+            self.queue_traced_frame(frame_type, flags, stream_id.to_u32(), &[], headers);
+            return;
+        }
+        while let Some(chunk) = chunks.next() {
+            let mut flags = 0;
+            if frame_type == FRAME_HEADERS && end_stream {
+                flags |= FLAG_END_STREAM;
+            }
+            if chunks.peek().is_none() {
+                flags |= FLAG_END_HEADERS;
+            }
+            self.queue_traced_frame(frame_type, flags, stream_id.to_u32(), chunk, headers.take());
+            frame_type = FRAME_CONTINUATION;
+        }
+    }
+
+    fn queue_headers(
+        &mut self,
+        stream_id: u31,
+        headers: &Vec<(Vec<u8>, Vec<u8>)>,
+        end_stream: bool,
+    ) {
+        let block = self.encode_headers(headers);
+        self.queue_header_block(stream_id, block, end_stream, Some(headers));
+    }
+
+    fn encode_headers(&mut self, headers: &Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        let mut hpack = Hpack::new();
+        hpack.encode(headers, &mut self.hpack_context);
+        let headers_payload = HeadersPayload {
+            HeaderBlockFragment: hpack,
+            PadLength: None,
+            Padding: None,
+            Priority: None,
+        };
+        <Payload as Into<Vec<u8>>>::into(Payload::Headers(headers_payload))
+    }
+
+    pub fn send_headers(
+        &mut self,
+        stream_id: u31,
+        headers: Vec<(Vec<u8>, Vec<u8>)>,
+        end_stream: bool,
+    ) -> Result<(), ContextError> {
+        self.queue_headers(stream_id, &headers, end_stream);
+        self.flush()
+    }
+
+    /// Attaches a body to a stream whose headers were already sent. It is
+    /// written out by `pump_outgoing` as the flow-control windows allow.
+    pub fn send_body(
+        &mut self,
+        stream_id: u31,
+        body: Box<dyn BodyStream>,
+    ) -> Result<(), ContextError> {
+        self.send_body_with_trailers(stream_id, body, None)
+    }
+
+    /// Like `send_body`, ending the stream with a trailing HEADERS frame
+    /// instead of an empty DATA frame once the body is done.
+    pub fn send_body_with_trailers(
+        &mut self,
+        stream_id: u31,
+        body: Box<dyn BodyStream>,
+        trailers: Option<Trailers>,
+    ) -> Result<(), ContextError> {
+        self.insert_outgoing(
+            stream_id,
+            OutgoingBody::new(
+                OutgoingSource::Body(body),
+                self.nax_window_size as i64,
+                trailers,
+            ),
+        );
+        self.pump_outgoing()
+    }
+
+    /// Keeps a stream whose headers were already sent open in both
+    /// directions, relaying DATA to and from `tunnel`.
+    pub fn open_tunnel(
+        &mut self,
+        stream_id: u31,
+        tunnel: Box<dyn Tunnel>,
+    ) -> Result<(), ContextError> {
+        self.insert_outgoing(
+            stream_id,
+            OutgoingBody::new(
+                OutgoingSource::Tunnel(tunnel),
+                self.nax_window_size as i64,
+                None,
+            ),
+        );
+        if let Some((backlog, ended)) = self.tunnel_backlog.remove(&stream_id) {
+            if !backlog.is_empty() || ended {
+                self.feed_tunnel(stream_id, &backlog, ended);
+            }
+        }
+        self.pump_outgoing()
+    }
+
+    pub fn send_trailers(&mut self, stream_id: u31, trailers: Trailers) -> Result<(), ContextError> {
+        self.queue_headers(stream_id, &trailers.to_list(), true);
+        self.flush()
+    }
+
+    pub fn reset_stream(&mut self, stream_id: u31, error_code: u32) -> Result<(), ContextError> {
+        self.tunnel_backlog.remove(&stream_id);
+        if let Some(mut outgoing) = self.remove_outgoing(&stream_id) {
+            if let OutgoingSource::Tunnel(tunnel) = &mut outgoing.source {
+                tunnel.on_reset();
+            }
+        }
+        self.streams.remove(&stream_id);
+        self.queue_frame(
+            FRAME_RST_STREAM,
+            0,
+            stream_id.to_u32(),
+            &frames::rst_stream_payload(error_code),
+        );
+        self.flush()
+    }
+
+    /// Sends DATA frames for every streamed body while both the connection
+    /// and the stream windows have room, then flushes.
+    pub fn pump_outgoing(&mut self) -> Result<(), ContextError> {
+        self.resolve_deferred()?;
+        let stream_ids: Vec<u31> = self.outgoing.keys().cloned().collect();
+        for stream_id in stream_ids {
+            loop {
+                if self.write_buffer.len() >= self.buffer_size * 16 {
+                    break;
+                }
+                let outgoing = match self.outgoing.get_mut(&stream_id) {
+                    Some(outgoing) if !outgoing.local_ended => outgoing,
+                    _ => break,
+                };
+                let budget = i64::min(self.send_window, outgoing.window)
+                    .min(self.max_frame_size as i64);
+                if budget <= 0 {
+                    break;
+                }
+                match outgoing.source.next_chunk(budget as usize) {
+                    Ok(BodyChunk::Data(data)) => {
+                        if data.is_empty() {
+                            continue;
+                        }
+                        outgoing.window -= data.len() as i64;
+                        self.send_window -= data.len() as i64;
+                        self.queue_frame(FRAME_DATA, 0, stream_id.to_u32(), &data);
+                    }
+                    Ok(BodyChunk::Pending) => break,
+                    Ok(BodyChunk::End) => {
+                        // A tunnel stays open until the peer half-closes too.
+                        if let OutgoingSource::Tunnel(_) = outgoing.source {
+                            outgoing.local_ended = true;
+                            if outgoing.remote_ended {
+                                self.remove_outgoing(&stream_id);
+                            }
+                            self.queue_frame(FRAME_DATA, FLAG_END_STREAM, stream_id.to_u32(), &[]);
+                            break;
+                        }
+                        let trailers = self.remove_outgoing(&stream_id).and_then(|o| o.trailers);
+                        match trailers {
+                            Some(trailers) => {
+                                self.queue_headers(stream_id, &trailers.to_list(), true);
+                            }
+                            None => {
+                                self.queue_frame(FRAME_DATA, FLAG_END_STREAM, stream_id.to_u32(), &[])
+                            }
+                        }
+                        break;
+                    }
+                    Ok(BodyChunk::Trailers(trailers)) => {
+                        self.remove_outgoing(&stream_id);
+                        self.queue_headers(stream_id, &Trailers(trailers).to_list(), true);
+                        break;
+                    }
+                    Err(e) => {
+                        log_error!("{}", e);
+                        self.remove_outgoing(&stream_id);
+                        self.queue_frame(
+                            FRAME_RST_STREAM,
+                            0,
+                            stream_id.to_u32(),
+                            &frames::rst_stream_payload(frames::INTERNAL_ERROR),
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        self.flush()
+    }
+
+    /// Sends the responses of `Deferred` handlers that became ready. The
+    /// socket they waited on keeps its token for their body.
+    fn resolve_deferred(&mut self) -> Result<(), ContextError> {
+        let mut ready = Vec::new();
+        for (stream_id, outgoing) in self.outgoing.iter_mut() {
+            let deferred = match &mut outgoing.source {
+                OutgoingSource::Deferred(deferred) => deferred,
+                _ => continue,
+            };
+            match deferred.poll_response() {
+                Ok(Some(response)) => ready.push((*stream_id, response)),
+                Ok(None) => {}
+                Err(e) => {
+                    log_error!("{}", e);
+                    let response = Response::builder()
+                        .status(http::StatusCode::BAD_GATEWAY)
+                        .body(Vec::new())
+                        .unwrap();
+                    ready.push((*stream_id, response));
+                }
+            }
+        }
+        for (stream_id, response) in ready {
+            let source_token = match self.outgoing.remove(&stream_id) {
+                Some(outgoing) => outgoing.source_token,
+                None => continue,
+            };
+            self.metrics.response_finished();
+            self.send_http_response(stream_id, response)?;
+            if let Some(token) = source_token {
+                match self.outgoing.get_mut(&stream_id) {
+                    Some(outgoing) if outgoing.source_token.is_none() => {
+                        if outgoing.source.source().is_some() {
+                            outgoing.source_token = Some(token);
+                        } else {
+                            self.released_tokens.push(token);
+                        }
+                    }
+                    _ => self.released_tokens.push(token),
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn send_response(
@@ -361,41 +1357,77 @@ impl Http2Context {
             Padding: None,
             Priority: None,
         };
-        let headers_payload = Payload::Headers(headers_payload);
-        self.connection
-            .write(&<Payload as Into<Vec<u8>>>::into(headers_payload))?;
-
-        if data.is_some() {
-            let data_payload = DataPayload {
-                PadLength: None,
-                Padding: None,
-                data: data.unwrap(),
-            };
-            let data_paylaod = Payload::Data(data_payload);
-            self.connection
-                .write(&<Payload as Into<Vec<u8>>>::into(data_paylaod))?;
-        }
+        let block = <Payload as Into<Vec<u8>>>::into(Payload::Headers(headers_payload));
+        self.queue_header_block(stream_id, block, data.is_none(), None);
 
-        Ok(())
+        match data {
+            Some(data) => self.send_body(stream_id, Box::new(BytesBody::new(data))),
+            None => self.flush(),
+        }
     }
 
     pub fn send_http_response(
         &mut self,
         stream_id: u31,
-        response: Response<Vec<u8>>,
+        mut response: Response<Vec<u8>>,
     ) -> Result<(), ContextError> {
-        let mut hpack = Hpack::new();
-        let l: Vec<(Vec<u8>, Vec<u8>)> = response
-            .headers()
-            .iter()
-            .map(|(key, value)| {
-                // Convert HeaderValue to String for easier use
-                let key_bytes = key.as_str().as_bytes();
-                let value_bytes = value.to_str().unwrap_or_default().as_bytes();
-                (key_bytes.to_vec(), value_bytes.to_vec())
-            })
-            .collect();
-        hpack.encode(&l, &mut self.hpack_context);
-        self.send_response(stream_id, hpack, Some(response.body().to_owned()))
+        let mut headers = vec![(
+            b":status".to_vec(),
+            response.status().as_str().as_bytes().to_vec(),
+        )];
+        headers.extend(response.headers().iter().map(|(key, value)| {
+            let key_bytes = key.as_str().as_bytes();
+            let value_bytes = value.as_bytes();
+            (key_bytes.to_vec(), value_bytes.to_vec())
+        }));
+
+        if let Some(tunnel) = response
+            .extensions_mut()
+            .remove::<Upgrade>()
+            .and_then(|upgrade| upgrade.take())
+        {
+            self.send_headers(stream_id, headers, false)?;
+            return self.open_tunnel(stream_id, tunnel);
+        }
+        if let Some(deferred) = response
+            .extensions_mut()
+            .remove::<Deferred>()
+            .and_then(|deferred| deferred.take())
+        {
+            self.insert_outgoing(
+                stream_id,
+                OutgoingBody::new(
+                    OutgoingSource::Deferred(deferred),
+                    self.nax_window_size as i64,
+                    None,
+                ),
+            );
+            return self.pump_outgoing();
+        }
+        self.tunnel_backlog.remove(&stream_id);
+
+        let trailers = response.extensions_mut().remove::<Trailers>();
+        let streaming = response
+            .extensions_mut()
+            .remove::<StreamingBody>()
+            .and_then(|body| body.take());
+        let body: Option<Box<dyn BodyStream>> = match streaming {
+            Some(body) => Some(body),
+            None if response.body().is_empty() && trailers.is_none() => None,
+            None => Some(Box::new(BytesBody::new(response.into_body()))),
+        };
+
+        self.send_headers(stream_id, headers, body.is_none())?;
+        match body {
+            Some(body) => self.send_body_with_trailers(stream_id, body, trailers),
+            None => Ok(()),
+        }
     }
 }
+
+fn payload_too_large() -> Response<Vec<u8>> {
+    Response::builder()
+        .status(http::StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Vec::new())
+        .unwrap()
+}
@@ -0,0 +1,131 @@
+// Frame types, flags and error codes of RFC 9113, used when writing frames.
+// https://datatracker.ietf.org/doc/html/rfc9113#name-frame-definitions
+
+/// What a client sends first on every connection.
+/// https://datatracker.ietf.org/doc/html/rfc9113#section-3.4
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub const FRAME_DATA: u8 = 0x0;
+pub const FRAME_HEADERS: u8 = 0x1;
+pub const FRAME_PRIORITY: u8 = 0x2;
+pub const FRAME_RST_STREAM: u8 = 0x3;
+pub const FRAME_SETTINGS: u8 = 0x4;
+pub const FRAME_PUSH_PROMISE: u8 = 0x5;
+pub const FRAME_PING: u8 = 0x6;
+pub const FRAME_GOAWAY: u8 = 0x7;
+pub const FRAME_WINDOW_UPDATE: u8 = 0x8;
+pub const FRAME_CONTINUATION: u8 = 0x9;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const SETTINGS_TIMEOUT: u32 = 0x4;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const CANCEL: u32 = 0x8;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const CONNECT_ERROR: u32 = 0xa;
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+pub const INADEQUATE_SECURITY: u32 = 0xc;
+pub const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+/// Frame type names, indexed by type.
+pub const FRAME_NAMES: [&str; 10] = [
+    "DATA",
+    "HEADERS",
+    "PRIORITY",
+    "RST_STREAM",
+    "SETTINGS",
+    "PUSH_PROMISE",
+    "PING",
+    "GOAWAY",
+    "WINDOW_UPDATE",
+    "CONTINUATION",
+];
+
+/// Error code names, indexed by code.
+pub const ERROR_NAMES: [&str; 14] = [
+    "NO_ERROR",
+    "PROTOCOL_ERROR",
+    "INTERNAL_ERROR",
+    "FLOW_CONTROL_ERROR",
+    "SETTINGS_TIMEOUT",
+    "STREAM_CLOSED",
+    "FRAME_SIZE_ERROR",
+    "REFUSED_STREAM",
+    "CANCEL",
+    "COMPRESSION_ERROR",
+    "CONNECT_ERROR",
+    "ENHANCE_YOUR_CALM",
+    "INADEQUATE_SECURITY",
+    "HTTP_1_1_REQUIRED",
+];
+
+pub fn frame_name(frame_type: u8) -> Option<&'static str> {
+    FRAME_NAMES.get(frame_type as usize).copied()
+}
+
+pub fn error_name(error_code: u32) -> Option<&'static str> {
+    ERROR_NAMES.get(error_code as usize).copied()
+}
+
+// https://datatracker.ietf.org/doc/html/rfc8441#section-3
+pub const SETTINGS_ENABLE_CONNECT_PROTOCOL: u16 = 0x8;
+
+pub const DEFAULT_WINDOW_SIZE: i64 = 65535;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+pub const MAX_WINDOW_SIZE: i64 = 0x7fff_ffff;
+
+/// The length of the frame at the start of `buf`, header included, once the
+/// header is complete.
+pub fn frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 9 {
+        return None;
+    }
+    Some(9 + u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize)
+}
+
+/// Serializes a frame header followed by `payload`.
+pub fn encode_frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len() as u32;
+    let mut result = Vec::with_capacity(9 + payload.len());
+    result.extend_from_slice(&len.to_be_bytes()[1..4]);
+    result.push(frame_type);
+    result.push(flags);
+    result.extend_from_slice(&(stream_id & 0x7fff_ffff).to_be_bytes());
+    result.extend_from_slice(payload);
+    result
+}
+
+pub fn settings_payload(settings: &[(u16, u32)]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(settings.len() * 6);
+    for (id, value) in settings {
+        result.extend_from_slice(&id.to_be_bytes());
+        result.extend_from_slice(&value.to_be_bytes());
+    }
+    result
+}
+
+pub fn window_update_payload(increment: u32) -> Vec<u8> {
+    (increment & 0x7fff_ffff).to_be_bytes().to_vec()
+}
+
+pub fn rst_stream_payload(error_code: u32) -> Vec<u8> {
+    error_code.to_be_bytes().to_vec()
+}
+
+pub fn goaway_payload(last_stream_id: u32, error_code: u32, debug_data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(8 + debug_data.len());
+    result.extend_from_slice(&(last_stream_id & 0x7fff_ffff).to_be_bytes());
+    result.extend_from_slice(&error_code.to_be_bytes());
+    result.extend_from_slice(debug_data);
+    result
+}
@@ -0,0 +1,570 @@
+use std::{collections::HashMap, fmt::Display, io};
+
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use mio::{event::Source, Token};
+
+use super::{
+    BodyChunk, BodyStream, Handler, QueueReceiver, QueueSender, Queued, StreamingBody, Trailers,
+};
+
+// https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+pub const GRPC_OK: u32 = 0;
+pub const GRPC_CANCELLED: u32 = 1;
+pub const GRPC_UNKNOWN: u32 = 2;
+pub const GRPC_INVALID_ARGUMENT: u32 = 3;
+pub const GRPC_DEADLINE_EXCEEDED: u32 = 4;
+pub const GRPC_NOT_FOUND: u32 = 5;
+pub const GRPC_ALREADY_EXISTS: u32 = 6;
+pub const GRPC_PERMISSION_DENIED: u32 = 7;
+pub const GRPC_RESOURCE_EXHAUSTED: u32 = 8;
+pub const GRPC_FAILED_PRECONDITION: u32 = 9;
+pub const GRPC_ABORTED: u32 = 10;
+pub const GRPC_OUT_OF_RANGE: u32 = 11;
+pub const GRPC_UNIMPLEMENTED: u32 = 12;
+pub const GRPC_INTERNAL: u32 = 13;
+pub const GRPC_UNAVAILABLE: u32 = 14;
+pub const GRPC_DATA_LOSS: u32 = 15;
+pub const GRPC_UNAUTHENTICATED: u32 = 16;
+
+/// The outcome of a call, sent in the `grpc-status` and `grpc-message` trailers.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub code: u32,
+    pub message: String,
+}
+
+impl Status {
+    pub fn new(code: u32, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn ok() -> Self {
+        Self::new(GRPC_OK, "")
+    }
+
+    fn to_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("grpc-status", HeaderValue::from(self.code));
+        if !self.message.is_empty() {
+            if let Ok(message) = HeaderValue::from_str(&percent_encode(&self.message)) {
+                headers.insert("grpc-message", message);
+            }
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "grpc-status {}: {}", self.code, self.message)
+    }
+}
+
+/// Prefixes a message with the compressed flag and its 4-byte length.
+pub fn encode_message(message: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(5 + message.len());
+    result.push(0);
+    result.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    result.extend_from_slice(message);
+    result
+}
+
+/// Splits a request body into its length-prefixed messages.
+pub fn decode_messages(mut body: &[u8]) -> Result<Vec<Vec<u8>>, Status> {
+    let mut messages = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 5 {
+            return Err(Status::new(GRPC_INTERNAL, "Truncated Message Prefix"));
+        }
+        if body[0] != 0 {
+            return Err(Status::new(
+                GRPC_UNIMPLEMENTED,
+                "Compressed Messages Are Not Supported",
+            ));
+        }
+        let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        if body.len() - 5 < len {
+            return Err(Status::new(GRPC_INTERNAL, "Truncated Message"));
+        }
+        messages.push(body[5..5 + len].to_vec());
+        body = &body[5 + len..];
+    }
+    Ok(messages)
+}
+
+/// One step of a server-streaming call.
+#[derive(Debug)]
+pub enum MessageChunk {
+    Message(Vec<u8>),
+    /// Nothing to send right now, the call stays open.
+    Pending,
+    /// Ends the call with the status trailers.
+    End(Status),
+}
+
+/// The responses of a server-streaming call, pulled while the stream's
+/// flow-control window allows it.
+pub trait MessageStream: Send {
+    fn next_message(&mut self) -> io::Result<MessageChunk>;
+
+    /// Registered in the server's `Poll` like `BodyStream::source`, so a
+    /// `Pending` stream is pulled again once it becomes readable.
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        None
+    }
+}
+
+/// Messages that are known upfront or cheap to compute.
+pub struct MessageIter<I>(I);
+
+impl<I> MessageIter<I>
+where
+    I: Iterator<Item = Result<Vec<u8>, Status>> + Send,
+{
+    pub fn new(messages: I) -> Self {
+        Self(messages)
+    }
+}
+
+impl<I> MessageStream for MessageIter<I>
+where
+    I: Iterator<Item = Result<Vec<u8>, Status>> + Send,
+{
+    fn next_message(&mut self) -> io::Result<MessageChunk> {
+        Ok(match self.0.next() {
+            Some(Ok(message)) => MessageChunk::Message(message),
+            Some(Err(status)) => MessageChunk::End(status),
+            None => MessageChunk::End(Status::ok()),
+        })
+    }
+}
+
+/// Handle sending the responses of a server-streaming call from outside the
+/// handler, e.g. from another thread.
+#[derive(Clone)]
+pub struct MessageSender {
+    queue: QueueSender<Status>,
+}
+
+impl MessageSender {
+    pub fn send(&self, message: &[u8]) -> io::Result<()> {
+        self.queue.send(message.to_vec())
+    }
+
+    /// Ends the call with `status` once every message sent so far was written.
+    pub fn finish(&self, status: Status) {
+        self.queue.end(status);
+    }
+
+    /// The client cancelled the call or went away.
+    pub fn is_disconnected(&self) -> bool {
+        self.queue.is_disconnected()
+    }
+}
+
+/// The receiving end of a `MessageSender`, returned by a server-streaming
+/// method to produce its responses from another thread.
+pub struct MessageChannel {
+    queue: QueueReceiver<Status>,
+}
+
+impl MessageChannel {
+    pub fn new() -> io::Result<(MessageSender, MessageChannel)> {
+        let (queue, receiver) = QueueReceiver::new()?;
+        Ok((MessageSender { queue }, MessageChannel { queue: receiver }))
+    }
+}
+
+impl MessageStream for MessageChannel {
+    fn next_message(&mut self) -> io::Result<MessageChunk> {
+        Ok(match self.queue.next_item()? {
+            Queued::Data(message) => MessageChunk::Message(message),
+            Queued::Pending => MessageChunk::Pending,
+            Queued::End(Some(status)) => MessageChunk::End(status),
+            // The producer went away without finishing the call.
+            Queued::End(None) => {
+                MessageChunk::End(Status::new(GRPC_UNKNOWN, "message sender dropped"))
+            }
+        })
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        Some(self.queue.source())
+    }
+}
+
+type UnaryMethod = Box<dyn Fn(Token, &Request<Vec<u8>>, Vec<u8>) -> Result<Vec<u8>, Status>>;
+type ServerStreamingMethod =
+    Box<dyn Fn(Token, &Request<Vec<u8>>, Vec<u8>) -> Result<Box<dyn MessageStream>, Status>>;
+
+enum GrpcMethod {
+    Unary(UnaryMethod),
+    ServerStreaming(ServerStreamingMethod),
+}
+
+/// Streams the messages of a server-streaming call, then the status trailers.
+struct MessageStreamBody {
+    messages: Box<dyn MessageStream>,
+    pending: Vec<u8>,
+}
+
+impl BodyStream for MessageStreamBody {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        if self.pending.is_empty() {
+            match self.messages.next_message()? {
+                MessageChunk::Message(message) => self.pending = encode_message(&message),
+                MessageChunk::Pending => return Ok(BodyChunk::Pending),
+                MessageChunk::End(status) => {
+                    let mut trailers = HeaderMap::new();
+                    status.to_headers(&mut trailers);
+                    return Ok(BodyChunk::Trailers(trailers));
+                }
+            }
+        }
+        let len = usize::min(max_len, self.pending.len());
+        Ok(BodyChunk::Data(self.pending.drain(0..len).collect()))
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        self.messages.source()
+    }
+}
+
+/// A handler dispatching gRPC calls to the registered methods by `:path`.
+pub struct Grpc {
+    methods: HashMap<String, GrpcMethod>,
+}
+
+impl Default for Grpc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Grpc {
+    pub fn new() -> Self {
+        Self {
+            methods: HashMap::new(),
+        }
+    }
+
+    pub fn unary<F>(mut self, service: &str, method: &str, handler: F) -> Self
+    where
+        F: Fn(Token, &Request<Vec<u8>>, Vec<u8>) -> Result<Vec<u8>, Status> + 'static,
+    {
+        self.methods.insert(
+            format!("/{}/{}", service, method),
+            GrpcMethod::Unary(Box::new(handler)),
+        );
+        self
+    }
+
+    pub fn server_streaming<F>(mut self, service: &str, method: &str, handler: F) -> Self
+    where
+        F: Fn(Token, &Request<Vec<u8>>, Vec<u8>) -> Result<Box<dyn MessageStream>, Status>
+            + 'static,
+    {
+        self.methods.insert(
+            format!("/{}/{}", service, method),
+            GrpcMethod::ServerStreaming(Box::new(handler)),
+        );
+        self
+    }
+
+    fn dispatch(
+        &self,
+        token: Token,
+        request: &Request<Vec<u8>>,
+    ) -> Result<Response<Vec<u8>>, Status> {
+        let method = self
+            .methods
+            .get(request.uri().path())
+            .ok_or_else(|| Status::new(GRPC_UNIMPLEMENTED, "Unknown Method"))?;
+
+        let mut messages = decode_messages(request.body())?;
+        if messages.len() != 1 {
+            return Err(Status::new(
+                GRPC_UNIMPLEMENTED,
+                "Exactly One Request Message Is Expected",
+            ));
+        }
+        let message = messages.remove(0);
+
+        let mut response = grpc_response();
+        match method {
+            GrpcMethod::Unary(handler) => {
+                *response.body_mut() = encode_message(&handler(token, request, message)?);
+                let mut trailers = Trailers::default();
+                Status::ok().to_headers(&mut trailers.0);
+                response.extensions_mut().insert(trailers);
+            }
+            GrpcMethod::ServerStreaming(handler) => {
+                let messages = handler(token, request, message)?;
+                response
+                    .extensions_mut()
+                    .insert(StreamingBody::new(MessageStreamBody {
+                        messages,
+                        pending: Vec::new(),
+                    }));
+            }
+        }
+        Ok(response)
+    }
+}
+
+impl Handler for Grpc {
+    fn call(&self, token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        if request.method() != Method::POST {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, "POST")
+                .body(Vec::new())
+                .unwrap();
+        }
+        let is_grpc = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value == "application/grpc" || value.starts_with("application/grpc+")
+            });
+        if !is_grpc {
+            return Response::builder()
+                .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                .body(Vec::new())
+                .unwrap();
+        }
+
+        match self.dispatch(token, &request) {
+            Ok(response) => response,
+            Err(status) => {
+                // Trailers-Only response, the status goes with the headers.
+                let mut response = grpc_response();
+                status.to_headers(response.headers_mut());
+                response
+            }
+        }
+    }
+}
+
+fn grpc_response() -> Response<Vec<u8>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/grpc")
+        .body(Vec::new())
+        .unwrap()
+}
+
+/// Percent-encodes `grpc-message` as required by the gRPC HTTP/2 protocol.
+fn percent_encode(message: &str) -> String {
+    let mut result = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::MAX_QUEUED, *};
+
+    fn call(grpc: &Grpc, path: &str, body: Vec<u8>) -> Response<Vec<u8>> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .body(body)
+            .unwrap();
+        grpc.call(Token(0), request)
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    fn echo() -> Grpc {
+        Grpc::new()
+            .unary("test.Echo", "Say", |_, _, message| Ok(message))
+            .unary("test.Echo", "Fail", |_, _, _| {
+                Err(Status::new(GRPC_INVALID_ARGUMENT, "bad 100%\n"))
+            })
+            .server_streaming("test.Echo", "Repeat", |_, _, message| {
+                Ok(Box::new(MessageIter::new(
+                    vec![Ok(message.clone()), Ok(message)].into_iter(),
+                )))
+            })
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut body = encode_message(b"hello");
+        assert_eq!(&body[..5], &[0, 0, 0, 0, 5]);
+        body.extend(encode_message(b""));
+        body.extend(encode_message(&[7; 300]));
+        assert_eq!(
+            decode_messages(&body).unwrap(),
+            vec![b"hello".to_vec(), Vec::new(), vec![7; 300]]
+        );
+        assert!(decode_messages(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let code = |body: &[u8]| decode_messages(body).unwrap_err().code;
+        assert_eq!(code(&[0, 0, 0]), GRPC_INTERNAL);
+        assert_eq!(code(&[0, 0, 0, 0, 4, 1, 2, 3]), GRPC_INTERNAL);
+        assert_eq!(code(&[1, 0, 0, 0, 1, 1]), GRPC_UNIMPLEMENTED);
+    }
+
+    #[test]
+    fn unary_calls_end_with_ok_trailers() {
+        let response = call(&echo(), "/test.Echo/Say", encode_message(b"hi"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), &encode_message(b"hi"));
+        assert_eq!(header(response.headers(), "grpc-status"), None);
+        let trailers = response.extensions().get::<Trailers>().unwrap();
+        assert_eq!(header(&trailers.0, "grpc-status"), Some("0"));
+    }
+
+    #[test]
+    fn errors_are_trailers_only() {
+        let grpc = echo();
+        let cases = [
+            (
+                "/test.Echo/Fail",
+                encode_message(b"hi"),
+                GRPC_INVALID_ARGUMENT,
+            ),
+            (
+                "/test.Echo/Missing",
+                encode_message(b"hi"),
+                GRPC_UNIMPLEMENTED,
+            ),
+            ("/test.Echo/Say", vec![0, 0], GRPC_INTERNAL),
+            ("/test.Echo/Say", Vec::new(), GRPC_UNIMPLEMENTED),
+        ];
+        for (path, body, code) in cases {
+            let response = call(&grpc, path, body);
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.body().is_empty());
+            assert!(response.extensions().get::<Trailers>().is_none());
+            assert!(response.extensions().get::<StreamingBody>().is_none());
+            assert_eq!(
+                header(response.headers(), "grpc-status"),
+                Some(code.to_string().as_str())
+            );
+        }
+
+        let response = call(&grpc, "/test.Echo/Fail", encode_message(b""));
+        assert_eq!(
+            header(response.headers(), "grpc-message"),
+            Some("bad 100%25%0A")
+        );
+    }
+
+    #[test]
+    fn non_grpc_requests_are_refused() {
+        let get = Request::builder()
+            .uri("/test.Echo/Say")
+            .body(Vec::new())
+            .unwrap();
+        assert_eq!(
+            echo().call(Token(0), get).status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        let json = Request::builder()
+            .method(Method::POST)
+            .uri("/test.Echo/Say")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Vec::new())
+            .unwrap();
+        assert_eq!(
+            echo().call(Token(0), json).status(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[test]
+    fn server_streaming_sends_messages_then_trailers() {
+        let response = call(&echo(), "/test.Echo/Repeat", encode_message(b"hi"));
+        let mut body = response
+            .extensions()
+            .get::<StreamingBody>()
+            .and_then(|body| body.take())
+            .unwrap();
+        let mut data = Vec::new();
+        loop {
+            match body.next_chunk(3).unwrap() {
+                BodyChunk::Data(chunk) => data.extend(chunk),
+                BodyChunk::Trailers(trailers) => {
+                    assert_eq!(header(&trailers, "grpc-status"), Some("0"));
+                    break;
+                }
+                chunk => panic!("unexpected {:?}", chunk),
+            }
+        }
+        assert_eq!(decode_messages(&data).unwrap(), vec![b"hi".to_vec(); 2]);
+    }
+
+    #[test]
+    fn channels_wait_for_the_sender() {
+        let (sender, channel) = MessageChannel::new().unwrap();
+        let mut body = MessageStreamBody {
+            messages: Box::new(channel),
+            pending: Vec::new(),
+        };
+        assert!(body.source().is_some());
+        assert!(matches!(body.next_chunk(1024).unwrap(), BodyChunk::Pending));
+
+        sender.send(b"one").unwrap();
+        sender.finish(Status::new(GRPC_ABORTED, "stop"));
+        assert!(sender.send(b"two").is_err());
+        match body.next_chunk(1024).unwrap() {
+            BodyChunk::Data(data) => assert_eq!(data, encode_message(b"one")),
+            chunk => panic!("unexpected {:?}", chunk),
+        }
+        match body.next_chunk(1024).unwrap() {
+            BodyChunk::Trailers(trailers) => {
+                assert_eq!(header(&trailers, "grpc-status"), Some("10"));
+                assert_eq!(header(&trailers, "grpc-message"), Some("stop"));
+            }
+            chunk => panic!("unexpected {:?}", chunk),
+        }
+    }
+
+    #[test]
+    fn dropped_senders_fail_the_call() {
+        let (sender, mut channel) = MessageChannel::new().unwrap();
+        sender.send(b"one").unwrap();
+        drop(sender);
+        assert!(matches!(
+            channel.next_message().unwrap(),
+            MessageChunk::Message(message) if message == b"one"
+        ));
+        match channel.next_message().unwrap() {
+            MessageChunk::End(status) => assert_eq!(status.code, GRPC_UNKNOWN),
+            chunk => panic!("unexpected {:?}", chunk),
+        }
+    }
+
+    #[test]
+    fn slow_clients_push_back_on_the_sender() {
+        let (sender, channel) = MessageChannel::new().unwrap();
+        let message = vec![0; MAX_QUEUED / 2];
+        sender.send(&message).unwrap();
+        sender.send(&message).unwrap();
+        let err = sender.send(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        assert!(!sender.is_disconnected());
+        drop(channel);
+        assert!(sender.is_disconnected());
+        let err = sender.send(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
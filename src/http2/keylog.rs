@@ -0,0 +1,110 @@
+use std::{
+    env,
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+// TLS secrets in the NSS key log format read by Wireshark, so that captures of
+// TLS connections can be decrypted. The crate has no TLS `Transport` of its
+// own: one built on a TLS library hands the secrets of each handshake to a
+// `KeyLog`, nothing is logged unless one is given.
+// https://firefox-source-docs.mozilla.org/security/nss/legacy/key_log_format/
+
+/// Receives the secrets negotiated by TLS handshakes.
+pub trait KeyLog: Send + Sync {
+    /// `label` is the NSS label of `secret`, `CLIENT_RANDOM` for TLS 1.2 or
+    /// `CLIENT_TRAFFIC_SECRET_0` for example, `client_random` identifies the
+    /// handshake.
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]);
+}
+
+/// Appends secrets to a key log file, one line each.
+pub struct KeyLogWriter<W: Write + Send> {
+    out: Mutex<W>,
+}
+
+impl KeyLogWriter<File> {
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Appends to the file named by `SSLKEYLOGFILE`, `None` when it is unset
+    /// or empty.
+    pub fn from_env() -> Option<io::Result<Self>> {
+        let path = env::var_os("SSLKEYLOGFILE").filter(|path| !path.is_empty())?;
+        Some(Self::create(path))
+    }
+}
+
+impl<W: Write + Send> KeyLogWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write + Send> KeyLog for KeyLogWriter<W> {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format_line(label, client_random, secret);
+        // A single write per line, so that processes appending to the same
+        // file don't interleave.
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = out.write_all(line.as_bytes()).and_then(|_| out.flush());
+    }
+}
+
+/// `<label> <client random> <secret>`, both hex encoded, with a newline.
+pub fn format_line(label: &str, client_random: &[u8], secret: &[u8]) -> String {
+    let mut line =
+        String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+    line.push_str(label);
+    for bytes in [client_random, secret] {
+        line.push(' ');
+        for byte in bytes {
+            let _ = write!(line, "{:02x}", byte);
+        }
+    }
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_in_nss_format() {
+        let log = KeyLogWriter::new(Vec::new());
+        log.log("CLIENT_RANDOM", &[0x01, 0xab], &[0xff, 0x00, 0x10]);
+        log.log("SERVER_TRAFFIC_SECRET_0", &[0x02], &[0x03]);
+        assert_eq!(
+            String::from_utf8(log.into_inner()).unwrap(),
+            "CLIENT_RANDOM 01ab ff0010\nSERVER_TRAFFIC_SECRET_0 02 03\n"
+        );
+    }
+
+    #[test]
+    fn files_are_appended_to() {
+        let path = env::temp_dir().join(format!("khttp-keylog-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        KeyLogWriter::create(&path)
+            .unwrap()
+            .log("CLIENT_RANDOM", &[1], &[2]);
+        KeyLogWriter::create(&path)
+            .unwrap()
+            .log("CLIENT_RANDOM", &[3], &[4]);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "CLIENT_RANDOM 01 02\nCLIENT_RANDOM 03 04\n");
+    }
+}
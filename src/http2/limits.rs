@@ -0,0 +1,739 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use mio::Token;
+
+use super::frames::{
+    FLAG_ACK, FLAG_END_STREAM, FLAG_PADDED, FRAME_CONTINUATION, FRAME_DATA, FRAME_PING,
+    FRAME_PRIORITY, FRAME_RST_STREAM, FRAME_SETTINGS,
+};
+
+/// A token bucket allowing `burst` events at once, refilled by `rate` per
+/// second.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self { rate, burst }
+    }
+}
+
+/// What happens to a stream opened over the rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// RST_STREAM with REFUSED_STREAM, the client may retry it.
+    Refuse,
+    /// A `429 Too Many Requests` response with `retry-after`.
+    TooManyRequests,
+}
+
+/// Connection caps and stream rate limits of an `Http2Server`.
+///
+/// Connections over a cap get the server's SETTINGS followed by a GOAWAY
+/// with NO_ERROR instead of being served.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    streams_per_connection: Option<RateLimit>,
+    streams_per_ip: Option<RateLimit>,
+    action: RateLimitAction,
+    flood: FloodLimits,
+    body: BodyLimits,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Self {
+            max_connections: None,
+            max_connections_per_ip: None,
+            streams_per_connection: None,
+            streams_per_ip: None,
+            action: RateLimitAction::Refuse,
+            flood: FloodLimits::new(),
+            body: BodyLimits::new(),
+        }
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+
+    pub fn streams_per_connection(mut self, limit: RateLimit) -> Self {
+        self.streams_per_connection = Some(limit);
+        self
+    }
+
+    pub fn streams_per_ip(mut self, limit: RateLimit) -> Self {
+        self.streams_per_ip = Some(limit);
+        self
+    }
+
+    pub fn action(mut self, action: RateLimitAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn flood(mut self, flood: FloodLimits) -> Self {
+        self.flood = flood;
+        self
+    }
+
+    pub fn body(mut self, body: BodyLimits) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+/// What happens to a request whose body is over its `BodyLimits` cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLimitAction {
+    /// A `413 Payload Too Large` response, followed by RST_STREAM with
+    /// NO_ERROR when the client is still sending.
+    PayloadTooLarge,
+    /// RST_STREAM with CANCEL.
+    Reset,
+}
+
+/// Caps on the size of request bodies, none by default.
+///
+/// A body whose `content-length` is over the cap is rejected with its
+/// headers, before any DATA, one without is rejected once its DATA goes
+/// over it.
+#[derive(Debug, Clone)]
+pub struct BodyLimits {
+    max: Option<usize>,
+    routes: Vec<(Vec<u8>, Option<usize>)>,
+    action: BodyLimitAction,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BodyLimits {
+    pub fn new() -> Self {
+        Self {
+            max: None,
+            routes: Vec::new(),
+            action: BodyLimitAction::PayloadTooLarge,
+        }
+    }
+
+    /// The cap of requests no route matches.
+    pub fn max(mut self, max: usize) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    /// The cap of requests whose path starts with `prefix`, `None` lifting
+    /// it. The longest matching prefix applies.
+    pub fn route(mut self, prefix: &str, max: Option<usize>) -> Self {
+        self.routes.push((prefix.as_bytes().to_vec(), max));
+        self
+    }
+
+    pub fn action(mut self, action: BodyLimitAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// The cap of a request to `path`.
+    pub(crate) fn limit(&self, path: &[u8]) -> Option<usize> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.max, |(_, max)| *max)
+    }
+
+    pub(crate) fn exceeded_action(&self) -> BodyLimitAction {
+        self.action
+    }
+}
+
+/// Budgets of frames a peer may send per `window` before the connection is
+/// closed with an ENHANCE_YOUR_CALM GOAWAY. They cover frames that cost the
+/// server work without producing requests: rapid reset (CVE-2023-44487),
+/// PING and SETTINGS floods, empty DATA frames and endless CONTINUATION.
+#[derive(Debug, Clone)]
+pub struct FloodLimits {
+    window: Duration,
+    control_frames: u32,
+    reset_streams: u32,
+    empty_data_frames: u32,
+    continuation_bytes: usize,
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FloodLimits {
+    pub fn new() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            control_frames: 500,
+            reset_streams: 200,
+            empty_data_frames: 100,
+            continuation_bytes: 1024 * 1024,
+        }
+    }
+
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// PING, SETTINGS and PRIORITY frames, acknowledgements excluded.
+    pub fn control_frames(mut self, max: u32) -> Self {
+        self.control_frames = max;
+        self
+    }
+
+    /// RST_STREAM frames received.
+    pub fn reset_streams(mut self, max: u32) -> Self {
+        self.reset_streams = max;
+        self
+    }
+
+    /// DATA frames carrying no data that don't end their stream.
+    pub fn empty_data_frames(mut self, max: u32) -> Self {
+        self.empty_data_frames = max;
+        self
+    }
+
+    /// Bytes of CONTINUATION frames, frame headers included.
+    pub fn continuation_bytes(mut self, max: usize) -> Self {
+        self.continuation_bytes = max;
+        self
+    }
+}
+
+/// Counts the frames of one connection against its `FloodLimits`.
+pub(crate) struct FloodGuard {
+    limits: FloodLimits,
+    window_start: Instant,
+    control_frames: u32,
+    reset_streams: u32,
+    empty_data_frames: u32,
+    continuation_bytes: usize,
+}
+
+impl FloodGuard {
+    pub(crate) fn new(limits: FloodLimits) -> Self {
+        Self {
+            limits,
+            window_start: Instant::now(),
+            control_frames: 0,
+            reset_streams: 0,
+            empty_data_frames: 0,
+            continuation_bytes: 0,
+        }
+    }
+
+    /// Records one raw frame, returns which budget it exceeded.
+    pub(crate) fn record(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        if frame.len() < 9 {
+            return Ok(());
+        }
+        if self.window_start.elapsed() >= self.limits.window {
+            *self = Self::new(self.limits.clone());
+        }
+        let len = frame.len() - 9;
+        let frame_type = frame[3];
+        let flags = frame[4];
+        match frame_type {
+            FRAME_PING | FRAME_SETTINGS if flags & FLAG_ACK == FLAG_ACK => {}
+            FRAME_PING | FRAME_SETTINGS | FRAME_PRIORITY => {
+                self.control_frames += 1;
+                if self.control_frames > self.limits.control_frames {
+                    return Err("control frame flood");
+                }
+            }
+            FRAME_RST_STREAM => {
+                self.reset_streams += 1;
+                if self.reset_streams > self.limits.reset_streams {
+                    return Err("too many reset streams");
+                }
+            }
+            FRAME_DATA if flags & FLAG_END_STREAM != FLAG_END_STREAM => {
+                let padding = if flags & FLAG_PADDED == FLAG_PADDED && len > 0 {
+                    frame[9] as usize + 1
+                } else {
+                    0
+                };
+                if len <= padding {
+                    self.empty_data_frames += 1;
+                    if self.empty_data_frames > self.limits.empty_data_frames {
+                        return Err("empty data frame flood");
+                    }
+                }
+            }
+            FRAME_CONTINUATION => {
+                self.continuation_bytes += frame.len();
+                if self.continuation_bytes > self.limits.continuation_bytes {
+                    return Err("continuation flood");
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            tokens: limit.burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = f64::min(limit.burst, self.tokens + elapsed * limit.rate);
+        self.last = now;
+    }
+
+    fn has_token(&mut self, limit: &RateLimit) -> bool {
+        self.refill(limit);
+        self.tokens >= 1.0
+    }
+
+    /// Time until the next token is available.
+    fn retry_after(&self, limit: &RateLimit) -> Duration {
+        if limit.rate <= 0.0 {
+            return Duration::from_secs(60);
+        }
+        Duration::from_secs_f64(f64::max(0.0, 1.0 - self.tokens) / limit.rate)
+    }
+}
+
+struct IpState {
+    connections: usize,
+    bucket: Option<TokenBucket>,
+}
+
+/// Book-keeping for `Limits`, owned by the server's event loop.
+pub(crate) struct Limiter {
+    limits: Limits,
+    per_ip: HashMap<IpAddr, IpState>,
+    per_connection: HashMap<Token, (Option<IpAddr>, Option<TokenBucket>)>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            per_ip: HashMap::new(),
+            per_connection: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn action(&self) -> RateLimitAction {
+        self.limits.action
+    }
+
+    pub(crate) fn flood_limits(&self) -> &FloodLimits {
+        &self.limits.flood
+    }
+
+    pub(crate) fn body_limits(&self) -> &BodyLimits {
+        &self.limits.body
+    }
+
+    /// Whether one more connection from `ip` fits within the caps.
+    pub(crate) fn admit(&self, ip: Option<IpAddr>) -> bool {
+        if let Some(max) = self.limits.max_connections {
+            if self.per_connection.len() >= max {
+                return false;
+            }
+        }
+        match (self.limits.max_connections_per_ip, ip) {
+            (Some(max), Some(ip)) => self
+                .per_ip
+                .get(&ip)
+                .map(|state| state.connections < max)
+                .unwrap_or(max > 0),
+            _ => true,
+        }
+    }
+
+    pub(crate) fn opened(&mut self, token: Token, ip: Option<IpAddr>) {
+        let bucket = self
+            .limits
+            .streams_per_connection
+            .as_ref()
+            .map(TokenBucket::new);
+        self.per_connection.insert(token, (ip, bucket));
+        if let Some(ip) = ip {
+            let streams_per_ip = self.limits.streams_per_ip;
+            let state = self.per_ip.entry(ip).or_insert_with(|| IpState {
+                connections: 0,
+                bucket: streams_per_ip.as_ref().map(TokenBucket::new),
+            });
+            state.connections += 1;
+        }
+    }
+
+    pub(crate) fn closed(&mut self, token: Token) {
+        let ip = match self.per_connection.remove(&token) {
+            Some((Some(ip), _)) => ip,
+            _ => return,
+        };
+        if let Some(state) = self.per_ip.get_mut(&ip) {
+            state.connections = state.connections.saturating_sub(1);
+        }
+    }
+
+    /// Takes a token for a new stream on the connection, returns how long to
+    /// wait when over the limit.
+    pub(crate) fn allow_stream(&mut self, token: Token) -> Result<(), Duration> {
+        let (ip, bucket) = match self.per_connection.get_mut(&token) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        let state = ip.and_then(|ip| self.per_ip.get_mut(&ip));
+        let mut buckets = [
+            self.limits
+                .streams_per_connection
+                .as_ref()
+                .zip(bucket.as_mut()),
+            self.limits
+                .streams_per_ip
+                .as_ref()
+                .zip(state.and_then(|state| state.bucket.as_mut())),
+        ];
+        // A stream refused by one bucket doesn't spend a token of the other.
+        for (limit, bucket) in buckets.iter_mut().flatten() {
+            if !bucket.has_token(limit) {
+                return Err(bucket.retry_after(limit));
+            }
+        }
+        for (_, bucket) in buckets.iter_mut().flatten() {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Forgets addresses without connections once their bucket is full again,
+    /// so reconnecting doesn't reset a limit.
+    pub(crate) fn prune(&mut self) {
+        let streams_per_ip = self.limits.streams_per_ip;
+        self.per_ip.retain(|_, state| {
+            if state.connections > 0 {
+                return true;
+            }
+            match (&streams_per_ip, state.bucket.as_mut()) {
+                (Some(limit), Some(bucket)) => {
+                    bucket.refill(limit);
+                    bucket.tokens < limit.burst
+                }
+                _ => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        thread,
+    };
+
+    use super::*;
+    use crate::http2::{
+        context::ContextError,
+        frames,
+        transport::{duplex, Duplex},
+        Http2Context,
+    };
+
+    // :method GET, :scheme http, :path /
+    const GET: &[u8] = &[0x82, 0x86, 0x84];
+
+    fn connect(limits: FloodLimits) -> (Duplex, Http2Context<Duplex>) {
+        let (mut client, server) = duplex().unwrap();
+        let mut context = Http2Context::new(server, None, None);
+        context.set_flood_limits(limits);
+        let mut handshake = frames::PREFACE.to_vec();
+        handshake.extend(frames::encode_frame(FRAME_SETTINGS, 0, 0, &[]));
+        client.write_all(&handshake).unwrap();
+        (client, context)
+    }
+
+    /// Writes `frames` in batches that fit the context's read buffer and
+    /// reads them, returning the first error other than running out of data.
+    fn flood(
+        client: &mut Duplex,
+        context: &mut Http2Context<Duplex>,
+        frames: Vec<Vec<u8>>,
+    ) -> Option<ContextError> {
+        for batch in frames.chunks(64) {
+            client.write_all(&batch.concat()).unwrap();
+            loop {
+                match context.handle_read(false) {
+                    Ok(_) => continue,
+                    Err(ContextError::NoDataReady) => break,
+                    Err(e) => return Some(e),
+                }
+            }
+        }
+        None
+    }
+
+    /// The error code of the first GOAWAY the client received.
+    fn goaway_code(client: &mut Duplex) -> Option<u32> {
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while let Ok(read) = client.read(&mut buffer) {
+            if read == 0 {
+                break;
+            }
+            received.extend(&buffer[..read]);
+        }
+        let mut offset = 0;
+        while offset + 9 <= received.len() {
+            let len = u32::from_be_bytes([
+                0,
+                received[offset],
+                received[offset + 1],
+                received[offset + 2],
+            ]) as usize;
+            let payload = &received[offset + 9..usize::min(received.len(), offset + 9 + len)];
+            if received[offset + 3] == frames::FRAME_GOAWAY && payload.len() >= 8 {
+                return Some(u32::from_be_bytes(payload[4..8].try_into().unwrap()));
+            }
+            offset += 9 + len;
+        }
+        None
+    }
+
+    fn assert_calmed(client: &mut Duplex, error: Option<ContextError>) {
+        assert!(matches!(error, Some(ContextError::EnhanceYourCalm(_))));
+        assert_eq!(goaway_code(client), Some(frames::ENHANCE_YOUR_CALM));
+    }
+
+    #[test]
+    fn rapid_reset() {
+        let (mut client, mut context) = connect(FloodLimits::new().reset_streams(10));
+        let mut attack = Vec::new();
+        for stream_id in (1..200).step_by(2) {
+            attack.push(frames::encode_frame(
+                frames::FRAME_HEADERS,
+                frames::FLAG_END_HEADERS | FLAG_END_STREAM,
+                stream_id,
+                GET,
+            ));
+            attack.push(frames::encode_frame(
+                FRAME_RST_STREAM,
+                0,
+                stream_id,
+                &frames::rst_stream_payload(frames::CANCEL),
+            ));
+        }
+        let error = flood(&mut client, &mut context, attack);
+        assert_calmed(&mut client, error);
+    }
+
+    #[test]
+    fn ping_flood() {
+        let (mut client, mut context) = connect(FloodLimits::new().control_frames(20));
+        let attack = (0..100u64)
+            .map(|i| frames::encode_frame(FRAME_PING, 0, 0, &i.to_be_bytes()))
+            .collect();
+        let error = flood(&mut client, &mut context, attack);
+        assert_calmed(&mut client, error);
+    }
+
+    #[test]
+    fn settings_flood() {
+        let (mut client, mut context) = connect(FloodLimits::new().control_frames(20));
+        let attack = (0..100)
+            .map(|_| frames::encode_frame(FRAME_SETTINGS, 0, 0, &[]))
+            .collect();
+        let error = flood(&mut client, &mut context, attack);
+        assert_calmed(&mut client, error);
+    }
+
+    #[test]
+    fn empty_data_flood() {
+        let (mut client, mut context) = connect(FloodLimits::new().empty_data_frames(20));
+        let mut attack = vec![frames::encode_frame(
+            frames::FRAME_HEADERS,
+            frames::FLAG_END_HEADERS,
+            1,
+            GET,
+        )];
+        attack.extend((0..100).map(|_| frames::encode_frame(FRAME_DATA, 0, 1, &[])));
+        let error = flood(&mut client, &mut context, attack);
+        assert_calmed(&mut client, error);
+    }
+
+    #[test]
+    fn continuation_flood() {
+        let (mut client, mut context) = connect(FloodLimits::new().continuation_bytes(128));
+        let mut attack = vec![frames::encode_frame(frames::FRAME_HEADERS, 0, 1, GET)];
+        attack.extend((0..100).map(|_| frames::encode_frame(FRAME_CONTINUATION, 0, 1, &[])));
+        let error = flood(&mut client, &mut context, attack);
+        assert_calmed(&mut client, error);
+    }
+
+    #[test]
+    fn pings_within_budget_are_answered() {
+        let (mut client, mut context) = connect(FloodLimits::new().control_frames(20));
+        let pings = (0..10u64)
+            .map(|i| frames::encode_frame(FRAME_PING, 0, 0, &i.to_be_bytes()))
+            .collect();
+        assert!(flood(&mut client, &mut context, pings).is_none());
+        assert_eq!(goaway_code(&mut client), None);
+    }
+
+    #[test]
+    fn budgets_refill_after_window() {
+        let mut guard = FloodGuard::new(
+            FloodLimits::new()
+                .window(Duration::from_millis(50))
+                .control_frames(2),
+        );
+        let ping = frames::encode_frame(FRAME_PING, 0, 0, &[0; 8]);
+        assert!(guard.record(&ping).is_ok());
+        assert!(guard.record(&ping).is_ok());
+        assert!(guard.record(&ping).is_err());
+        thread::sleep(Duration::from_millis(60));
+        assert!(guard.record(&ping).is_ok());
+    }
+
+    #[test]
+    fn longest_route_sets_the_body_limit() {
+        let limits = BodyLimits::new()
+            .max(10)
+            .route("/upload", Some(1000))
+            .route("/upload/avatar", Some(100))
+            .route("/stream", None);
+        assert_eq!(limits.limit(b"/"), Some(10));
+        assert_eq!(limits.limit(b"/upload"), Some(1000));
+        assert_eq!(limits.limit(b"/upload/file?name=a"), Some(1000));
+        assert_eq!(limits.limit(b"/upload/avatar"), Some(100));
+        assert_eq!(limits.limit(b"/stream/events"), None);
+        assert_eq!(BodyLimits::new().limit(b"/"), None);
+    }
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([127, 0, 0, last]))
+    }
+
+    #[test]
+    fn connections_are_capped() {
+        let mut limiter = Limiter::new(Limits::new().max_connections(2).max_connections_per_ip(1));
+        assert!(limiter.admit(ip(1)));
+        limiter.opened(Token(1), ip(1));
+        assert!(!limiter.admit(ip(1)));
+        assert!(limiter.admit(ip(2)));
+        limiter.opened(Token(2), ip(2));
+        assert!(!limiter.admit(ip(3)));
+        limiter.closed(Token(1));
+        assert!(limiter.admit(ip(1)));
+    }
+
+    #[test]
+    fn connections_without_address_only_count_against_the_total() {
+        let mut limiter = Limiter::new(Limits::new().max_connections_per_ip(0));
+        limiter.opened(Token(1), None);
+        assert!(limiter.admit(None));
+        assert!(!limiter.admit(ip(1)));
+    }
+
+    #[test]
+    fn streams_are_limited_per_connection_and_per_address() {
+        let mut limiter = Limiter::new(
+            Limits::new()
+                .streams_per_connection(RateLimit::new(0.0, 2.0))
+                .streams_per_ip(RateLimit::new(0.0, 3.0)),
+        );
+        limiter.opened(Token(1), ip(1));
+        limiter.opened(Token(2), ip(1));
+        assert!(limiter.allow_stream(Token(1)).is_ok());
+        assert!(limiter.allow_stream(Token(1)).is_ok());
+        // The connection's burst is spent, its address has one stream left.
+        assert_eq!(limiter.allow_stream(Token(1)), Err(Duration::from_secs(60)));
+        assert!(limiter.allow_stream(Token(2)).is_ok());
+        assert!(limiter.allow_stream(Token(2)).is_err());
+        assert!(limiter.allow_stream(Token(3)).is_ok());
+    }
+
+    #[test]
+    fn streams_refused_by_the_address_keep_the_connection_token() {
+        let mut limiter = Limiter::new(
+            Limits::new()
+                .streams_per_connection(RateLimit::new(0.0, 2.0))
+                .streams_per_ip(RateLimit::new(0.0, 1.0)),
+        );
+        limiter.opened(Token(1), ip(1));
+        limiter.opened(Token(2), ip(1));
+        assert!(limiter.allow_stream(Token(1)).is_ok());
+        assert!(limiter.allow_stream(Token(2)).is_err());
+        let (_, bucket) = &limiter.per_connection[&Token(2)];
+        assert_eq!(bucket.as_ref().unwrap().tokens, 2.0);
+    }
+
+    #[test]
+    fn retry_after_is_the_time_to_the_next_stream() {
+        let mut limiter =
+            Limiter::new(Limits::new().streams_per_connection(RateLimit::new(2.0, 1.0)));
+        limiter.opened(Token(1), None);
+        assert!(limiter.allow_stream(Token(1)).is_ok());
+        let retry_after = limiter.allow_stream(Token(1)).unwrap_err();
+        assert!(retry_after > Duration::from_millis(400));
+        assert!(retry_after <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn prune_forgets_idle_addresses() {
+        let mut limiter = Limiter::new(Limits::new().streams_per_ip(RateLimit::new(0.0, 1.0)));
+        for last in 1..=3 {
+            limiter.opened(Token(last as usize), ip(last));
+        }
+        assert!(limiter.allow_stream(Token(2)).is_ok());
+        limiter.closed(Token(1));
+        limiter.closed(Token(2));
+        limiter.prune();
+        // The first had a full bucket, the second still owes a stream and the
+        // third has a connection open.
+        assert!(!limiter.per_ip.contains_key(&ip(1).unwrap()));
+        assert!(limiter.per_ip.contains_key(&ip(2).unwrap()));
+        assert!(limiter.per_ip.contains_key(&ip(3).unwrap()));
+    }
+}
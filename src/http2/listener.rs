@@ -0,0 +1,255 @@
+use std::{
+    env, io, mem,
+    net::{SocketAddr, ToSocketAddrs},
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    path::Path,
+    process,
+};
+
+use mio::net::{TcpListener, UnixListener};
+
+use super::transport::Socket;
+
+// https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket an `Http2Server` accepts connections from.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the first of the resolved addresses.
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        match address.to_socket_addrs()?.next() {
+            Some(sock_addr) => Ok(Listener::Tcp(TcpListener::bind(sock_addr)?)),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "No Valid Address where Found",
+            )),
+        }
+    }
+
+    /// Binds a Unix socket at `path`, which must not exist yet.
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Listener::Unix(UnixListener::bind(path)?))
+    }
+
+    /// Binds a Unix socket in the abstract namespace, which has no file and
+    /// goes away with the listener.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_abstract(name: &[u8]) -> io::Result<Self> {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let listener = std::os::unix::net::UnixListener::bind_addr(&address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(UnixListener::from_std(listener)))
+    }
+
+    /// Adopts an inherited listening socket, taking ownership of `fd`. It
+    /// must be a TCP or Unix stream socket already listening, it is closed
+    /// otherwise.
+    pub fn from_fd(fd: RawFd) -> io::Result<Self> {
+        let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+        if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a stream socket",
+            ));
+        }
+        if socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Socket is not listening",
+            ));
+        }
+        let family = socket_family(fd)?;
+        if family != libc::AF_INET && family != libc::AF_INET6 && family != libc::AF_UNIX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Neither an IP nor a Unix socket",
+            ));
+        }
+        set_flags(fd)?;
+        let fd = owned.into_raw_fd();
+        if family == libc::AF_UNIX {
+            Ok(Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }))
+        } else {
+            Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }))
+        }
+    }
+
+    /// The sockets passed by systemd socket activation, in the order of the
+    /// socket unit, or none when `LISTEN_PID` names another process.
+    ///
+    /// The `LISTEN_*` variables are removed so that children don't try to
+    /// adopt the sockets too.
+    pub fn from_systemd() -> io::Result<Vec<Self>> {
+        let pid = env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<RawFd>().ok());
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        match (pid, count) {
+            (Some(pid), Some(count)) if pid == process::id() => {
+                // Every fd is adopted or closed, even past a rejected one.
+                let mut listeners = Vec::new();
+                let mut error = None;
+                for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+                    match Self::from_fd(fd) {
+                        Ok(listener) => listeners.push(listener),
+                        Err(e) => {
+                            error.get_or_insert(e);
+                        }
+                    }
+                }
+                match error {
+                    Some(e) => Err(e),
+                    None => Ok(listeners),
+                }
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Accepts one connection, with its address when it is IP based.
+    pub fn accept(&self) -> io::Result<(Socket, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Socket::Tcp(stream), Some(addr)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Socket::Unix(stream), None))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(value: TcpListener) -> Self {
+        Listener::Tcp(value)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(value: UnixListener) -> Self {
+        Listener::Unix(value)
+    }
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(address.ss_family as libc::c_int)
+}
+
+/// Inherited sockets are blocking and survive exec, mio expects neither.
+fn set_flags(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{TcpListener as StdTcpListener, TcpStream, UdpSocket},
+    };
+
+    use super::*;
+
+    #[test]
+    fn from_fd_adopts_a_listening_socket() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = Listener::from_fd(listener.into_raw_fd()).unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+        TcpStream::connect(addr).unwrap();
+    }
+
+    #[test]
+    fn from_fd_rejects_and_closes_a_udp_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let error = Listener::from_fd(socket.into_raw_fd()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        // The port is free again once the socket is closed.
+        UdpSocket::bind(addr).unwrap();
+    }
+
+    #[test]
+    fn from_fd_rejects_and_closes_a_connected_socket() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let error = Listener::from_fd(client.into_raw_fd()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(peer.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn from_systemd_ignores_another_process_sockets() {
+        env::set_var("LISTEN_PID", (process::id() + 1).to_string());
+        env::set_var("LISTEN_FDS", "1");
+        assert!(Listener::from_systemd().unwrap().is_empty());
+        assert!(env::var_os("LISTEN_PID").is_none());
+        assert!(env::var_os("LISTEN_FDS").is_none());
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http::{Request, Response};
+use mio::{event::Source, Token};
+
+use super::{
+    static_files::{civil_from_days, MONTHS},
+    BodyChunk, BodyStream, Handler, Middleware, RemoteAddr, StreamId, StreamingBody, Upgrade,
+};
+
+// Diagnostics go through the `tracing` or `log` facade when one of those
+// features is enabled, and to stderr otherwise.
+
+macro_rules! log_error {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::error!($($arg)+);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        log::error!($($arg)+);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        eprintln!("Error : {}", format_args!($($arg)+));
+    }};
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)+);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        log::warn!($($arg)+);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        eprintln!("Warning : {}", format_args!($($arg)+));
+    }};
+}
+
+/// Only emitted through a facade, there is no stderr fallback.
+macro_rules! log_debug {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        log::debug!($($arg)+);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+pub(crate) use log_debug;
+pub(crate) use log_error;
+pub(crate) use log_warn;
+
+/// Keeps a `tracing` span entered while it is alive, does nothing without
+/// the `tracing` feature.
+pub(crate) struct SpanGuard {
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+pub(crate) fn connection_span(token: Token, peer: Option<SocketAddr>) -> SpanGuard {
+    let _ = (token, peer);
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _span: tracing::info_span!("connection", token = token.0, peer = ?peer).entered(),
+    }
+}
+
+pub(crate) fn stream_span(stream_id: u32) -> SpanGuard {
+    let _ = stream_id;
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _span: tracing::info_span!("stream", id = stream_id).entered(),
+    }
+}
+
+/// One finished request, as written to the access log.
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    pub peer: Option<SocketAddr>,
+    pub method: String,
+    pub path: String,
+    pub protocol: &'static str,
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration: Duration,
+    pub stream_id: Option<u32>,
+    pub time: SystemTime,
+}
+
+impl AccessRecord {
+    /// Common Log Format, followed by the bytes received, the duration in
+    /// milliseconds and the stream id.
+    pub fn common(&self) -> String {
+        let secs = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let rem = secs % 86400;
+        format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {} {} {} {}",
+            self.peer
+                .map(|peer| peer.ip().to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            day,
+            MONTHS[(month - 1) as usize],
+            year,
+            rem / 3600,
+            (rem % 3600) / 60,
+            rem % 60,
+            self.method,
+            self.path,
+            self.protocol,
+            self.status,
+            self.bytes_out,
+            self.bytes_in,
+            self.duration.as_millis(),
+            self.stream_id
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+        )
+    }
+
+    pub fn json(&self) -> String {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or(0);
+        let peer = match self.peer {
+            Some(peer) => format!("\"{}\"", peer),
+            None => "null".to_string(),
+        };
+        let stream_id = match self.stream_id {
+            Some(id) => id.to_string(),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"time\":{},\"peer\":{},\"method\":\"{}\",\"path\":\"{}\",\"protocol\":\"{}\",\"status\":{},\"bytes_in\":{},\"bytes_out\":{},\"duration_ms\":{:.3},\"stream_id\":{}}}",
+            time,
+            peer,
+            json_escape(&self.method),
+            json_escape(&self.path),
+            self.protocol,
+            self.status,
+            self.bytes_in,
+            self.bytes_out,
+            self.duration.as_secs_f64() * 1000.0,
+            stream_id,
+        )
+    }
+}
+
+fn json_escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AccessLogFormat {
+    Common,
+    Json,
+    Custom(fn(&AccessRecord) -> String),
+}
+
+type Sink = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Middleware writing one line per request once its response is complete,
+/// streamed bodies included.
+///
+/// Lines go to the `khttp::access` target of the `tracing` or `log` facade
+/// when enabled, to stdout otherwise, or to the function given to `sink`.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Sink,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat) -> Self {
+        Self {
+            format,
+            sink: Arc::new(default_sink),
+        }
+    }
+
+    pub fn sink<F: Fn(&str) + Send + Sync + 'static>(mut self, sink: F) -> Self {
+        self.sink = Arc::new(sink);
+        self
+    }
+}
+
+fn default_sink(line: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(target: "khttp::access", "{}", line);
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    log::info!(target: "khttp::access", "{}", line);
+    #[cfg(not(any(feature = "log", feature = "tracing")))]
+    println!("{}", line);
+}
+
+/// Writes the access log line once `record` is complete.
+struct Entry {
+    record: AccessRecord,
+    start: Instant,
+    format: AccessLogFormat,
+    sink: Sink,
+}
+
+impl Entry {
+    fn write(mut self) {
+        self.record.duration = self.start.elapsed();
+        let line = match self.format {
+            AccessLogFormat::Common => self.record.common(),
+            AccessLogFormat::Json => self.record.json(),
+            AccessLogFormat::Custom(format) => format(&self.record),
+        };
+        (self.sink)(&line);
+    }
+}
+
+/// Counts the bytes of a streamed body, logging when it ends or is dropped
+/// early because the stream was reset.
+struct LoggedBody {
+    inner: Box<dyn BodyStream>,
+    entry: Option<Entry>,
+}
+
+impl BodyStream for LoggedBody {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        let chunk = self.inner.next_chunk(max_len);
+        match &chunk {
+            Ok(BodyChunk::Data(data)) => {
+                if let Some(entry) = self.entry.as_mut() {
+                    entry.record.bytes_out += data.len() as u64;
+                }
+            }
+            Ok(BodyChunk::Pending) => {}
+            Ok(BodyChunk::End) | Ok(BodyChunk::Trailers(_)) | Err(_) => {
+                if let Some(entry) = self.entry.take() {
+                    entry.write();
+                }
+            }
+        }
+        chunk
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        self.inner.source()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            entry.write();
+        }
+    }
+}
+
+impl Middleware for AccessLog {
+    fn handle(
+        &self,
+        token: Token,
+        request: Request<Vec<u8>>,
+        next: &dyn Handler,
+    ) -> Response<Vec<u8>> {
+        let start = Instant::now();
+        let time = SystemTime::now();
+        let peer = request
+            .extensions()
+            .get::<RemoteAddr>()
+            .map(|RemoteAddr(addr)| *addr);
+        let stream_id = request
+            .extensions()
+            .get::<StreamId>()
+            .map(|StreamId(id)| *id);
+        // Taken from `:path`, a plain `path` header could be sent by anyone.
+        let path = match request.uri().path_and_query() {
+            Some(path) => path.to_string(),
+            None => request.uri().to_string(),
+        };
+        let method = request.method().to_string();
+        let bytes_in = request.body().len() as u64;
+
+        let mut response = next.call(token, request);
+
+        let mut entry = Entry {
+            record: AccessRecord {
+                peer,
+                method,
+                path,
+                protocol: "HTTP/2.0",
+                status: response.status().as_u16(),
+                bytes_in,
+                bytes_out: 0,
+                duration: Duration::ZERO,
+                stream_id,
+                time,
+            },
+            start,
+            format: self.format,
+            sink: self.sink.clone(),
+        };
+
+        if response.extensions().get::<Upgrade>().is_some() {
+            entry.write();
+            return response;
+        }
+        match response
+            .extensions_mut()
+            .remove::<StreamingBody>()
+            .and_then(|body| body.take())
+        {
+            Some(body) => {
+                response
+                    .extensions_mut()
+                    .insert(StreamingBody::new(LoggedBody {
+                        inner: body,
+                        entry: Some(entry),
+                    }));
+            }
+            None => {
+                entry.record.bytes_out = response.body().len() as u64;
+                entry.write();
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::{super::BytesBody, *};
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            peer: Some("192.0.2.1:50000".parse().unwrap()),
+            method: "GET".to_string(),
+            path: "/index.html?q=1".to_string(),
+            protocol: "HTTP/2.0",
+            status: 200,
+            bytes_in: 12,
+            bytes_out: 345,
+            duration: Duration::from_micros(1500),
+            stream_id: Some(3),
+            // 2024-03-01 13:45:07 UTC
+            time: UNIX_EPOCH + Duration::from_secs(1709300707),
+        }
+    }
+
+    #[test]
+    fn records_use_the_common_log_format() {
+        assert_eq!(
+            record().common(),
+            "192.0.2.1 - - [01/Mar/2024:13:45:07 +0000] \"GET /index.html?q=1 HTTP/2.0\" 200 345 12 1 3"
+        );
+        let record = AccessRecord {
+            peer: None,
+            stream_id: None,
+            ..record()
+        };
+        assert!(record.common().starts_with("- - - ["));
+        assert!(record.common().ends_with(" -"));
+    }
+
+    #[test]
+    fn records_are_written_as_json() {
+        assert_eq!(
+            record().json(),
+            "{\"time\":1709300707000,\"peer\":\"192.0.2.1:50000\",\"method\":\"GET\",\"path\":\"/index.html?q=1\",\"protocol\":\"HTTP/2.0\",\"status\":200,\"bytes_in\":12,\"bytes_out\":345,\"duration_ms\":1.500,\"stream_id\":3}"
+        );
+        let record = AccessRecord {
+            peer: None,
+            path: "/\"quoted\"".to_string(),
+            stream_id: None,
+            ..record()
+        };
+        let json = record.json();
+        assert!(json.contains("\"peer\":null"));
+        assert!(json.contains("\"path\":\"/\\\"quoted\\\"\""));
+        assert!(json.ends_with("\"stream_id\":null}"));
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_escape("plain/ünïcode"), "plain/ünïcode");
+        assert_eq!(json_escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(
+            json_escape("line\nbreak\t\u{1}"),
+            "line\\u000abreak\\u0009\\u0001"
+        );
+    }
+
+    fn logged(
+        request: Request<Vec<u8>>,
+        handler: impl Handler,
+    ) -> (Response<Vec<u8>>, Arc<Mutex<Vec<String>>>) {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let log = AccessLog::new(AccessLogFormat::Custom(|record| {
+            format!(
+                "{} {} {} {}",
+                record.path, record.status, record.bytes_in, record.bytes_out
+            )
+        }))
+        .sink(move |line| sink.lock().unwrap().push(line.to_string()));
+        (log.handle(Token(0), request, &handler), lines)
+    }
+
+    #[test]
+    fn the_path_comes_from_the_request_uri() {
+        let request = Request::builder()
+            .uri("/real?x=1")
+            .header("path", "/spoofed")
+            .body(b"body".to_vec())
+            .unwrap();
+        let handler = |_: Token, _: Request<Vec<u8>>| Response::new(b"response".to_vec());
+        let (_, lines) = logged(request, handler);
+        assert_eq!(*lines.lock().unwrap(), vec!["/real?x=1 200 4 8"]);
+    }
+
+    #[test]
+    fn streamed_bodies_are_logged_once_they_end() {
+        let request = Request::builder().uri("/stream").body(Vec::new()).unwrap();
+        let handler = |_: Token, _: Request<Vec<u8>>| {
+            let mut response = Response::new(Vec::new());
+            response
+                .extensions_mut()
+                .insert(StreamingBody::new(BytesBody::new(vec![0; 10])));
+            response
+        };
+        let (response, lines) = logged(request, handler);
+        let mut body = response
+            .extensions()
+            .get::<StreamingBody>()
+            .and_then(|body| body.take())
+            .unwrap();
+        assert!(matches!(body.next_chunk(6).unwrap(), BodyChunk::Data(_)));
+        assert!(matches!(body.next_chunk(6).unwrap(), BodyChunk::Data(_)));
+        assert!(lines.lock().unwrap().is_empty());
+        assert!(matches!(body.next_chunk(6).unwrap(), BodyChunk::End));
+        assert_eq!(*lines.lock().unwrap(), vec!["/stream 200 0 10"]);
+        drop(body);
+        assert_eq!(lines.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,587 @@
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use http::{header, Method, Request, Response, StatusCode};
+use mio::Token;
+
+use super::{
+    frames::{ERROR_NAMES, FRAME_GOAWAY, FRAME_NAMES, FRAME_RST_STREAM},
+    Handler, Middleware,
+};
+
+/// Counters by frame type and by error code, the last one counting unknown
+/// values.
+const FRAME_SLOTS: usize = FRAME_NAMES.len() + 1;
+const ERROR_SLOTS: usize = ERROR_NAMES.len() + 1;
+const UNKNOWN: &[&str] = &["UNKNOWN"];
+
+/// Upper bounds of the request duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+fn frame_index(frame_type: u8) -> usize {
+    usize::min(frame_type as usize, FRAME_SLOTS - 1)
+}
+
+fn error_index(error_code: u32) -> usize {
+    usize::min(error_code as usize, ERROR_SLOTS - 1)
+}
+
+#[derive(Default)]
+struct FrameCounters {
+    frames: [AtomicU64; FRAME_SLOTS],
+    resets: [AtomicU64; ERROR_SLOTS],
+    goaways: [AtomicU64; ERROR_SLOTS],
+}
+
+impl FrameCounters {
+    /// Counts one serialized frame, header included.
+    fn record(&self, frame: &[u8]) {
+        if frame.len() < 9 {
+            return;
+        }
+        let frame_type = frame[3];
+        self.frames[frame_index(frame_type)].fetch_add(1, Ordering::Relaxed);
+        let error_code = |offset: usize| {
+            frame
+                .get(offset..offset + 4)
+                .map(|code| u32::from_be_bytes([code[0], code[1], code[2], code[3]]))
+        };
+        match frame_type {
+            FRAME_RST_STREAM => {
+                if let Some(code) = error_code(9) {
+                    self.resets[error_index(code)].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            FRAME_GOAWAY => {
+                if let Some(code) = error_code(13) {
+                    self.goaways[error_index(code)].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters and gauges of an `Http2Server`, shared by all of its connections.
+#[derive(Default)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    active_connections: AtomicI64,
+    streams_total: AtomicU64,
+    active_streams: AtomicI64,
+    received: FrameCounters,
+    sent: FrameCounters,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    request_duration: Histogram,
+    hpack_table_size: AtomicI64,
+    hpack_decoded_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stream_opened(&self) {
+        self.streams_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn response_started(&self) {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn response_finished(&self) {
+        self.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn frame_received(&self, frame: &[u8]) {
+        self.received.record(frame);
+    }
+
+    pub(crate) fn frame_sent(&self, frame: &[u8]) {
+        self.sent.record(frame);
+    }
+
+    pub(crate) fn read(&self, len: usize) {
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn written(&self, len: usize) {
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_handled(&self, duration: Duration) {
+        self.request_duration.observe(duration);
+    }
+
+    pub(crate) fn hpack_table_resized(&self, old_size: u32, new_size: u32) {
+        self.hpack_table_size
+            .fetch_add(new_size as i64 - old_size as i64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn headers_decoded(&self, len: usize) {
+        self.hpack_decoded_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn connections_total(&self) -> u64 {
+        self.connections_total.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> i64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn streams_total(&self) -> u64 {
+        self.streams_total.load(Ordering::Relaxed)
+    }
+
+    /// Streams whose response is still being sent.
+    pub fn active_streams(&self) -> i64 {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_received(&self, frame_type: u8) -> u64 {
+        self.received.frames[frame_index(frame_type)].load(Ordering::Relaxed)
+    }
+
+    pub fn frames_sent(&self, frame_type: u8) -> u64 {
+        self.sent.frames[frame_index(frame_type)].load(Ordering::Relaxed)
+    }
+
+    pub fn resets_received(&self, error_code: u32) -> u64 {
+        self.received.resets[error_index(error_code)].load(Ordering::Relaxed)
+    }
+
+    pub fn resets_sent(&self, error_code: u32) -> u64 {
+        self.sent.resets[error_index(error_code)].load(Ordering::Relaxed)
+    }
+
+    pub fn goaways_received(&self, error_code: u32) -> u64 {
+        self.received.goaways[error_index(error_code)].load(Ordering::Relaxed)
+    }
+
+    pub fn goaways_sent(&self, error_code: u32) -> u64 {
+        self.sent.goaways[error_index(error_code)].load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.request_duration.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of the HPACK dynamic table sizes the peers allowed for.
+    pub fn hpack_table_size(&self) -> i64 {
+        self.hpack_table_size.load(Ordering::Relaxed)
+    }
+
+    pub fn hpack_decoded_bytes(&self) -> u64 {
+        self.hpack_decoded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Prometheus text exposition format.
+    /// https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let single = |value: String| vec![(String::new(), value)];
+        let by_frame = |counters: &FrameCounters| {
+            FRAME_NAMES
+                .iter()
+                .chain(UNKNOWN)
+                .zip(counters.frames.iter())
+                .map(|(name, count)| {
+                    (
+                        format!("{{type=\"{}\"}}", name),
+                        count.load(Ordering::Relaxed).to_string(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let by_error = |counters: &[AtomicU64; ERROR_SLOTS], direction: &str| {
+            ERROR_NAMES
+                .iter()
+                .chain(UNKNOWN)
+                .zip(counters.iter())
+                .map(|(name, count)| (name, count.load(Ordering::Relaxed)))
+                .filter(|(_, count)| *count > 0)
+                .map(|(name, count)| {
+                    (
+                        format!("{{direction=\"{}\",code=\"{}\"}}", direction, name),
+                        count.to_string(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        metric(
+            "khttp_connections_total",
+            "counter",
+            "Accepted connections.",
+            single(self.connections_total().to_string()),
+        );
+        metric(
+            "khttp_active_connections",
+            "gauge",
+            "Open connections.",
+            single(self.active_connections().to_string()),
+        );
+        metric(
+            "khttp_streams_total",
+            "counter",
+            "Streams opened by peers.",
+            single(self.streams_total().to_string()),
+        );
+        metric(
+            "khttp_active_streams",
+            "gauge",
+            "Streams whose response is still being sent.",
+            single(self.active_streams().to_string()),
+        );
+        metric(
+            "khttp_frames_received_total",
+            "counter",
+            "Frames received by type.",
+            by_frame(&self.received),
+        );
+        metric(
+            "khttp_frames_sent_total",
+            "counter",
+            "Frames sent by type.",
+            by_frame(&self.sent),
+        );
+        let mut resets = by_error(&self.received.resets, "received");
+        resets.extend(by_error(&self.sent.resets, "sent"));
+        metric(
+            "khttp_rst_stream_total",
+            "counter",
+            "RST_STREAM frames by error code.",
+            resets,
+        );
+        let mut goaways = by_error(&self.received.goaways, "received");
+        goaways.extend(by_error(&self.sent.goaways, "sent"));
+        metric(
+            "khttp_goaway_total",
+            "counter",
+            "GOAWAY frames by error code.",
+            goaways,
+        );
+        metric(
+            "khttp_received_bytes_total",
+            "counter",
+            "Bytes read from connections.",
+            single(self.bytes_in().to_string()),
+        );
+        metric(
+            "khttp_sent_bytes_total",
+            "counter",
+            "Bytes written to connections.",
+            single(self.bytes_out().to_string()),
+        );
+
+        let histogram = &self.request_duration;
+        let count = histogram.count.load(Ordering::Relaxed);
+        let mut samples: Vec<(String, String)> = DURATION_BUCKETS
+            .iter()
+            .zip(histogram.buckets.iter())
+            .map(|(bound, bucket)| {
+                (
+                    format!("_bucket{{le=\"{}\"}}", bound),
+                    bucket.load(Ordering::Relaxed).to_string(),
+                )
+            })
+            .collect();
+        samples.push(("_bucket{le=\"+Inf\"}".to_string(), count.to_string()));
+        samples.push((
+            "_sum".to_string(),
+            (histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0).to_string(),
+        ));
+        samples.push(("_count".to_string(), count.to_string()));
+        metric(
+            "khttp_request_duration_seconds",
+            "histogram",
+            "Time spent in the handler per request.",
+            samples,
+        );
+
+        metric(
+            "khttp_hpack_table_size_bytes",
+            "gauge",
+            "Sum of the HPACK dynamic table sizes allowed by peers.",
+            single(self.hpack_table_size().to_string()),
+        );
+        metric(
+            "khttp_hpack_decoded_bytes_total",
+            "counter",
+            "Size of the decoded header lists.",
+            single(self.hpack_decoded_bytes().to_string()),
+        );
+        out
+    }
+}
+
+/// Middleware answering `GET path` with the Prometheus text rendering of
+/// `metrics`, other requests go to the next handler.
+pub struct PrometheusEndpoint {
+    path: String,
+    metrics: Arc<Metrics>,
+}
+
+impl PrometheusEndpoint {
+    pub fn new(path: &str, metrics: Arc<Metrics>) -> Self {
+        Self {
+            path: path.to_string(),
+            metrics,
+        }
+    }
+}
+
+impl Middleware for PrometheusEndpoint {
+    fn handle(
+        &self,
+        token: Token,
+        request: Request<Vec<u8>>,
+        next: &dyn Handler,
+    ) -> Response<Vec<u8>> {
+        if request.method() != Method::GET || request.uri().path() != self.path {
+            return next.call(token, request);
+        }
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(self.metrics.render().into_bytes())
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::frames::FRAME_HEADERS, *};
+
+    fn frame(frame_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend([frame_type, 0, 0, 0, 0, 1]);
+        frame.extend(payload);
+        frame
+    }
+
+    #[test]
+    fn unknown_types_and_codes_share_the_last_slot() {
+        let metrics = Metrics::new();
+        metrics.frame_received(&frame(FRAME_HEADERS, &[]));
+        metrics.frame_received(&frame(0x0a, &[]));
+        metrics.frame_received(&frame(0xff, &[]));
+        metrics.frame_received(&frame(FRAME_RST_STREAM, &8u32.to_be_bytes()));
+        metrics.frame_received(&frame(FRAME_RST_STREAM, &0x20u32.to_be_bytes()));
+        let mut goaway = vec![0, 0, 0, 1];
+        goaway.extend(0xdeadu32.to_be_bytes());
+        metrics.frame_sent(&frame(FRAME_GOAWAY, &goaway));
+        metrics.frame_sent(&[0; 8]);
+
+        assert_eq!(metrics.frames_received(FRAME_HEADERS), 1);
+        assert_eq!(metrics.frames_received(FRAME_RST_STREAM), 2);
+        assert_eq!(metrics.frames_received(0x0a), 2);
+        assert_eq!(metrics.frames_received(0x0b), 2);
+        assert_eq!(metrics.resets_received(8), 1);
+        assert_eq!(metrics.resets_received(0x0e), 1);
+        assert_eq!(metrics.resets_received(0x20), 1);
+        assert_eq!(metrics.resets_sent(8), 0);
+        assert_eq!(metrics.frames_sent(FRAME_GOAWAY), 1);
+        assert_eq!(metrics.goaways_sent(0xdead), 1);
+        assert_eq!(metrics.goaways_sent(0), 0);
+        assert_eq!(metrics.frames_sent(0), 0);
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(1));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(60));
+        let buckets: Vec<u64> = histogram
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(buckets, [1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2]);
+        assert_eq!(histogram.count.load(Ordering::Relaxed), 3);
+        assert_eq!(histogram.sum_micros.load(Ordering::Relaxed), 60_021_000);
+    }
+
+    #[test]
+    fn render_uses_the_text_format() {
+        let metrics = Metrics::new();
+        metrics.connection_opened();
+        metrics.stream_opened();
+        metrics.frame_received(&frame(FRAME_HEADERS, &[]));
+        metrics.frame_sent(&frame(FRAME_RST_STREAM, &8u32.to_be_bytes()));
+        metrics.read(9);
+        metrics.written(13);
+        metrics.request_handled(Duration::from_millis(20));
+        metrics.hpack_table_resized(0, 4096);
+        metrics.headers_decoded(42);
+
+        let frames = |counts: [u8; FRAME_SLOTS]| {
+            FRAME_NAMES
+                .iter()
+                .chain(UNKNOWN)
+                .zip(counts)
+                .map(|(name, count)| format!("{{type=\"{}\"}} {}\n", name, count))
+                .collect::<Vec<_>>()
+        };
+        let mut expected = String::from(
+            "# HELP khttp_connections_total Accepted connections.
+# TYPE khttp_connections_total counter
+khttp_connections_total 1
+# HELP khttp_active_connections Open connections.
+# TYPE khttp_active_connections gauge
+khttp_active_connections 1
+# HELP khttp_streams_total Streams opened by peers.
+# TYPE khttp_streams_total counter
+khttp_streams_total 1
+# HELP khttp_active_streams Streams whose response is still being sent.
+# TYPE khttp_active_streams gauge
+khttp_active_streams 0
+# HELP khttp_frames_received_total Frames received by type.
+# TYPE khttp_frames_received_total counter
+",
+        );
+        for sample in frames([0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]) {
+            expected += "khttp_frames_received_total";
+            expected += &sample;
+        }
+        expected += "# HELP khttp_frames_sent_total Frames sent by type.
+# TYPE khttp_frames_sent_total counter
+";
+        for sample in frames([0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]) {
+            expected += "khttp_frames_sent_total";
+            expected += &sample;
+        }
+        expected += "# HELP khttp_rst_stream_total RST_STREAM frames by error code.
+# TYPE khttp_rst_stream_total counter
+khttp_rst_stream_total{direction=\"sent\",code=\"CANCEL\"} 1
+# HELP khttp_goaway_total GOAWAY frames by error code.
+# TYPE khttp_goaway_total counter
+# HELP khttp_received_bytes_total Bytes read from connections.
+# TYPE khttp_received_bytes_total counter
+khttp_received_bytes_total 9
+# HELP khttp_sent_bytes_total Bytes written to connections.
+# TYPE khttp_sent_bytes_total counter
+khttp_sent_bytes_total 13
+# HELP khttp_request_duration_seconds Time spent in the handler per request.
+# TYPE khttp_request_duration_seconds histogram
+khttp_request_duration_seconds_bucket{le=\"0.001\"} 0
+khttp_request_duration_seconds_bucket{le=\"0.005\"} 0
+khttp_request_duration_seconds_bucket{le=\"0.01\"} 0
+khttp_request_duration_seconds_bucket{le=\"0.025\"} 1
+khttp_request_duration_seconds_bucket{le=\"0.05\"} 1
+khttp_request_duration_seconds_bucket{le=\"0.1\"} 1
+khttp_request_duration_seconds_bucket{le=\"0.25\"} 1
+khttp_request_duration_seconds_bucket{le=\"0.5\"} 1
+khttp_request_duration_seconds_bucket{le=\"1\"} 1
+khttp_request_duration_seconds_bucket{le=\"2.5\"} 1
+khttp_request_duration_seconds_bucket{le=\"5\"} 1
+khttp_request_duration_seconds_bucket{le=\"10\"} 1
+khttp_request_duration_seconds_bucket{le=\"+Inf\"} 1
+khttp_request_duration_seconds_sum 0.02
+khttp_request_duration_seconds_count 1
+# HELP khttp_hpack_table_size_bytes Sum of the HPACK dynamic table sizes allowed by peers.
+# TYPE khttp_hpack_table_size_bytes gauge
+khttp_hpack_table_size_bytes 4096
+# HELP khttp_hpack_decoded_bytes_total Size of the decoded header lists.
+# TYPE khttp_hpack_decoded_bytes_total counter
+khttp_hpack_decoded_bytes_total 42
+";
+        assert_eq!(metrics.render(), expected);
+    }
+
+    #[test]
+    fn endpoint_only_answers_get_on_its_path() {
+        let metrics = Metrics::new();
+        metrics.connection_opened();
+        let endpoint = PrometheusEndpoint::new("/metrics", metrics);
+        let next = |_token, _request| {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Vec::new())
+                .unwrap()
+        };
+        let request = |method: Method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Vec::new())
+                .unwrap()
+        };
+
+        let response = endpoint.handle(Token(0), request(Method::GET, "/metrics"), &next);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; version=0.0.4"
+        );
+        let body = String::from_utf8(response.into_body()).unwrap();
+        assert!(body.contains("\nkhttp_active_connections 1\n"));
+
+        for (method, path) in [
+            (Method::POST, "/metrics"),
+            (Method::GET, "/metrics/"),
+            (Method::GET, "/"),
+        ] {
+            let response = endpoint.handle(Token(0), request(method, path), &next);
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use http::{Request, Response};
+use mio::Token;
+
+/// Anything that can turn a request into a response.
+///
+/// Plain functions and closures with the `fn(Token, Request) -> Response` shape
+/// accepted by `Http2Server::listen` implement this trait.
+pub trait Handler {
+    fn call(&self, token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Token, Request<Vec<u8>>) -> Response<Vec<u8>>,
+{
+    fn call(&self, token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        self(token, request)
+    }
+}
+
+/// Wraps a handler with cross-cutting behaviour.
+///
+/// A middleware receives the request and the rest of the chain as `next`. It can
+/// inspect or modify the request, short-circuit by returning a response without
+/// calling `next`, or post-process the response that `next` returns.
+pub trait Middleware {
+    fn handle(
+        &self,
+        token: Token,
+        request: Request<Vec<u8>>,
+        next: &dyn Handler,
+    ) -> Response<Vec<u8>>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Token, Request<Vec<u8>>, &dyn Handler) -> Response<Vec<u8>>,
+{
+    fn handle(
+        &self,
+        token: Token,
+        request: Request<Vec<u8>>,
+        next: &dyn Handler,
+    ) -> Response<Vec<u8>> {
+        self(token, request, next)
+    }
+}
+
+/// A handler wrapped in an ordered stack of middlewares.
+///
+/// The first layer added is the outermost one: it sees the request first and the
+/// response last.
+pub struct Layered<H: Handler> {
+    layers: Vec<Box<dyn Middleware>>,
+    handler: H,
+}
+
+impl<H: Handler> Layered<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            layers: Vec::new(),
+            handler,
+        }
+    }
+
+    pub fn layer<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.layers.push(Box::new(middleware));
+        self
+    }
+}
+
+impl<H: Handler> Handler for Layered<H> {
+    fn call(&self, token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let next = Next {
+            layers: &self.layers,
+            handler: &self.handler,
+        };
+        next.call(token, request)
+    }
+}
+
+struct Next<'a> {
+    layers: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Handler for Next<'_> {
+    fn call(&self, token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        match self.layers.split_first() {
+            Some((layer, rest)) => {
+                let next = Next {
+                    layers: rest,
+                    handler: self.handler,
+                };
+                layer.handle(token, request, &next)
+            }
+            None => self.handler.call(token, request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use http::StatusCode;
+
+    use super::*;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    /// Logs the request on its way in and the response on its way out.
+    fn traced(name: &'static str, log: &Log) -> impl Middleware {
+        let log = log.clone();
+        move |token, request, next: &dyn Handler| {
+            log.borrow_mut().push(format!("{} request", name));
+            let response = next.call(token, request);
+            log.borrow_mut().push(format!("{} response", name));
+            response
+        }
+    }
+
+    fn handler(log: &Log) -> impl Handler {
+        let log = log.clone();
+        move |_token, _request| {
+            log.borrow_mut().push("handler".to_string());
+            Response::new(Vec::new())
+        }
+    }
+
+    #[test]
+    fn first_layer_is_the_outermost() {
+        let log = Log::default();
+        let layered = Layered::new(handler(&log))
+            .layer(traced("outer", &log))
+            .layer(traced("inner", &log));
+        layered.call(Token(0), Request::new(Vec::new()));
+        assert_eq!(
+            *log.borrow(),
+            [
+                "outer request",
+                "inner request",
+                "handler",
+                "inner response",
+                "outer response",
+            ]
+        );
+    }
+
+    #[test]
+    fn middleware_can_short_circuit() {
+        let log = Log::default();
+        let layered = Layered::new(handler(&log))
+            .layer(traced("outer", &log))
+            .layer(|_token, _request, _next: &dyn Handler| {
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Vec::new())
+                    .unwrap()
+            })
+            .layer(traced("inner", &log));
+        let response = layered.call(Token(0), Request::new(Vec::new()));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(*log.borrow(), ["outer request", "outer response"]);
+    }
+}
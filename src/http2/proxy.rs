@@ -0,0 +1,1519 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use kparser::http2::{
+    ContinuationPayloadFlag, DataPayloadFlag, Frame, HeadersPayload, HeadersPayloadFlag, Hpack,
+    HpackContext, Payload, SETTINGS_ENABLE_PUSH, SETTINGS_INITIAL_WINDOW_SIZE,
+    SETTINGS_MAX_FRAME_SIZE,
+};
+use mio::{event::Source, net::TcpStream, Interest, Registry, Token};
+
+use super::{
+    logging::log_warn,
+    frames::{
+        self, FLAG_ACK, FLAG_END_HEADERS, FLAG_END_STREAM, FRAME_CONTINUATION, FRAME_DATA,
+        FRAME_HEADERS, FRAME_PING, FRAME_SETTINGS, FRAME_WINDOW_UPDATE,
+    },
+    BodyChunk, BodyStream, Deferred, DeferredResponse, Handler, RemoteAddr, StreamingBody,
+    Trailers,
+};
+
+// https://datatracker.ietf.org/doc/html/rfc9110#name-connection
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers the server fills in from the request's pseudo-headers.
+const PSEUDO_HEADERS: [&str; 6] = [
+    "method",
+    "path",
+    "scheme",
+    "authority",
+    "status",
+    "protocol",
+];
+
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const READ_SIZE: usize = 16384;
+
+type HeaderList = Vec<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    Http1,
+    /// HTTP/2 over cleartext TCP with prior knowledge.
+    Http2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+#[derive(Debug)]
+enum ProxyError {
+    /// The upstream couldn't be reached, nothing was sent to it.
+    Connect(io::Error),
+    Timeout,
+    IOError(io::Error),
+    InvalidResponse,
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::TimedOut => ProxyError::Timeout,
+            _ => ProxyError::IOError(value),
+        }
+    }
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::Connect(e) => write!(f, "ProxyError::Connect({})", e),
+            ProxyError::Timeout => f.write_str("ProxyError::Timeout"),
+            ProxyError::IOError(e) => write!(f, "ProxyError::IOError({})", e),
+            ProxyError::InvalidResponse => f.write_str("ProxyError::InvalidResponse"),
+        }
+    }
+}
+
+impl ProxyError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Upstream {
+    addr: SocketAddr,
+    protocol: UpstreamProtocol,
+    active: Arc<AtomicUsize>,
+}
+
+/// The upstreams of a `ReverseProxy`, shared with its exchanges in flight.
+#[derive(Clone)]
+struct Upstreams {
+    list: Vec<Upstream>,
+    balance: Balance,
+    next: Arc<AtomicUsize>,
+}
+
+impl Upstreams {
+    fn select(&self, tried: &[usize]) -> Option<usize> {
+        let len = self.list.len();
+        if len == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut candidates = (0..len)
+            .map(|i| (start + i) % len)
+            .filter(|i| !tried.contains(i));
+        let selected = match self.balance {
+            Balance::RoundRobin => candidates.next(),
+            Balance::LeastConnections => {
+                candidates.min_by_key(|i| self.list[*i].active.load(Ordering::SeqCst))
+            }
+        };
+        // Every upstream failed already, start over.
+        Some(selected.unwrap_or(start % len))
+    }
+}
+
+/// Counts an exchange with an upstream for `Balance::LeastConnections` until
+/// its response body is done.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(active.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The parts of an incoming request sent to the upstream.
+struct Forward {
+    method: Method,
+    scheme: String,
+    authority: String,
+    path: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    trailers: Option<Trailers>,
+}
+
+impl Forward {
+    fn http1_head(&self) -> Vec<u8> {
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nhost: {}\r\n",
+            self.method, self.path, self.authority
+        )
+        .into_bytes();
+        for (name, value) in self.headers.iter() {
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        if !self.body.is_empty()
+            || matches!(self.method, Method::POST | Method::PUT | Method::PATCH)
+        {
+            head.extend_from_slice(format!("content-length: {}\r\n", self.body.len()).as_bytes());
+        }
+        head.extend_from_slice(b"connection: close\r\n\r\n");
+        head
+    }
+
+    fn http2_headers(&self) -> HeaderList {
+        let mut list = vec![
+            (
+                b":method".to_vec(),
+                self.method.as_str().as_bytes().to_vec(),
+            ),
+            (b":scheme".to_vec(), self.scheme.as_bytes().to_vec()),
+            (b":authority".to_vec(), self.authority.as_bytes().to_vec()),
+            (b":path".to_vec(), self.path.as_bytes().to_vec()),
+        ];
+        list.extend(
+            self.headers.iter().map(|(name, value)| {
+                (name.as_str().as_bytes().to_vec(), value.as_bytes().to_vec())
+            }),
+        );
+        list
+    }
+}
+
+/// Handler forwarding requests to a set of upstream servers.
+///
+/// Hop-by-hop headers are stripped and `x-forwarded-for`, `x-forwarded-proto`
+/// and `forwarded` are added. Response bodies are streamed back as the
+/// client's flow-control windows allow. Requests that fail before a response
+/// arrives are retried on another upstream when their method is idempotent,
+/// or when the upstream couldn't be connected to at all.
+///
+/// Upstream sockets are non-blocking: the response is `Deferred` until its
+/// headers arrive, and the request body is written as the upstream reads it.
+pub struct ReverseProxy {
+    upstreams: Upstreams,
+    connect_timeout: Duration,
+    timeout: Duration,
+    retries: usize,
+}
+
+impl Default for ReverseProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReverseProxy {
+    pub fn new() -> Self {
+        Self {
+            upstreams: Upstreams {
+                list: Vec::new(),
+                balance: Balance::RoundRobin,
+                next: Arc::new(AtomicUsize::new(0)),
+            },
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            retries: 1,
+        }
+    }
+
+    pub fn upstream(mut self, addr: SocketAddr, protocol: UpstreamProtocol) -> Self {
+        self.upstreams.list.push(Upstream {
+            addr,
+            protocol,
+            active: Arc::new(AtomicUsize::new(0)),
+        });
+        self
+    }
+
+    pub fn balance(mut self, balance: Balance) -> Self {
+        self.upstreams.balance = balance;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Time the upstream may stay silent, while the request is written and
+    /// until the response headers are received. It is checked on every tick
+    /// of the server.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    fn forward(&self, request: Request<Vec<u8>>) -> Forward {
+        let (parts, body) = request.into_parts();
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        let scheme = header("scheme").unwrap_or_else(|| "http".to_string());
+        let authority = header("authority")
+            .or_else(|| header("host"))
+            .unwrap_or_default();
+        let path = header("path")
+            .or_else(|| parts.uri.path_and_query().map(|path| path.to_string()))
+            .unwrap_or_else(|| "/".to_string());
+
+        let mut headers = parts.headers.clone();
+        strip_hop_by_hop(&mut headers);
+        for name in PSEUDO_HEADERS
+            .iter()
+            .chain(["host", "content-length"].iter())
+        {
+            headers.remove(*name);
+        }
+        // The only TE value allowed over HTTP/2, which gRPC requires.
+        // https://datatracker.ietf.org/doc/html/rfc9113#section-8.2.2
+        let te_trailers = parts
+            .headers
+            .get_all("te")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|coding| {
+                let coding = coding.split(';').next().unwrap_or_default();
+                coding.trim().eq_ignore_ascii_case("trailers")
+            });
+        if te_trailers {
+            headers.insert("te", HeaderValue::from_static("trailers"));
+        }
+
+        if let Some(RemoteAddr(addr)) = parts.extensions.get::<RemoteAddr>() {
+            let ip = addr.ip().to_string();
+            append_list(&mut headers, "x-forwarded-for", &ip);
+            let node = match addr {
+                SocketAddr::V4(_) => ip,
+                SocketAddr::V6(_) => format!("\"[{}]\"", ip),
+            };
+            let mut element = format!("for={};proto={}", node, scheme);
+            if !authority.is_empty() {
+                element.push_str(&format!(";host=\"{}\"", authority));
+            }
+            append_list(&mut headers, "forwarded", &element);
+        }
+        if let Ok(value) = HeaderValue::from_str(&scheme) {
+            headers.insert("x-forwarded-proto", value);
+        }
+
+        Forward {
+            method: parts.method,
+            scheme,
+            authority,
+            path,
+            headers,
+            body,
+            trailers: parts.extensions.get::<Trailers>().cloned(),
+        }
+    }
+}
+
+impl Handler for ReverseProxy {
+    fn call(&self, _token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let forward = self.forward(request);
+        // https://datatracker.ietf.org/doc/html/rfc9110#name-idempotent-methods
+        let idempotent = matches!(
+            forward.method,
+            Method::GET
+                | Method::HEAD
+                | Method::PUT
+                | Method::DELETE
+                | Method::OPTIONS
+                | Method::TRACE
+        );
+        Deferred::into_response(Exchange {
+            upstreams: self.upstreams.clone(),
+            forward,
+            idempotent,
+            connect_timeout: self.connect_timeout,
+            timeout: self.timeout,
+            retries: self.retries,
+            tried: Vec::new(),
+            status: StatusCode::BAD_GATEWAY,
+            attempt: None,
+            registration: None,
+        })
+    }
+}
+
+/// A request relayed to the upstreams until one of them answers it.
+struct Exchange {
+    upstreams: Upstreams,
+    forward: Forward,
+    idempotent: bool,
+    connect_timeout: Duration,
+    timeout: Duration,
+    retries: usize,
+    tried: Vec<usize>,
+    /// Answered once every attempt failed.
+    status: StatusCode,
+    attempt: Option<Attempt>,
+    /// Where the server registered the exchange, the socket of every attempt
+    /// is registered there in turn.
+    registration: Option<(Registry, Token, Interest)>,
+}
+
+impl Exchange {
+    /// Starts connecting to the next upstream, `false` when there's none left
+    /// to try.
+    fn start_attempt(&mut self) -> bool {
+        while self.tried.len() <= self.retries {
+            let index = match self.upstreams.select(&self.tried) {
+                Some(index) => index,
+                None => return false,
+            };
+            self.tried.push(index);
+            let upstream = &self.upstreams.list[index];
+            let mut attempt =
+                match Attempt::connect(upstream, &self.forward, self.connect_timeout, self.timeout)
+                {
+                    Ok(attempt) => attempt,
+                    Err(e) => {
+                        log_warn!("upstream {}: {}", upstream.addr, e);
+                        self.status = e.status();
+                        continue;
+                    }
+                };
+            if let Some((registry, token, interests)) = &self.registration {
+                if let Err(e) = registry.register(&mut attempt.socket.socket, *token, *interests) {
+                    log_warn!("upstream {}: {}", upstream.addr, e);
+                    continue;
+                }
+            }
+            self.attempt = Some(attempt);
+            return true;
+        }
+        false
+    }
+
+    fn failed(&self) -> Response<Vec<u8>> {
+        Response::builder()
+            .status(self.status)
+            .body(Vec::new())
+            .unwrap()
+    }
+}
+
+impl DeferredResponse for Exchange {
+    fn poll_response(&mut self) -> io::Result<Option<Response<Vec<u8>>>> {
+        loop {
+            if self.attempt.is_none() && !self.start_attempt() {
+                return Ok(Some(self.failed()));
+            }
+            let attempt = match &mut self.attempt {
+                Some(attempt) => attempt,
+                None => continue,
+            };
+            match attempt.poll(&self.forward) {
+                Ok(false) => return Ok(None),
+                Ok(true) => {
+                    if let Some(attempt) = self.attempt.take() {
+                        return Ok(Some(attempt.into_response(&self.forward.method)));
+                    }
+                }
+                Err(e) => {
+                    log_warn!("upstream {}: {}", attempt.addr, e);
+                    self.attempt = None;
+                    self.status = e.status();
+                    if !self.idempotent && !matches!(e, ProxyError::Connect(_)) {
+                        return Ok(Some(self.failed()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        Some(self)
+    }
+}
+
+impl Source for Exchange {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.registration = Some((registry.try_clone()?, token, interests));
+        match &mut self.attempt {
+            Some(attempt) => registry.register(&mut attempt.socket.socket, token, interests),
+            None => Ok(()),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.registration = Some((registry.try_clone()?, token, interests));
+        match &mut self.attempt {
+            Some(attempt) => registry.reregister(&mut attempt.socket.socket, token, interests),
+            None => Ok(()),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.registration = None;
+        match &mut self.attempt {
+            Some(attempt) => registry.deregister(&mut attempt.socket.socket),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Socket to an upstream, its deadline moves on whenever data goes through.
+struct UpstreamSocket {
+    socket: TcpStream,
+    deadline: Instant,
+    timeout: Duration,
+}
+
+impl Read for UpstreamSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.socket.read(buf)?;
+        self.deadline = Instant::now() + self.timeout;
+        Ok(len)
+    }
+}
+
+impl Write for UpstreamSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.socket.write(buf)?;
+        self.deadline = Instant::now() + self.timeout;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+enum Progress {
+    Http1 {
+        head: Vec<u8>,
+        /// Bytes of the head and then of the body written so far.
+        written: usize,
+        buffer: Vec<u8>,
+        response: Option<(StatusCode, HeaderMap)>,
+    },
+    Http2 {
+        state: Http2State,
+        /// Bytes of the body queued so far.
+        offset: usize,
+        trailers_sent: bool,
+        block: HeaderList,
+        status: Option<StatusCode>,
+    },
+}
+
+/// One try at getting the response from an upstream.
+struct Attempt {
+    addr: SocketAddr,
+    socket: UpstreamSocket,
+    connected: bool,
+    progress: Progress,
+    active: ActiveGuard,
+}
+
+impl Attempt {
+    fn connect(
+        upstream: &Upstream,
+        forward: &Forward,
+        connect_timeout: Duration,
+        timeout: Duration,
+    ) -> Result<Self, ProxyError> {
+        let socket = TcpStream::connect(upstream.addr).map_err(ProxyError::Connect)?;
+        let progress = match upstream.protocol {
+            UpstreamProtocol::Http1 => Progress::Http1 {
+                head: forward.http1_head(),
+                written: 0,
+                buffer: Vec::new(),
+                response: None,
+            },
+            UpstreamProtocol::Http2 => {
+                let mut state = Http2State::new();
+                state.write_buffer.extend_from_slice(frames::PREFACE);
+                state.queue_frame(
+                    FRAME_SETTINGS,
+                    0,
+                    0,
+                    &frames::settings_payload(&[(SETTINGS_ENABLE_PUSH, 0)]),
+                );
+                let no_body = forward.body.is_empty() && forward.trailers.is_none();
+                state.queue_headers(&forward.http2_headers(), no_body);
+                Progress::Http2 {
+                    state,
+                    offset: 0,
+                    trailers_sent: false,
+                    block: Vec::new(),
+                    status: None,
+                }
+            }
+        };
+        Ok(Self {
+            addr: upstream.addr,
+            socket: UpstreamSocket {
+                socket,
+                deadline: Instant::now() + connect_timeout,
+                timeout,
+            },
+            connected: false,
+            progress,
+            active: ActiveGuard::new(&upstream.active),
+        })
+    }
+
+    /// Writes the request and reads the response as far as the socket allows,
+    /// `true` once the response headers were received.
+    fn poll(&mut self, forward: &Forward) -> Result<bool, ProxyError> {
+        if !self.connected {
+            if let Some(e) = self
+                .socket
+                .socket
+                .take_error()
+                .map_err(ProxyError::Connect)?
+            {
+                return Err(ProxyError::Connect(e));
+            }
+            match self.socket.socket.peer_addr() {
+                Ok(_) => {
+                    self.connected = true;
+                    self.socket.deadline = Instant::now() + self.socket.timeout;
+                    self.socket.socket.set_nodelay(true)?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+                    if Instant::now() >= self.socket.deadline {
+                        return Err(ProxyError::Connect(io::ErrorKind::TimedOut.into()));
+                    }
+                    return Ok(false);
+                }
+                Err(e) => return Err(ProxyError::Connect(e)),
+            }
+        }
+
+        let done = match &mut self.progress {
+            Progress::Http1 {
+                head,
+                written,
+                buffer,
+                response,
+            } => {
+                write_http1(&mut self.socket, head, &forward.body, written)?;
+                if response.is_none() {
+                    *response = read_http1_response(&mut self.socket, buffer)?;
+                }
+                response.is_some()
+            }
+            Progress::Http2 {
+                state,
+                offset,
+                trailers_sent,
+                block,
+                status,
+            } => {
+                if status.is_none() {
+                    *status = exchange_http2(
+                        &mut self.socket,
+                        state,
+                        forward,
+                        offset,
+                        trailers_sent,
+                        block,
+                    )?;
+                }
+                status.is_some()
+            }
+        };
+        if !done && Instant::now() >= self.socket.deadline {
+            return Err(ProxyError::Timeout);
+        }
+        Ok(done)
+    }
+
+    fn into_response(self, method: &Method) -> Response<Vec<u8>> {
+        let (status, mut headers, body) = match self.progress {
+            Progress::Http1 {
+                buffer, response, ..
+            } => {
+                let (status, mut headers) =
+                    response.unwrap_or((StatusCode::BAD_GATEWAY, HeaderMap::new()));
+                let framing = http1_framing(method, status, &mut headers);
+                let body = StreamingBody::new(Http1Body {
+                    socket: self.socket.socket,
+                    buffer,
+                    framing,
+                    eof: false,
+                    _active: self.active,
+                });
+                (status, headers, body)
+            }
+            Progress::Http2 {
+                state,
+                block,
+                status,
+                ..
+            } => {
+                let mut headers = HeaderMap::new();
+                for (name, value) in block {
+                    if name.starts_with(b":") {
+                        continue;
+                    }
+                    if let (Ok(name), Ok(value)) = (
+                        HeaderName::from_bytes(&name),
+                        HeaderValue::from_bytes(&value),
+                    ) {
+                        headers.append(name, value);
+                    }
+                }
+                let ended = state.end_stream;
+                let body = StreamingBody::new(Http2Body {
+                    socket: self.socket.socket,
+                    state,
+                    pending: Vec::new(),
+                    trailers: None,
+                    ended,
+                    _active: self.active,
+                });
+                (status.unwrap_or(StatusCode::BAD_GATEWAY), headers, body)
+            }
+        };
+        strip_hop_by_hop(&mut headers);
+        let mut response = Response::builder().status(status).body(Vec::new()).unwrap();
+        *response.headers_mut() = headers;
+        response.extensions_mut().insert(body);
+        response
+    }
+}
+
+/// Writes what's left of the request head and body.
+fn write_http1<S: Write>(
+    socket: &mut S,
+    head: &[u8],
+    body: &[u8],
+    written: &mut usize,
+) -> Result<(), ProxyError> {
+    while *written < head.len() + body.len() {
+        let rest = match head.get(*written..) {
+            Some(rest) if !rest.is_empty() => rest,
+            _ => &body[*written - head.len()..],
+        };
+        match socket.write(rest) {
+            Ok(0) => return Err(ProxyError::IOError(io::ErrorKind::WriteZero.into())),
+            Ok(len) => *written += len,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Reads until the final response head, `None` when the socket has nothing
+/// more to read right now.
+fn read_http1_response<S: Read>(
+    socket: &mut S,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<(StatusCode, HeaderMap)>, ProxyError> {
+    loop {
+        while let Some((status, headers)) = parse_http1_head(buffer)? {
+            if !status.is_informational() {
+                return Ok(Some((status, headers)));
+            }
+        }
+        if buffer.len() > MAX_HEAD_LEN {
+            return Err(ProxyError::InvalidResponse);
+        }
+        let mut chunk = [0u8; 4096];
+        match socket.read(&mut chunk) {
+            Ok(0) => return Err(ProxyError::InvalidResponse),
+            Ok(len) => buffer.extend_from_slice(&chunk[..len]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn http1_framing(method: &Method, status: StatusCode, headers: &mut HeaderMap) -> Framing {
+    let chunked = headers
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("chunked"));
+    let content_length = headers
+        .get("content-length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    if method == Method::HEAD
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        Framing::Length(0)
+    } else if chunked {
+        headers.remove("content-length");
+        Framing::Chunked(ChunkState::Size)
+    } else if let Some(len) = content_length {
+        Framing::Length(len)
+    } else {
+        Framing::Close
+    }
+}
+
+/// Sends the request body as the upstream's windows allow and reads frames
+/// until the final response headers, returning their status.
+fn exchange_http2<S: Read + Write>(
+    socket: &mut S,
+    state: &mut Http2State,
+    forward: &Forward,
+    offset: &mut usize,
+    trailers_sent: &mut bool,
+    block: &mut HeaderList,
+) -> Result<Option<StatusCode>, ProxyError> {
+    loop {
+        // Only a few frames are queued ahead of the socket.
+        while *offset < forward.body.len() && state.write_buffer.len() < READ_SIZE {
+            let budget =
+                i64::min(state.send_window, state.stream_window).min(state.max_frame_size as i64);
+            if budget <= 0 {
+                break;
+            }
+            let end = usize::min(*offset + budget as usize, forward.body.len());
+            let last = end == forward.body.len() && forward.trailers.is_none();
+            let flags = if last { FLAG_END_STREAM } else { 0 };
+            state.queue_frame(FRAME_DATA, flags, 1, &forward.body[*offset..end]);
+            state.send_window -= (end - *offset) as i64;
+            state.stream_window -= (end - *offset) as i64;
+            *offset = end;
+        }
+        if *offset == forward.body.len() && !*trailers_sent {
+            if let Some(trailers) = &forward.trailers {
+                state.queue_headers(&trailers.to_list(), true);
+            }
+            *trailers_sent = true;
+        }
+        match state.flush(socket) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            result => result?,
+        }
+
+        let frame = match state.read_frame(socket)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let end_headers = match &frame.payload {
+            Payload::Headers(headers_payload) if frame.stream_id.to_u32() == 1 => {
+                block.extend(state.decode(&headers_payload.HeaderBlockFragment)?);
+                frame.flags & HeadersPayloadFlag::END_HEADERS == HeadersPayloadFlag::END_HEADERS
+            }
+            Payload::Continuation(continuation_payload) if frame.stream_id.to_u32() == 1 => {
+                block.extend(state.decode(&continuation_payload.HeaderBlockFragment)?);
+                frame.flags & ContinuationPayloadFlag::END_HEADERS
+                    == ContinuationPayloadFlag::END_HEADERS
+            }
+            Payload::Data(_) if frame.stream_id.to_u32() == 1 => {
+                return Err(ProxyError::InvalidResponse)
+            }
+            _ => {
+                state.handle_control(&frame)?;
+                continue;
+            }
+        };
+        if frame.flags & HeadersPayloadFlag::END_STREAM == HeadersPayloadFlag::END_STREAM {
+            state.end_stream = true;
+        }
+        if !end_headers {
+            continue;
+        }
+        let status = block
+            .iter()
+            .find(|(name, _)| name.as_slice() == b":status")
+            .and_then(|(_, value)| StatusCode::from_bytes(value).ok())
+            .ok_or(ProxyError::InvalidResponse)?;
+        if status.is_informational() {
+            block.clear();
+            continue;
+        }
+        return Ok(Some(status));
+    }
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    for name in listed.iter().map(|name| name.as_str()).chain(HOP_BY_HOP) {
+        headers.remove(name);
+    }
+}
+
+fn append_list(headers: &mut HeaderMap, name: &'static str, element: &str) {
+    let value = match headers.get(name).and_then(|value| value.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, element),
+        None => element.to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_header_lines(lines: &[u8]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for line in lines.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let colon = match line.iter().position(|b| *b == b':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        let value = String::from_utf8_lossy(&line[colon + 1..]);
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(&line[..colon]),
+            HeaderValue::from_str(value.trim()),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    headers
+}
+
+/// Takes a status line and headers off `buffer`, `None` until they are
+/// complete.
+fn parse_http1_head(buffer: &mut Vec<u8>) -> Result<Option<(StatusCode, HeaderMap)>, ProxyError> {
+    let end = match find(buffer, b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let head: Vec<u8> = buffer.drain(..end + 4).collect();
+    let line_end = find(&head, b"\r\n").unwrap_or(end);
+    let status_line = &head[..line_end];
+    if !status_line.starts_with(b"HTTP/1.") {
+        return Err(ProxyError::InvalidResponse);
+    }
+    let status = status_line
+        .split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| StatusCode::from_bytes(code).ok())
+        .ok_or(ProxyError::InvalidResponse)?;
+    Ok(Some((status, parse_header_lines(&head[line_end..]))))
+}
+
+enum ChunkState {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+}
+
+enum Framing {
+    Length(u64),
+    Chunked(ChunkState),
+    /// The body ends when the upstream closes the connection.
+    Close,
+}
+
+/// Response body of an HTTP/1.1 upstream.
+struct Http1Body {
+    socket: TcpStream,
+    buffer: Vec<u8>,
+    framing: Framing,
+    eof: bool,
+    _active: ActiveGuard,
+}
+
+impl Http1Body {
+    fn take_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match find(&self.buffer, b"\r\n") {
+            Some(end) => {
+                let line = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + 2);
+                Ok(Some(line))
+            }
+            None if self.buffer.len() > 1024 => Err(io::ErrorKind::InvalidData.into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes the next chunk from the buffered bytes, `None` when more have to
+    /// be read first.
+    fn decode(&mut self, max_len: usize) -> io::Result<Option<BodyChunk>> {
+        loop {
+            match &mut self.framing {
+                Framing::Length(0) => return Ok(Some(BodyChunk::End)),
+                Framing::Length(remaining) => {
+                    if self.buffer.is_empty() {
+                        return Ok(None);
+                    }
+                    let len = u64::min(*remaining, max_len as u64) as usize;
+                    let data: Vec<u8> = self.buffer.drain(..len.min(self.buffer.len())).collect();
+                    *remaining -= data.len() as u64;
+                    return Ok(Some(BodyChunk::Data(data)));
+                }
+                Framing::Close => {
+                    if !self.buffer.is_empty() {
+                        let len = usize::min(max_len, self.buffer.len());
+                        return Ok(Some(BodyChunk::Data(self.buffer.drain(..len).collect())));
+                    }
+                    if self.eof {
+                        return Ok(Some(BodyChunk::End));
+                    }
+                    return Ok(None);
+                }
+                Framing::Chunked(ChunkState::Size) => {
+                    let line = match self.take_line()? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    let size = String::from_utf8_lossy(&line);
+                    let size = size.split(';').next().unwrap_or_default().trim();
+                    let size = u64::from_str_radix(size, 16)
+                        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                    self.framing = match size {
+                        0 => Framing::Chunked(ChunkState::Trailers),
+                        size => Framing::Chunked(ChunkState::Data(size)),
+                    };
+                }
+                Framing::Chunked(ChunkState::Data(remaining)) => {
+                    if self.buffer.is_empty() {
+                        return Ok(None);
+                    }
+                    let len = u64::min(*remaining, max_len as u64) as usize;
+                    let data: Vec<u8> = self.buffer.drain(..len.min(self.buffer.len())).collect();
+                    *remaining -= data.len() as u64;
+                    if *remaining == 0 {
+                        self.framing = Framing::Chunked(ChunkState::DataEnd);
+                    }
+                    return Ok(Some(BodyChunk::Data(data)));
+                }
+                Framing::Chunked(ChunkState::DataEnd) => match self.take_line()? {
+                    Some(line) if line.is_empty() => {
+                        self.framing = Framing::Chunked(ChunkState::Size)
+                    }
+                    Some(_) => return Err(io::ErrorKind::InvalidData.into()),
+                    None => return Ok(None),
+                },
+                Framing::Chunked(ChunkState::Trailers) => {
+                    if self.buffer.starts_with(b"\r\n") {
+                        return Ok(Some(BodyChunk::End));
+                    }
+                    let end = match find(&self.buffer, b"\r\n\r\n") {
+                        Some(end) => end,
+                        None if self.buffer.len() > MAX_HEAD_LEN => {
+                            return Err(io::ErrorKind::InvalidData.into())
+                        }
+                        None => return Ok(None),
+                    };
+                    let mut trailers = parse_header_lines(&self.buffer[..end]);
+                    strip_hop_by_hop(&mut trailers);
+                    return Ok(Some(BodyChunk::Trailers(trailers)));
+                }
+            }
+        }
+    }
+}
+
+impl BodyStream for Http1Body {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        loop {
+            if let Some(chunk) = self.decode(max_len)? {
+                return Ok(chunk);
+            }
+            if self.eof {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let mut chunk = [0u8; READ_SIZE];
+            match self.socket.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(BodyChunk::Pending),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        Some(&mut self.socket)
+    }
+}
+
+/// Client side of a single-stream HTTP/2 connection to an upstream.
+struct Http2State {
+    encoder: HpackContext,
+    decoder: HpackContext,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    send_window: i64,
+    stream_window: i64,
+    initial_window: i64,
+    max_frame_size: u32,
+    end_stream: bool,
+}
+
+impl Http2State {
+    fn new() -> Self {
+        Self {
+            encoder: HpackContext::new(128),
+            decoder: HpackContext::new(128),
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            send_window: frames::DEFAULT_WINDOW_SIZE,
+            stream_window: frames::DEFAULT_WINDOW_SIZE,
+            initial_window: frames::DEFAULT_WINDOW_SIZE,
+            max_frame_size: 16384,
+            end_stream: false,
+        }
+    }
+
+    fn queue_frame(&mut self, frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        self.write_buffer
+            .extend(frames::encode_frame(frame_type, flags, stream_id, payload));
+    }
+
+    fn queue_headers(&mut self, headers: &HeaderList, end_stream: bool) {
+        let mut hpack = Hpack::new();
+        hpack.encode(headers, &mut self.encoder);
+        let block = <Payload as Into<Vec<u8>>>::into(Payload::Headers(HeadersPayload {
+            HeaderBlockFragment: hpack,
+            PadLength: None,
+            Padding: None,
+            Priority: None,
+        }));
+        let max_len = self.max_frame_size as usize;
+        let mut chunks = block.chunks(max_len).peekable();
+        let mut frame_type = FRAME_HEADERS;
+        let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+        if chunks.peek().is_none() {
+            self.queue_frame(frame_type, flags | FLAG_END_HEADERS, 1, &[]);
+        }
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= FLAG_END_HEADERS;
+            }
+            self.queue_frame(frame_type, flags, 1, chunk);
+            frame_type = FRAME_CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    fn decode(&mut self, hpack: &Hpack) -> Result<HeaderList, ProxyError> {
+        hpack
+            .decode(&mut self.decoder)
+            .map(|(headers, _)| headers)
+            .map_err(|_| ProxyError::InvalidResponse)
+    }
+
+    fn flush<S: Write>(&mut self, socket: &mut S) -> io::Result<()> {
+        while !self.write_buffer.is_empty() {
+            match socket.write(&self.write_buffer) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.write_buffer.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the next complete frame, `None` when the socket has nothing
+    /// more to read right now.
+    fn read_frame<S: Read>(&mut self, socket: &mut S) -> io::Result<Option<Frame>> {
+        loop {
+            if self.read_buffer.len() >= 9 {
+                let len = u32::from_be_bytes([
+                    0,
+                    self.read_buffer[0],
+                    self.read_buffer[1],
+                    self.read_buffer[2],
+                ]) as usize;
+                if self.read_buffer.len() >= 9 + len {
+                    let frame = <Frame as TryFrom<&[u8]>>::try_from(&self.read_buffer[..9 + len])
+                        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                    self.read_buffer.drain(..9 + len);
+                    return Ok(Some(frame));
+                }
+            }
+            let mut chunk = [0u8; READ_SIZE];
+            match socket.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => self.read_buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Connection-level frames and the ones for other streams.
+    fn handle_control(&mut self, frame: &Frame) -> io::Result<()> {
+        match &frame.payload {
+            Payload::Settings(settings_payload) => {
+                if frame.flags & FLAG_ACK == FLAG_ACK {
+                    return Ok(());
+                }
+                for (id, value) in settings_payload.settings.iter() {
+                    match *id {
+                        SETTINGS_INITIAL_WINDOW_SIZE => {
+                            self.stream_window += *value as i64 - self.initial_window;
+                            self.initial_window = *value as i64;
+                        }
+                        SETTINGS_MAX_FRAME_SIZE => self.max_frame_size = *value,
+                        _ => {}
+                    }
+                }
+                self.queue_frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]);
+            }
+            Payload::Ping(ping_payload) if frame.flags & FLAG_ACK != FLAG_ACK => {
+                let opaque = ping_payload.OpaqueData.to_be_bytes();
+                self.queue_frame(FRAME_PING, FLAG_ACK, 0, &opaque);
+            }
+            Payload::WindowUpdate(window_update_payload) => {
+                let increment = window_update_payload.WindowSizeIncrement as i64;
+                match frame.stream_id.to_u32() {
+                    0 => self.send_window += increment,
+                    1 => self.stream_window += increment,
+                    _ => {}
+                }
+            }
+            Payload::RstStream(_) if frame.stream_id.to_u32() == 1 => {
+                return Err(io::ErrorKind::ConnectionReset.into())
+            }
+            Payload::GoAway(_) => return Err(io::ErrorKind::ConnectionAborted.into()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Response body of an HTTP/2 upstream. Its stream window is only reopened
+/// once the data was handed to the client.
+struct Http2Body {
+    socket: TcpStream,
+    state: Http2State,
+    pending: Vec<u8>,
+    trailers: Option<HeaderList>,
+    ended: bool,
+    _active: ActiveGuard,
+}
+
+impl BodyStream for Http2Body {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        loop {
+            if !self.pending.is_empty() {
+                let len = usize::min(max_len, self.pending.len());
+                let data: Vec<u8> = self.pending.drain(..len).collect();
+                let increment = frames::window_update_payload(len as u32);
+                self.state
+                    .queue_frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+                if !self.ended {
+                    self.state
+                        .queue_frame(FRAME_WINDOW_UPDATE, 0, 1, &increment);
+                }
+                self.flush()?;
+                return Ok(BodyChunk::Data(data));
+            }
+            if self.ended {
+                return Ok(match self.trailers.take() {
+                    Some(trailers) => BodyChunk::Trailers(Trailers::from_list(&trailers).0),
+                    None => BodyChunk::End,
+                });
+            }
+
+            self.flush()?;
+            let frame = match self.state.read_frame(&mut self.socket)? {
+                Some(frame) => frame,
+                None => return Ok(BodyChunk::Pending),
+            };
+            let on_stream = frame.stream_id.to_u32() == 1;
+            match &frame.payload {
+                Payload::Data(data_payload) if on_stream => {
+                    self.pending.extend_from_slice(&data_payload.data);
+                    self.ended =
+                        frame.flags & DataPayloadFlag::END_STREAM == DataPayloadFlag::END_STREAM;
+                }
+                Payload::Headers(headers_payload) if on_stream => {
+                    if frame.flags & HeadersPayloadFlag::END_STREAM
+                        != HeadersPayloadFlag::END_STREAM
+                    {
+                        return Err(io::ErrorKind::InvalidData.into());
+                    }
+                    let headers = self
+                        .state
+                        .decode(&headers_payload.HeaderBlockFragment)
+                        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                    self.trailers = Some(headers);
+                    self.ended = frame.flags & HeadersPayloadFlag::END_HEADERS
+                        == HeadersPayloadFlag::END_HEADERS;
+                }
+                Payload::Continuation(continuation_payload) if on_stream => {
+                    let headers = self
+                        .state
+                        .decode(&continuation_payload.HeaderBlockFragment)
+                        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                    self.trailers.get_or_insert_with(Vec::new).extend(headers);
+                    self.ended = frame.flags & ContinuationPayloadFlag::END_HEADERS
+                        == ContinuationPayloadFlag::END_HEADERS;
+                }
+                _ => self.state.handle_control(&frame)?,
+            }
+        }
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        Some(&mut self.socket)
+    }
+}
+
+impl Http2Body {
+    fn flush(&mut self) -> io::Result<()> {
+        match self.state.flush(&mut self.socket) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use mio::{Events, Poll};
+
+    use super::*;
+
+    /// Calls `step` until it returns a value, waiting for the sockets
+    /// registered with `poll` in between as the server would.
+    fn run<T>(poll: &mut Poll, mut step: impl FnMut() -> io::Result<Option<T>>) -> T {
+        let mut events = Events::with_capacity(8);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(value) = step().unwrap() {
+                return value;
+            }
+            assert!(Instant::now() < deadline, "the exchange is stuck");
+            poll.poll(&mut events, Some(Duration::from_millis(50))).unwrap();
+        }
+    }
+
+    /// Relays `request` and waits for the response headers.
+    fn respond(proxy: &ReverseProxy, request: Request<Vec<u8>>) -> (Poll, Response<Vec<u8>>) {
+        let mut response = proxy.call(Token(0), request);
+        let mut deferred = response
+            .extensions_mut()
+            .remove::<Deferred>()
+            .and_then(|deferred| deferred.take())
+            .unwrap();
+        let mut poll = Poll::new().unwrap();
+        let first = deferred.poll_response().unwrap();
+        poll.registry()
+            .register(
+                deferred.source().unwrap(),
+                Token(1),
+                Interest::READABLE | Interest::WRITABLE,
+            )
+            .unwrap();
+        let response = match first {
+            Some(response) => response,
+            None => run(&mut poll, || deferred.poll_response()),
+        };
+        (poll, response)
+    }
+
+    fn body(poll: &mut Poll, response: &Response<Vec<u8>>) -> Vec<u8> {
+        let mut body = response
+            .extensions()
+            .get::<StreamingBody>()
+            .and_then(|body| body.take())
+            .unwrap();
+        let mut received = Vec::new();
+        run(poll, || loop {
+            match body.next_chunk(READ_SIZE)? {
+                BodyChunk::Data(data) => received.extend(data),
+                BodyChunk::End => return Ok(Some(())),
+                _ => return Ok(None),
+            }
+        });
+        received
+    }
+
+    /// Answers one request with `response`, returning the request received.
+    fn upstream(response: &'static [u8]) -> (SocketAddr, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            loop {
+                if let Some(end) = find(&request, b"\r\n\r\n") {
+                    let len = parse_header_lines(&request[..end])
+                        .get("content-length")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + len {
+                        break;
+                    }
+                }
+                match stream.read(&mut chunk).unwrap() {
+                    0 => break,
+                    len => request.extend_from_slice(&chunk[..len]),
+                }
+            }
+            stream.write_all(response).unwrap();
+            request
+        });
+        (addr, thread)
+    }
+
+    fn post(body: Vec<u8>) -> Request<Vec<u8>> {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/upload?id=1")
+            .header("host", "example.com")
+            .header("connection", "x-hop")
+            .header("x-hop", "1")
+            .body(body)
+            .unwrap()
+    }
+
+    #[test]
+    fn http1_exchange_streams_both_bodies() {
+        let (addr, upstream) = upstream(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 201 Created\r\ntransfer-encoding: chunked\r\nx-upstream: 1\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        );
+        let proxy = ReverseProxy::new().upstream(addr, UpstreamProtocol::Http1);
+        // More than the socket buffers take at once.
+        let sent = vec![b'x'; 4 * 1024 * 1024];
+        let (mut poll, response) = respond(&proxy, post(sent.clone()));
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-upstream"], "1");
+        assert!(response.headers().get("transfer-encoding").is_none());
+        assert_eq!(body(&mut poll, &response), b"hello world");
+
+        let request = upstream.join().unwrap();
+        let end = find(&request, b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&request[..end]);
+        assert!(head.starts_with("POST /upload?id=1 HTTP/1.1\r\nhost: example.com\r\n"));
+        assert!(head.contains(&format!("content-length: {}", sent.len())));
+        assert!(!head.contains("x-hop"));
+        assert!(&request[end + 4..] == sent.as_slice());
+    }
+
+    #[test]
+    fn only_te_trailers_is_forwarded() {
+        let proxy = ReverseProxy::new();
+        let te = |value: &str| {
+            let request = Request::builder()
+                .header("te", value)
+                .body(Vec::new())
+                .unwrap();
+            proxy.forward(request).headers.get("te").cloned()
+        };
+        assert_eq!(te("trailers").unwrap(), "trailers");
+        assert_eq!(te("gzip, Trailers;q=1").unwrap(), "trailers");
+        assert_eq!(te("gzip"), None);
+    }
+
+    #[test]
+    fn silent_upstream_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = ReverseProxy::new()
+            .upstream(listener.local_addr().unwrap(), UpstreamProtocol::Http1)
+            .timeout(Duration::from_millis(200))
+            .retries(0);
+        let (_, response) = respond(&proxy, post(b"hello".to_vec()));
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn unreachable_upstream_is_retried_on_the_next() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (addr, upstream) = upstream(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok");
+        let proxy = ReverseProxy::new()
+            .upstream(closed, UpstreamProtocol::Http1)
+            .upstream(addr, UpstreamProtocol::Http1);
+        // Nothing reached the first upstream, so even a POST is retried.
+        let (mut poll, response) = respond(&proxy, post(b"hello".to_vec()));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(&mut poll, &response), b"ok");
+        assert!(upstream.join().unwrap().ends_with(b"\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn failed_post_is_not_retried() {
+        let (first, dropped) = upstream(b"");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = ReverseProxy::new()
+            .upstream(first, UpstreamProtocol::Http1)
+            .upstream(listener.local_addr().unwrap(), UpstreamProtocol::Http1);
+        let (_, response) = respond(&proxy, post(b"hello".to_vec()));
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        dropped.join().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    sync::Arc,
+};
+
+use http::{Request, Response};
+use kparser::u31::u31;
+use mio::{event::Source, Interest, Registry, Token};
+
+use super::{
+    context::{ContextError, Http2Context},
+    frames::{FLAG_ACK, FRAME_GOAWAY, FRAME_RST_STREAM, FRAME_SETTINGS},
+    limits::{BodyLimits, FloodLimits},
+    metrics::Metrics,
+    trace::FrameTracer,
+    transport::Transport,
+};
+
+/// What a peer did, as reported by `Http2Session::receive`.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum SessionEvent {
+    /// A request received in full, to be answered with `send_response`.
+    Request {
+        stream_id: u31,
+        request: Request<Vec<u8>>,
+    },
+    /// Body bytes of a request still being received, when `stream_data` is
+    /// enabled. The `Request` then only carries the rest of the body.
+    Data {
+        stream_id: u31,
+        data: Vec<u8>,
+    },
+    Reset {
+        stream_id: u31,
+        error_code: u32,
+    },
+    /// The peer's SETTINGS, already applied.
+    Settings(Vec<(u16, u32)>),
+    GoAway {
+        last_stream_id: u32,
+        error_code: u32,
+    },
+}
+
+impl SessionEvent {
+    /// The event of a raw control frame, if it is one.
+    pub(crate) fn from_frame(frame: &[u8], stream_id: u31) -> Option<Self> {
+        let payload = frame.get(9..)?;
+        let word = |offset: usize| -> Option<u32> {
+            Some(u32::from_be_bytes(
+                payload.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+        match frame[3] {
+            FRAME_RST_STREAM => Some(SessionEvent::Reset {
+                stream_id,
+                error_code: word(0)?,
+            }),
+            FRAME_SETTINGS if frame[4] & FLAG_ACK == 0 => Some(SessionEvent::Settings(
+                payload
+                    .chunks_exact(6)
+                    .map(|setting| {
+                        (
+                            u16::from_be_bytes([setting[0], setting[1]]),
+                            u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]),
+                        )
+                    })
+                    .collect(),
+            )),
+            FRAME_GOAWAY => Some(SessionEvent::GoAway {
+                last_stream_id: word(0)? & 0x7fff_ffff,
+                error_code: word(4)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The server side of one HTTP/2 connection without any I/O: bytes read
+/// from the peer go into `receive`, which returns what happened, and
+/// `take_outbound` returns what to write back. Nothing has to be registered
+/// with mio, so it can be driven from any event loop or a fuzzer.
+///
+/// Streamed response bodies are pulled by `poll_bodies`.
+pub struct Http2Session {
+    context: Http2Context<Detached>,
+    stream_data: bool,
+    closed: bool,
+}
+
+impl Default for Http2Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Http2Session {
+    pub fn new() -> Self {
+        let mut context = Http2Context::new(Detached::default(), None, None);
+        context.record_events();
+        Self {
+            context,
+            stream_data: false,
+            closed: false,
+        }
+    }
+
+    pub fn stream_data(mut self, enable: bool) -> Self {
+        self.stream_data = enable;
+        self
+    }
+
+    pub fn flood_limits(mut self, limits: FloodLimits) -> Self {
+        self.context.set_flood_limits(limits);
+        self
+    }
+
+    pub fn body_limits(mut self, limits: BodyLimits) -> Self {
+        self.context.set_body_limits(limits);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.context.set_metrics(metrics);
+        self
+    }
+
+    pub fn tracer(mut self, tracer: Arc<dyn FrameTracer>) -> Self {
+        self.context.set_tracer(tracer);
+        self
+    }
+
+    /// Feeds bytes received from the peer, in any chunking.
+    ///
+    /// An error means the connection is unusable, the bytes of
+    /// `take_outbound` (a GOAWAY for example) should still be sent before
+    /// closing it.
+    pub fn receive(&mut self, data: &[u8]) -> Result<Vec<SessionEvent>, ContextError> {
+        let result = self.context.receive(data, self.stream_data);
+        let events = self.context.take_events();
+        match result {
+            Ok(_) => Ok(events),
+            // The peer's GOAWAY ends the session, it is reported as an event.
+            Err(ContextError::ClientDisconnected)
+                if matches!(events.last(), Some(SessionEvent::GoAway { .. })) =>
+            {
+                self.closed = true;
+                Ok(events)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn send_response(
+        &mut self,
+        stream_id: u31,
+        response: Response<Vec<u8>>,
+    ) -> Result<(), ContextError> {
+        self.context.send_http_response(stream_id, response)
+    }
+
+    pub fn reset_stream(&mut self, stream_id: u31, error_code: u32) -> Result<(), ContextError> {
+        self.context.reset_stream(stream_id, error_code)
+    }
+
+    /// Pulls streamed response bodies for as much as flow control allows.
+    pub fn poll_bodies(&mut self) -> Result<(), ContextError> {
+        self.context.pump_outgoing()
+    }
+
+    pub fn has_pending_bodies(&self) -> bool {
+        self.context.has_outgoing()
+    }
+
+    /// The bytes to write to the peer, in order.
+    pub fn take_outbound(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.context.transport_mut().outbound)
+    }
+
+    /// The peer sent GOAWAY.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// Collects what the context writes, nothing is ever read from it.
+#[derive(Default)]
+struct Detached {
+    outbound: Vec<u8>,
+}
+
+impl Read for Detached {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Write for Detached {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Source for Detached {
+    fn register(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _: &Registry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Detached {
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http2::frames::{
+        encode_frame, FLAG_END_HEADERS, FLAG_END_STREAM, FRAME_DATA, FRAME_HEADERS,
+        FRAME_PUSH_PROMISE, FRAME_SIZE_ERROR, PREFACE, PROTOCOL_ERROR,
+    };
+
+    /// GET / over http, from the HPACK static table.
+    fn get_request(extra: &[u8]) -> Vec<u8> {
+        let mut block = vec![0x82, 0x86, 0x84];
+        block.extend_from_slice(extra);
+        encode_frame(FRAME_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS, 1, &block)
+    }
+
+    fn started() -> Http2Session {
+        let mut session = Http2Session::new();
+        session.receive(PREFACE).unwrap();
+        session
+    }
+
+    #[test]
+    fn garbage_preface_is_rejected() {
+        let mut session = Http2Session::new();
+        assert!(matches!(
+            session.receive(b"GET / HTTP/1.1\r\nHost: example\r\n\r\n"),
+            Err(ContextError::NotHttp2)
+        ));
+    }
+
+    #[test]
+    fn partial_preface_waits() {
+        let mut session = Http2Session::new();
+        assert!(session.receive(&PREFACE[..10]).unwrap().is_empty());
+        assert!(session.receive(&PREFACE[10..]).is_ok());
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut session = started();
+        session.take_outbound();
+        assert!(matches!(
+            session.receive(&[0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 1]),
+            Err(ContextError::ConnectionError(FRAME_SIZE_ERROR, _))
+        ));
+        let outbound = session.take_outbound();
+        assert_eq!(outbound[3], FRAME_GOAWAY);
+        assert_eq!(&outbound[13..17], &FRAME_SIZE_ERROR.to_be_bytes());
+    }
+
+    #[test]
+    fn push_promise_from_client_is_rejected() {
+        let mut session = started();
+        let payload = [0, 0, 0, 2, 0x82, 0x86, 0x84];
+        assert!(session
+            .receive(&encode_frame(
+                FRAME_PUSH_PROMISE,
+                FLAG_END_HEADERS,
+                1,
+                &payload
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn data_on_idle_stream_is_rejected() {
+        let mut session = started();
+        session.take_outbound();
+        assert!(matches!(
+            session.receive(&encode_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"body")),
+            Err(ContextError::ConnectionError(PROTOCOL_ERROR, _))
+        ));
+        let outbound = session.take_outbound();
+        assert_eq!(outbound[3], FRAME_GOAWAY);
+        assert_eq!(&outbound[13..17], &PROTOCOL_ERROR.to_be_bytes());
+    }
+
+    #[test]
+    fn invalid_header_name_is_dropped() {
+        let mut session = started();
+        // Literal header field without indexing, new name.
+        let mut field = vec![0x00, 8];
+        field.extend_from_slice(b"bad name");
+        field.extend_from_slice(&[1, b'v']);
+        let events = session.receive(&get_request(&field)).unwrap();
+        let request = events.into_iter().find_map(|event| match event {
+            SessionEvent::Request { request, .. } => Some(request),
+            _ => None,
+        });
+        // Only the pseudo-headers of `get_request` are left.
+        let request = request.expect("no request");
+        let mut names: Vec<&str> = request.headers().keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["method", "path", "scheme"]);
+    }
+
+    #[test]
+    fn request_fed_byte_by_byte() {
+        let mut session = Http2Session::new();
+        let mut input = PREFACE.to_vec();
+        input.extend(encode_frame(FRAME_SETTINGS, 0, 0, &[]));
+        input.extend(get_request(&[]));
+        let mut events = Vec::new();
+        for byte in input {
+            events.extend(session.receive(&[byte]).unwrap());
+        }
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, SessionEvent::Request { .. })));
+    }
+}
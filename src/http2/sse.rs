@@ -0,0 +1,277 @@
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use http::{header, Request, Response, StatusCode};
+use mio::event::Source;
+
+use super::{BodyChunk, BodyStream, QueueReceiver, QueueSender, Queued, StreamingBody};
+
+/// One record of a `text/event-stream`.
+/// https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Self {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// Reconnection delay the browser should use from now on.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // A field value can't span lines, only `data` is split into several.
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        let mut result = String::new();
+        if let Some(event) = &self.event {
+            result.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            result.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry {
+            result.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n') {
+            result.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        result.push('\n');
+        result.into_bytes()
+    }
+}
+
+/// Handle emitting records on an event stream from outside the handler,
+/// e.g. from another thread.
+#[derive(Clone)]
+pub struct EventSender {
+    queue: QueueSender<()>,
+}
+
+impl EventSender {
+    pub fn send(&self, event: Event) -> io::Result<()> {
+        self.queue.send(event.encode())
+    }
+
+    /// Sends a comment line, ignored by browsers.
+    pub fn comment(&self, text: &str) -> io::Result<()> {
+        let mut record = String::new();
+        for line in text.split('\n') {
+            record.push_str(&format!(": {}\n", line.trim_end_matches('\r')));
+        }
+        record.push('\n');
+        self.queue.send(record.into_bytes())
+    }
+
+    /// Ends the response once everything sent so far was written.
+    pub fn close(&self) {
+        self.queue.end(());
+    }
+
+    /// The client went away or the stream was reset.
+    pub fn is_disconnected(&self) -> bool {
+        self.queue.is_disconnected()
+    }
+}
+
+/// A long-lived `text/event-stream` response body.
+///
+/// Records queued through its `EventSender` are written as DATA frames on the
+/// still open stream, and a comment is sent whenever nothing else was for
+/// `heartbeat` so proxies don't drop the idle connection. The stream ends
+/// once closed or once every sender was dropped.
+pub struct EventStream {
+    queue: QueueReceiver<()>,
+    heartbeat: Option<Duration>,
+    last_write: Instant,
+}
+
+impl EventStream {
+    pub fn new() -> io::Result<(EventSender, EventStream)> {
+        let (queue, receiver) = QueueReceiver::new()?;
+        let stream = EventStream {
+            queue: receiver,
+            heartbeat: Some(Duration::from_secs(15)),
+            last_write: Instant::now(),
+        };
+        Ok((EventSender { queue }, stream))
+    }
+
+    pub fn heartbeat(mut self, heartbeat: Option<Duration>) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn into_response(self) -> Response<Vec<u8>> {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Vec::new())
+            .unwrap();
+        response.extensions_mut().insert(StreamingBody::new(self));
+        response
+    }
+}
+
+impl BodyStream for EventStream {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        match self.queue.read(max_len)? {
+            Queued::Data(data) => {
+                self.last_write = Instant::now();
+                Ok(BodyChunk::Data(data))
+            }
+            Queued::End(_) => Ok(BodyChunk::End),
+            Queued::Pending => match self.heartbeat {
+                Some(heartbeat) if self.last_write.elapsed() >= heartbeat && max_len >= 3 => {
+                    self.last_write = Instant::now();
+                    Ok(BodyChunk::Data(b":\n\n".to_vec()))
+                }
+                _ => Ok(BodyChunk::Pending),
+            },
+        }
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        Some(self.queue.source())
+    }
+}
+
+/// The `last-event-id` a reconnecting browser sends, to resume from.
+pub fn last_event_id(request: &Request<Vec<u8>>) -> Option<&str> {
+    request
+        .headers()
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::MAX_QUEUED, *};
+
+    fn text(chunk: BodyChunk) -> String {
+        match chunk {
+            BodyChunk::Data(data) => String::from_utf8(data).unwrap(),
+            chunk => panic!("unexpected {:?}", chunk),
+        }
+    }
+
+    #[test]
+    fn events_are_encoded_as_fields() {
+        let event = Event::new("first\nsecond\r\nthird")
+            .event("update")
+            .id("42")
+            .retry(Duration::from_secs(3));
+        assert_eq!(
+            String::from_utf8(event.encode()).unwrap(),
+            "event: update\nid: 42\nretry: 3000\ndata: first\ndata: second\ndata: third\n\n"
+        );
+        assert_eq!(Event::new("").encode(), b"data: \n\n");
+    }
+
+    #[test]
+    fn line_breaks_are_stripped_from_single_line_fields() {
+        let event = Event::new("x").event("a\r\nb").id("1\n\rdata: injected");
+        assert_eq!(
+            String::from_utf8(event.encode()).unwrap(),
+            "event: ab\nid: 1data: injected\ndata: x\n\n"
+        );
+    }
+
+    #[test]
+    fn sent_events_are_streamed_until_closed() {
+        let (sender, stream) = EventStream::new().unwrap();
+        let mut stream = stream.heartbeat(None);
+        assert!(stream.source().is_some());
+        assert!(matches!(
+            stream.next_chunk(1024).unwrap(),
+            BodyChunk::Pending
+        ));
+
+        sender.send(Event::new("hello")).unwrap();
+        sender.comment("keep\nalive").unwrap();
+        assert_eq!(
+            text(stream.next_chunk(1024).unwrap()),
+            "data: hello\n\n: keep\n: alive\n\n"
+        );
+
+        sender.send(Event::new("bye")).unwrap();
+        sender.close();
+        let err = sender.send(Event::new("late")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        // What was sent before closing still goes out, in pieces if needed.
+        assert_eq!(text(stream.next_chunk(4).unwrap()), "data");
+        assert_eq!(text(stream.next_chunk(1024).unwrap()), ": bye\n\n");
+        assert!(matches!(stream.next_chunk(1024).unwrap(), BodyChunk::End));
+    }
+
+    #[test]
+    fn slow_clients_push_back_on_the_sender() {
+        let (sender, mut stream) = EventStream::new().unwrap();
+        let data = "x".repeat(MAX_QUEUED / 2);
+        sender.send(Event::new(&data)).unwrap();
+        let err = sender.send(Event::new(&data)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // Room is made as the stream is written.
+        while let BodyChunk::Data(_) = stream.next_chunk(16384).unwrap() {}
+        sender.send(Event::new(&data)).unwrap();
+    }
+
+    #[test]
+    fn dropped_streams_disconnect_the_sender() {
+        let (sender, stream) = EventStream::new().unwrap();
+        assert!(!sender.is_disconnected());
+        drop(stream);
+        assert!(sender.is_disconnected());
+        let err = sender.send(Event::new("gone")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn dropped_senders_end_the_stream() {
+        let (sender, mut stream) = EventStream::new().unwrap();
+        let other = sender.clone();
+        sender.send(Event::new("last")).unwrap();
+        drop(sender);
+        assert_eq!(text(stream.next_chunk(1024).unwrap()), "data: last\n\n");
+        assert!(matches!(
+            stream.next_chunk(1024).unwrap(),
+            BodyChunk::Pending
+        ));
+
+        drop(other);
+        assert!(matches!(stream.next_chunk(1024).unwrap(), BodyChunk::End));
+    }
+
+    #[test]
+    fn idle_streams_send_heartbeats() {
+        let (_sender, stream) = EventStream::new().unwrap();
+        let mut stream = stream.heartbeat(Some(Duration::ZERO));
+        assert_eq!(text(stream.next_chunk(1024).unwrap()), ":\n\n");
+        // A heartbeat doesn't fit, the stream waits for a larger window.
+        assert!(matches!(stream.next_chunk(2).unwrap(), BodyChunk::Pending));
+    }
+}
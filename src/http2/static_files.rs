@@ -0,0 +1,482 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use http::{header, Method, Request, Response, StatusCode};
+use mio::Token;
+
+use super::{BodyChunk, BodyStream, Handler, StreamingBody};
+
+/// Serves the files below `root` for request paths starting with `prefix`.
+///
+/// File contents are streamed as DATA frames through a `StreamingBody`, so large
+/// files are never loaded into memory at once.
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index_file: Option<String>,
+}
+
+impl StaticFiles {
+    pub fn new<P: Into<PathBuf>>(prefix: &str, root: P) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root: root.into(),
+            index_file: Some("index.html".to_string()),
+        }
+    }
+
+    pub fn index_file(mut self, index_file: Option<&str>) -> Self {
+        self.index_file = index_file.map(|name| name.to_string());
+        self
+    }
+
+    /// Maps a request path to a file below `root`, rejecting anything that
+    /// could escape it.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let relative = request_path.strip_prefix(self.prefix.as_str())?;
+        if !relative.is_empty() && !relative.starts_with('/') {
+            return None;
+        }
+        let relative = percent_decode(relative)?;
+        if relative.contains('\0') || relative.contains('\\') {
+            return None;
+        }
+
+        let mut path = self.root.clone();
+        for component in Path::new(relative.trim_start_matches('/')).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+
+        // Symlinks may still point outside of the served directory.
+        let root = fs::canonicalize(&self.root).ok()?;
+        let mut path = fs::canonicalize(path).ok()?;
+        if !path.starts_with(&root) {
+            return None;
+        }
+        if path.is_dir() {
+            path.push(self.index_file.as_ref()?);
+        }
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn serve(&self, request: &Request<Vec<u8>>) -> io::Result<Response<Vec<u8>>> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, "GET, HEAD")
+                .body(Vec::new())
+                .unwrap());
+        }
+
+        let path = match self.resolve(request.uri().path()) {
+            Some(path) => path,
+            None => return Ok(status_response(StatusCode::NOT_FOUND)),
+        };
+
+        let metadata = fs::metadata(&path)?;
+        let len = metadata.len();
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let modified_secs = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let etag = format!("\"{:x}-{:x}\"", len, modified_secs);
+        let last_modified = http_date(modified_secs);
+
+        if is_not_modified(request, &etag, modified_secs) {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .header(header::LAST_MODIFIED, last_modified)
+                .body(Vec::new())
+                .unwrap());
+        }
+
+        let mut builder = Response::builder()
+            .header(header::CONTENT_TYPE, content_type(&path))
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header(header::ETAG, etag.as_str())
+            .header(header::LAST_MODIFIED, last_modified.as_str());
+
+        let mut start = 0;
+        let mut end = len;
+        if let Some(range) = requested_range(request, &etag, modified_secs) {
+            match parse_range(&range, len) {
+                Some((first, last)) => {
+                    start = first;
+                    end = last + 1;
+                    builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", first, last, len),
+                    );
+                }
+                None => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                        .body(Vec::new())
+                        .unwrap());
+                }
+            }
+        }
+
+        let mut response = builder
+            .header(header::CONTENT_LENGTH, end - start)
+            .body(Vec::new())
+            .unwrap();
+        if request.method() == Method::GET && end > start {
+            let mut file = File::open(&path)?;
+            file.seek(SeekFrom::Start(start))?;
+            response.extensions_mut().insert(StreamingBody::new(FileBody {
+                file,
+                remaining: end - start,
+            }));
+        }
+        Ok(response)
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, _token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        match self.serve(&request) {
+            Ok(response) => response,
+            Err(e) => match e.kind() {
+                io::ErrorKind::NotFound => status_response(StatusCode::NOT_FOUND),
+                io::ErrorKind::PermissionDenied => status_response(StatusCode::FORBIDDEN),
+                _ => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+            },
+        }
+    }
+}
+
+struct FileBody {
+    file: File,
+    remaining: u64,
+}
+
+impl BodyStream for FileBody {
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        if self.remaining == 0 {
+            return Ok(BodyChunk::End);
+        }
+        let len = u64::min(self.remaining, max_len as u64) as usize;
+        let mut buffer = vec![0u8; len];
+        let read = self.file.read(&mut buffer)?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File Shrunk While Serving",
+            ));
+        }
+        buffer.truncate(read);
+        self.remaining -= read as u64;
+        Ok(BodyChunk::Data(buffer))
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .body(Vec::new())
+        .unwrap()
+}
+
+fn header_str(request: &Request<Vec<u8>>, name: header::HeaderName) -> Option<&str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn is_not_modified(request: &Request<Vec<u8>>, etag: &str, modified_secs: u64) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since.
+    // https://datatracker.ietf.org/doc/html/rfc9110#name-if-none-match
+    if let Some(if_none_match) = header_str(request, header::IF_NONE_MATCH) {
+        return if_none_match.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        });
+    }
+    match header_str(request, header::IF_MODIFIED_SINCE).and_then(parse_http_date) {
+        Some(since) => modified_secs <= since,
+        None => false,
+    }
+}
+
+/// Returns the `Range` header unless `If-Range` says the client's copy is stale.
+fn requested_range(request: &Request<Vec<u8>>, etag: &str, modified_secs: u64) -> Option<String> {
+    // Multiple ranges are not supported and are answered with the whole file.
+    let range = header_str(request, header::RANGE)?.trim();
+    if !range.starts_with("bytes=") || range.contains(',') {
+        return None;
+    }
+    if let Some(if_range) = header_str(request, header::IF_RANGE) {
+        let if_range = if_range.trim();
+        let fresh = if if_range.starts_with('"') {
+            if_range == etag
+        } else {
+            parse_http_date(if_range) == Some(modified_secs)
+        };
+        if !fresh {
+            return None;
+        }
+    }
+    Some(range.to_string())
+}
+
+/// Parses a single `bytes=` range into inclusive offsets, `None` when it
+/// can't be satisfied.
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = range.strip_prefix("bytes=")?.trim();
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = if first.is_empty() {
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 {
+            return None;
+        }
+        (len.saturating_sub(suffix), len.checked_sub(1)?)
+    } else {
+        let first: u64 = first.parse().ok()?;
+        let last = if last.is_empty() {
+            len.checked_sub(1)?
+        } else {
+            u64::min(last.parse().ok()?, len.checked_sub(1)?)
+        };
+        (first, last)
+    };
+    if first > last || first >= len {
+        return None;
+    }
+    Some((first, last))
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.split('?').next()?.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            result.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats seconds since the epoch as an IMF-fixdate, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(secs: u64) -> String {
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let rem = secs % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Parses an IMF-fixdate back to seconds since the epoch.
+fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_whitespace().skip(1);
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':');
+    let hour: u64 = time.next()?.parse().ok()?;
+    let minute: u64 = time.next()?.parse().ok()?;
+    let second: u64 = time.next()?.parse().ok()?;
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    Some(days as u64 * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Vec<u8>> {
+        let mut builder = Request::builder().uri("/static/file.txt");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn paths_stay_below_the_root() {
+        let dir = std::env::temp_dir().join(format!("khttp-static-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("file.txt"), "file").unwrap();
+        fs::write(root.join("docs/index.html"), "index").unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("link")).unwrap();
+        let files = StaticFiles::new("/static/", &root);
+
+        let resolved = files.resolve("/static/file.txt").unwrap();
+        assert!(resolved.ends_with("root/file.txt"));
+        assert!(files
+            .resolve("/static/docs")
+            .unwrap()
+            .ends_with("docs/index.html"));
+        assert!(files.resolve("/static/./file.txt?query").is_some());
+        assert_eq!(files.resolve("/static/../secret"), None);
+        assert_eq!(files.resolve("/static/%2e%2e/secret"), None);
+        assert_eq!(files.resolve("/static/docs/..%2f..%2fsecret"), None);
+        assert_eq!(files.resolve("/static/..%5csecret"), None);
+        assert_eq!(files.resolve("/static/file.txt%00"), None);
+        assert_eq!(files.resolve("/staticfile.txt"), None);
+        // A symlink to a file outside of the root.
+        assert_eq!(files.resolve("/static/link"), None);
+        assert_eq!(files.index_file(None).resolve("/static/docs"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_file() {
+        assert_eq!(parse_range("bytes=0-4", 10), Some((0, 4)));
+        assert_eq!(parse_range("bytes=5-", 10), Some((5, 9)));
+        assert_eq!(parse_range("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(parse_range("bytes=-30", 10), Some((0, 9)));
+        assert_eq!(parse_range("bytes=8-20", 10), Some((8, 9)));
+        assert_eq!(parse_range("bytes=-0", 10), None);
+        assert_eq!(parse_range("bytes=10-", 10), None);
+        assert_eq!(parse_range("bytes=4-2", 10), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=a-b", 10), None);
+    }
+
+    #[test]
+    fn if_range_falls_back_to_the_whole_file() {
+        let etag = "\"a-1\"";
+        let date = http_date(1);
+        let range = |headers: &[(&str, &str)]| requested_range(&request(headers), etag, 1);
+        assert_eq!(
+            range(&[("range", "bytes=0-1")]).as_deref(),
+            Some("bytes=0-1")
+        );
+        assert_eq!(range(&[("range", "bytes=0-1,4-5")]), None);
+        assert_eq!(range(&[("range", "items=0-1")]), None);
+        assert!(range(&[("range", "bytes=0-1"), ("if-range", etag)]).is_some());
+        assert!(range(&[("range", "bytes=0-1"), ("if-range", "\"b-1\"")]).is_none());
+        assert!(range(&[("range", "bytes=0-1"), ("if-range", &date)]).is_some());
+        assert!(range(&[("range", "bytes=0-1"), ("if-range", &http_date(2))]).is_none());
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let etag = "\"a-64\"";
+        let not_modified = |headers: &[(&str, &str)]| is_not_modified(&request(headers), etag, 100);
+        assert!(not_modified(&[("if-none-match", etag)]));
+        assert!(not_modified(&[("if-none-match", "\"x\", W/\"a-64\"")]));
+        assert!(not_modified(&[("if-none-match", "*")]));
+        assert!(!not_modified(&[("if-none-match", "\"x\"")]));
+        assert!(not_modified(&[("if-modified-since", &http_date(100))]));
+        assert!(not_modified(&[("if-modified-since", &http_date(200))]));
+        assert!(!not_modified(&[("if-modified-since", &http_date(99))]));
+        assert!(!not_modified(&[("if-modified-since", "yesterday")]));
+        // If-None-Match wins over If-Modified-Since.
+        assert!(!not_modified(&[
+            ("if-none-match", "\"x\""),
+            ("if-modified-since", &http_date(200)),
+        ]));
+        assert!(!not_modified(&[]));
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        for secs in [0, 784111777, 951782400, 4102444799] {
+            assert_eq!(parse_http_date(&http_date(secs)), Some(secs));
+        }
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994"), None);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+}
@@ -1,5 +1,4 @@
 use std::fmt::Display;
-use std::io::Write;
 use std::os::{fd::RawFd, unix::net::SocketAddr};
 use std::time;
 
@@ -10,6 +9,8 @@ use kparser::{
 };
 use mio::net::{TcpStream, UnixStream};
 
+use super::Trailers;
+
 #[derive(Debug)]
 pub enum StreamState {
     None,
@@ -26,8 +27,12 @@ pub struct Http2Stream {
     stream_id: u31,
     max_window_frame_size: u128,
     data: Option<Vec<u8>>,
+    body_len: usize,
     headers: Option<Vec<(Vec<u8>, Vec<u8>)>>,
     headers_len: u32,
+    headers_received: bool,
+    end_stream_received: bool,
+    trailers: Option<Vec<(Vec<u8>, Vec<u8>)>>,
     pub ping_opaque: u64,
 }
 
@@ -39,7 +44,11 @@ impl Http2Stream {
             max_window_frame_size: 4096,
             headers: None,
             data: None,
+            body_len: 0,
             headers_len: 0,
+            headers_received: false,
+            end_stream_received: false,
+            trailers: None,
             ping_opaque: 0,
         }
     }
@@ -57,15 +66,17 @@ impl Http2Stream {
     }
 
     pub fn write_data(&mut self, data: &mut DataPayload) {
-        if self.data.is_none() {
-            self.data = Some(Vec::new());
-        }
+        self.data.get_or_insert_with(Vec::new).extend_from_slice(&data.data);
+        self.body_len += data.data.len();
+    }
 
-        self.data.as_mut().unwrap().write(&data.data);
+    /// Bytes of body received so far, including those already handed out.
+    pub fn body_len(&self) -> usize {
+        self.body_len
     }
 
     pub fn read_data(&self) -> Option<Vec<u8>> {
-        Some(self.data.as_ref().unwrap().clone())
+        self.data.clone()
     }
 
     pub fn get_headers_len(&self) -> u32 {
@@ -73,11 +84,61 @@ impl Http2Stream {
     }
 
     pub fn add_headers(&mut self, headers: Vec<(Vec<u8>, Vec<u8>)>, size: u32) {
-        if self.headers.is_none() {
-            self.headers = Some(Vec::new());
-        }
-        self.headers.as_mut().unwrap().extend(headers);
-        self.headers_len += size;
+        self.headers.get_or_insert_with(Vec::new).extend(headers);
+        self.headers_len = self.headers_len.saturating_add(size);
+    }
+
+    /// Adds to the trailing header block, a HEADERS frame received after the
+    /// request headers were complete.
+    pub fn add_trailers(&mut self, trailers: Vec<(Vec<u8>, Vec<u8>)>, size: u32) {
+        self.trailers.get_or_insert_with(Vec::new).extend(trailers);
+        self.headers_len = self.headers_len.saturating_add(size);
+    }
+
+    pub fn get_trailers(&self) -> Option<&Vec<(Vec<u8>, Vec<u8>)>> {
+        self.trailers.as_ref()
+    }
+
+    pub fn is_receiving_trailers(&self) -> bool {
+        self.trailers.is_some()
+    }
+
+    pub fn headers_received(&self) -> bool {
+        self.headers_received
+    }
+
+    pub fn set_headers_received(&mut self) {
+        self.headers_received = true;
+    }
+
+    pub fn get_header(&self, name: &[u8]) -> Option<&Vec<u8>> {
+        self.headers
+            .as_ref()?
+            .iter()
+            .find(|(key, _)| key.as_slice() == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Every value of the `name` field, in the order received.
+    pub fn get_header_values<'a>(&'a self, name: &'a [u8]) -> impl Iterator<Item = &'a Vec<u8>> {
+        self.headers
+            .iter()
+            .flatten()
+            .filter(move |(key, _)| key.as_slice() == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.get_header(b":method")
+            .map_or(false, |method| method.as_slice() == b"CONNECT")
+    }
+
+    pub fn end_stream_received(&self) -> bool {
+        self.end_stream_received
+    }
+
+    pub fn set_end_stream_received(&mut self) {
+        self.end_stream_received = true;
     }
 
     pub fn clone(&self) -> Self {
@@ -88,11 +149,15 @@ impl Http2Stream {
                     stream_id: self.stream_id,
                     max_window_frame_size: self.max_window_frame_size,
                     data: Some(data.clone()),
+                    body_len: self.body_len,
                     headers: match &self.headers {
                         Some(headers) => Some(headers.clone()),
                         None => None,
                     },
                     headers_len: self.headers_len,
+                    headers_received: self.headers_received,
+                    end_stream_received: self.end_stream_received,
+                    trailers: self.trailers.clone(),
                     ping_opaque: self.ping_opaque,
                 };
                 result
@@ -103,11 +168,15 @@ impl Http2Stream {
                     stream_id: self.stream_id,
                     max_window_frame_size: self.max_window_frame_size,
                     data: None,
+                    body_len: self.body_len,
                     headers: match &self.headers {
                         Some(headers) => Some(headers.clone()),
                         None => None,
                     },
                     headers_len: self.headers_len,
+                    headers_received: self.headers_received,
+                    end_stream_received: self.end_stream_received,
+                    trailers: self.trailers.clone(),
                     ping_opaque: self.ping_opaque,
                 };
                 result
@@ -123,11 +192,15 @@ impl Http2Stream {
                     stream_id: self.stream_id,
                     max_window_frame_size: self.max_window_frame_size,
                     data: Some(data.clone()),
+                    body_len: self.body_len,
                     headers: match &self.headers {
                         Some(headers) => Some(headers.clone()),
                         None => None,
                     },
                     headers_len: self.headers_len,
+                    headers_received: self.headers_received,
+                    end_stream_received: self.end_stream_received,
+                    trailers: self.trailers.clone(),
                     ping_opaque: self.ping_opaque,
                 };
                 self.data.as_mut().unwrap().clear();
@@ -139,11 +212,15 @@ impl Http2Stream {
                     stream_id: self.stream_id,
                     max_window_frame_size: self.max_window_frame_size,
                     data: None,
+                    body_len: self.body_len,
                     headers: match &self.headers {
                         Some(headers) => Some(headers.clone()),
                         None => None,
                     },
                     headers_len: self.headers_len,
+                    headers_received: self.headers_received,
+                    end_stream_received: self.end_stream_received,
+                    trailers: self.trailers.clone(),
                     ping_opaque: self.ping_opaque,
                 };
                 result
@@ -187,74 +264,72 @@ impl Display for Http2Stream {
     }
 }
 
+/// Request extension holding the address of the client the request came from.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub std::net::SocketAddr);
+
+/// Request extension holding the id of the HTTP/2 stream the request came on.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamId(pub u32);
+
 impl Into<http::Request<Vec<u8>>> for Http2Stream {
     fn into(self) -> http::Request<Vec<u8>> {
-        let mut builder = http::Request::builder();
-        if self.headers.is_some() {
-            for (key, value) in self.headers.unwrap() {
-                match key.as_slice() {
-                    [0x3A, 0x6D, 0x65, 0x74, 0x68, 0x6F, 0x64] => { // :method
-                        builder = builder.header("method", value);
-                    }
-                    [0x3A, 0x70, 0x61, 0x74, 0x68] => { // :path
-                        builder = builder.header("path", value);
-                    }
-                    [0x3A, 0x73, 0x63, 0x68, 0x65, 0x6D, 0x65] => { // :scheme
-                        builder = builder.header("scheme", value);
-                    }
-                    [0x3A, 0x61, 0x75, 0x74, 0x68, 0x6F, 0x72, 0x69, 0x74, 0x79] => { // :authority
-                        builder = builder.header("authority", value);
-                    }
-                    [0x3a, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73] => { // :status
-                        builder = builder.header("status", value);
+        let mut request = http::Request::new(self.data.unwrap_or_default());
+        for (key, value) in self.headers.unwrap_or_default() {
+            let name: &[u8] = match key.as_slice() {
+                [0x3A, 0x6D, 0x65, 0x74, 0x68, 0x6F, 0x64] => { // :method
+                    if let Ok(method) = http::Method::from_bytes(&value) {
+                        *request.method_mut() = method;
                     }
-                    _ => {
-                        builder = builder.header(key, value);
+                    b"method"
+                }
+                [0x3A, 0x70, 0x61, 0x74, 0x68] => { // :path
+                    if let Ok(uri) = http::Uri::try_from(value.as_slice()) {
+                        *request.uri_mut() = uri;
                     }
+                    b"path"
                 }
-            }
+                [0x3A, 0x73, 0x63, 0x68, 0x65, 0x6D, 0x65] => b"scheme", // :scheme
+                [0x3A, 0x61, 0x75, 0x74, 0x68, 0x6F, 0x72, 0x69, 0x74, 0x79] => b"authority", // :authority
+                [0x3a, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73] => b"status", // :status
+                [0x3A, 0x70, 0x72, 0x6F, 0x74, 0x6F, 0x63, 0x6F, 0x6C] => b"protocol", // :protocol
+                _ => &key,
+            };
+            append_header(request.headers_mut(), name, &value);
         }
 
-        if self.data.is_some() {
-            return builder.body(self.data.unwrap()).unwrap();
+        if let Some(trailers) = self.trailers {
+            request.extensions_mut().insert(Trailers::from_list(&trailers));
         }
-
-        return builder.body(vec![0u8; 0]).unwrap();
+        request
     }
 }
 
 impl Into<http::Response<Vec<u8>>> for Http2Stream {
     fn into(self) -> http::Response<Vec<u8>> {
-        let mut builder = http::Response::builder();
-        if self.headers.is_some() {
-            for (key, value) in self.headers.unwrap() {
-                match key.as_slice() {
-                    [0x3A, 0x6D, 0x65, 0x74, 0x68, 0x6F, 0x64] => { // :method
-                        builder = builder.header("method", value);
-                    }
-                    [0x3A, 0x70, 0x61, 0x74, 0x68] => { // :path
-                        builder = builder.header("path", value);
-                    }
-                    [0x3A, 0x73, 0x63, 0x68, 0x65, 0x6D, 0x65] => { // :scheme
-                        builder = builder.header("scheme", value);
-                    }
-                    [0x3A, 0x61, 0x75, 0x74, 0x68, 0x6F, 0x72, 0x69, 0x74, 0x79] => { // :authority
-                        builder = builder.header("authority", value);
-                    }
-                    [0x3a, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73] => { // :status
-                        builder = builder.header("status", value);
-                    }
-                    _ => {
-                        builder = builder.header(key, value);
-                    }
-                }
-            }
-        }
-
-        if self.data.is_some() {
-            return builder.body(self.data.unwrap()).unwrap();
+        let mut response = http::Response::new(self.data.unwrap_or_default());
+        for (key, value) in self.headers.unwrap_or_default() {
+            let name: &[u8] = match key.as_slice() {
+                [0x3A, 0x6D, 0x65, 0x74, 0x68, 0x6F, 0x64] => b"method", // :method
+                [0x3A, 0x70, 0x61, 0x74, 0x68] => b"path", // :path
+                [0x3A, 0x73, 0x63, 0x68, 0x65, 0x6D, 0x65] => b"scheme", // :scheme
+                [0x3A, 0x61, 0x75, 0x74, 0x68, 0x6F, 0x72, 0x69, 0x74, 0x79] => b"authority", // :authority
+                [0x3a, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73] => b"status", // :status
+                _ => &key,
+            };
+            append_header(response.headers_mut(), name, &value);
         }
+        response
+    }
+}
 
-        return builder.body(vec![0u8; 0]).unwrap();
+/// Peers can send any bytes as a field, those that aren't valid HTTP are
+/// dropped instead of failing the whole message.
+fn append_header(headers: &mut http::HeaderMap, name: &[u8], value: &[u8]) {
+    if let (Ok(name), Ok(value)) = (
+        http::HeaderName::from_bytes(name),
+        http::HeaderValue::from_bytes(value),
+    ) {
+        headers.append(name, value);
     }
 }
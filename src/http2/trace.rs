@@ -0,0 +1,580 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{
+    connection::ConnectionState,
+    frames::{self, encode_frame, PREFACE},
+};
+
+// Frame tracing, for debugging a connection frame by frame: a callback sees
+// every frame read from or queued to the peer, `VerboseTracer` prints them the
+// way `nghttp -v` does and `CaptureWriter` records them for later analysis.
+
+const INDENT: &str = "          ";
+
+const CAPTURE_MAGIC: &[u8; 8] = b"KHTTPCAP";
+const CAPTURE_VERSION: u16 = 1;
+// Elapsed micros, connection id, direction.
+const RECORD_HEADER_LEN: usize = 8 + 8 + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the peer.
+    Inbound,
+    /// Queued to the peer.
+    Outbound,
+}
+
+impl Direction {
+    /// `recv` or `send`, as in nghttp traces.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "recv",
+            Direction::Outbound => "send",
+        }
+    }
+}
+
+/// A frame as seen on the wire.
+#[derive(Debug, Clone, Copy)]
+pub struct TracedFrame<'a> {
+    pub direction: Direction,
+    pub frame_type: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+    /// The payload length announced in the frame header.
+    pub length: usize,
+    pub payload: &'a [u8],
+    /// The decoded fields of a HEADERS or CONTINUATION frame. Outbound header
+    /// blocks split over several frames carry them on the HEADERS frame.
+    pub headers: Option<&'a [(Vec<u8>, Vec<u8>)]>,
+}
+
+impl<'a> TracedFrame<'a> {
+    /// Reads the complete frame at the start of `raw`.
+    pub fn parse(
+        direction: Direction,
+        raw: &'a [u8],
+        headers: Option<&'a [(Vec<u8>, Vec<u8>)]>,
+    ) -> Option<Self> {
+        let frame_len = frames::frame_len(raw)?;
+        let payload = raw.get(9..frame_len)?;
+        Some(Self {
+            direction,
+            frame_type: raw[3],
+            flags: raw[4],
+            stream_id: u32::from_be_bytes([raw[5], raw[6], raw[7], raw[8]]) & 0x7fff_ffff,
+            length: payload.len(),
+            payload,
+            headers,
+        })
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+
+    /// The frame serialized back, header included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_frame(self.frame_type, self.flags, self.stream_id, self.payload)
+    }
+}
+
+/// Receives every frame of the connections it is set on.
+pub trait FrameTracer: Send + Sync {
+    /// Called once a connection is accepted, returning false leaves it
+    /// untraced.
+    fn enabled(&self, _connection: &ConnectionState) -> bool {
+        true
+    }
+
+    fn on_frame(&self, connection: &ConnectionState, frame: &TracedFrame);
+}
+
+impl<F: Fn(&ConnectionState, &TracedFrame) + Send + Sync> FrameTracer for F {
+    fn on_frame(&self, connection: &ConnectionState, frame: &TracedFrame) {
+        self(connection, frame)
+    }
+}
+
+/// Prints frames like `nghttp -v`.
+pub struct VerboseTracer<W: Write + Send> {
+    out: Mutex<W>,
+    started: Instant,
+}
+
+impl<W: Write + Send> VerboseTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl VerboseTracer<io::Stderr> {
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+}
+
+impl<W: Write + Send> FrameTracer for VerboseTracer<W> {
+    fn on_frame(&self, connection: &ConnectionState, frame: &TracedFrame) {
+        let trace = format_frame(frame, self.started.elapsed());
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = write!(out, "[id={}] {}", connection.id(), trace);
+    }
+}
+
+/// Formats `frame` as `nghttp -v` does, `elapsed` being the time since the
+/// trace started. Every line ends with a newline.
+pub fn format_frame(frame: &TracedFrame, elapsed: Duration) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "[{:>7.3}] {} {} frame <length={}, flags=0x{:02x}, stream_id={}>",
+        elapsed.as_secs_f64(),
+        frame.direction.as_str(),
+        frame_type_name(frame.frame_type),
+        frame.length,
+        frame.flags,
+        frame.stream_id
+    );
+    let flags = flag_names(frame.frame_type, frame.flags);
+    if !flags.is_empty() {
+        let _ = writeln!(out, "{}; {}", INDENT, flags.join(" | "));
+    }
+    let payload = frame.payload;
+    match frame.frame_type {
+        frames::FRAME_DATA | frames::FRAME_HEADERS | frames::FRAME_PUSH_PROMISE => {
+            let mut details = Vec::new();
+            let mut offset = 0;
+            if frame.has_flag(frames::FLAG_PADDED) {
+                if let Some(padlen) = payload.first() {
+                    details.push(format!("padlen={}", padlen));
+                    offset = 1;
+                }
+            }
+            if frame.frame_type == frames::FRAME_HEADERS && frame.has_flag(frames::FLAG_PRIORITY) {
+                if let Some(priority) = payload.get(offset..offset + 5) {
+                    details.push(priority_details(priority));
+                }
+            }
+            if frame.frame_type == frames::FRAME_PUSH_PROMISE {
+                if let Some(promised) = read_u32(payload, offset) {
+                    details.push(format!("promised_stream_id={}", promised & 0x7fff_ffff));
+                }
+            }
+            if !details.is_empty() {
+                let _ = writeln!(out, "{}({})", INDENT, details.join(", "));
+            }
+        }
+        frames::FRAME_PRIORITY => {
+            if let Some(priority) = payload.get(0..5) {
+                let _ = writeln!(out, "{}({})", INDENT, priority_details(priority));
+            }
+        }
+        frames::FRAME_RST_STREAM => {
+            if let Some(code) = read_u32(payload, 0) {
+                let _ = writeln!(out, "{}(error_code={})", INDENT, error_code_name(code));
+            }
+        }
+        frames::FRAME_SETTINGS => {
+            let settings = payload.chunks_exact(6);
+            let _ = writeln!(out, "{}(niv={})", INDENT, settings.len());
+            for setting in settings {
+                let id = u16::from_be_bytes([setting[0], setting[1]]);
+                let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                let _ = writeln!(out, "{}[{}:{}]", INDENT, setting_name(id), value);
+            }
+        }
+        frames::FRAME_PING => {
+            let _ = writeln!(out, "{}(opaque_data={})", INDENT, hex(payload));
+        }
+        frames::FRAME_GOAWAY => {
+            if let (Some(last_stream_id), Some(code)) = (read_u32(payload, 0), read_u32(payload, 4))
+            {
+                let opaque = &payload[8..];
+                let _ = writeln!(
+                    out,
+                    "{}(last_stream_id={}, error_code={}, opaque_data({})=[{}])",
+                    INDENT,
+                    last_stream_id & 0x7fff_ffff,
+                    error_code_name(code),
+                    opaque.len(),
+                    String::from_utf8_lossy(opaque)
+                );
+            }
+        }
+        frames::FRAME_WINDOW_UPDATE => {
+            if let Some(increment) = read_u32(payload, 0) {
+                let _ = writeln!(
+                    out,
+                    "{}(window_size_increment={})",
+                    INDENT,
+                    increment & 0x7fff_ffff
+                );
+            }
+        }
+        _ => {}
+    }
+    for (name, value) in frame.headers.unwrap_or_default() {
+        let _ = writeln!(
+            out,
+            "{}{}: {}",
+            INDENT,
+            String::from_utf8_lossy(name),
+            String::from_utf8_lossy(value)
+        );
+    }
+    out
+}
+
+fn read_u32(payload: &[u8], offset: usize) -> Option<u32> {
+    let bytes = payload.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn priority_details(priority: &[u8]) -> String {
+    let dependency = u32::from_be_bytes([priority[0], priority[1], priority[2], priority[3]]);
+    format!(
+        "dep_stream_id={}, weight={}, exclusive={}",
+        dependency & 0x7fff_ffff,
+        priority[4] as u16 + 1,
+        dependency >> 31
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
+
+pub fn frame_type_name(frame_type: u8) -> String {
+    match frames::frame_name(frame_type) {
+        Some(name) => name.to_string(),
+        None => format!("UNKNOWN(0x{:02x})", frame_type),
+    }
+}
+
+fn flag_names(frame_type: u8, flags: u8) -> Vec<&'static str> {
+    let known: &[(u8, &str)] = match frame_type {
+        frames::FRAME_DATA => &[
+            (frames::FLAG_END_STREAM, "END_STREAM"),
+            (frames::FLAG_PADDED, "PADDED"),
+        ],
+        frames::FRAME_HEADERS => &[
+            (frames::FLAG_END_STREAM, "END_STREAM"),
+            (frames::FLAG_END_HEADERS, "END_HEADERS"),
+            (frames::FLAG_PADDED, "PADDED"),
+            (frames::FLAG_PRIORITY, "PRIORITY"),
+        ],
+        frames::FRAME_SETTINGS | frames::FRAME_PING => &[(frames::FLAG_ACK, "ACK")],
+        frames::FRAME_PUSH_PROMISE => &[
+            (frames::FLAG_END_HEADERS, "END_HEADERS"),
+            (frames::FLAG_PADDED, "PADDED"),
+        ],
+        frames::FRAME_CONTINUATION => &[(frames::FLAG_END_HEADERS, "END_HEADERS")],
+        _ => &[],
+    };
+    known
+        .iter()
+        .filter(|(flag, _)| flags & flag == *flag)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// The RFC 9113 name of `code` followed by its value, `PROTOCOL_ERROR(0x01)`.
+pub fn error_code_name(code: u32) -> String {
+    let name = frames::error_name(code).unwrap_or("UNKNOWN");
+    format!("{}(0x{:02x})", name, code)
+}
+
+fn setting_name(id: u16) -> String {
+    let name = match id {
+        0x1 => "SETTINGS_HEADER_TABLE_SIZE",
+        0x2 => "SETTINGS_ENABLE_PUSH",
+        0x3 => "SETTINGS_MAX_CONCURRENT_STREAMS",
+        0x4 => "SETTINGS_INITIAL_WINDOW_SIZE",
+        0x5 => "SETTINGS_MAX_FRAME_SIZE",
+        0x6 => "SETTINGS_MAX_HEADER_LIST_SIZE",
+        frames::SETTINGS_ENABLE_CONNECT_PROTOCOL => "SETTINGS_ENABLE_CONNECT_PROTOCOL",
+        _ => "UNKNOWN",
+    };
+    format!("{}(0x{:02x})", name, id)
+}
+
+/// Records the raw frames of every traced connection to a capture file, read
+/// back with `read_capture` and replayed with `replay_input`. Frames are
+/// traced above the `Transport`, as the connection reads and writes them.
+///
+/// The file starts with `KHTTPCAP` and a big-endian u16 version, followed by
+/// one record per frame: the micros since the capture started and the
+/// connection id as big-endian u64, 0 for inbound or 1 for outbound, then the
+/// frame, header included.
+pub struct CaptureWriter<W: Write + Send> {
+    out: Mutex<W>,
+    started: Instant,
+}
+
+impl CaptureWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Send> CaptureWriter<W> {
+    /// Writes the file header to `out`.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(CAPTURE_MAGIC)?;
+        out.write_all(&CAPTURE_VERSION.to_be_bytes())?;
+        out.flush()?;
+        Ok(Self {
+            out: Mutex::new(out),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write + Send> FrameTracer for CaptureWriter<W> {
+    fn on_frame(&self, connection: &ConnectionState, frame: &TracedFrame) {
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + 9 + frame.length);
+        record.extend_from_slice(&(self.started.elapsed().as_micros() as u64).to_be_bytes());
+        record.extend_from_slice(&connection.id().to_be_bytes());
+        record.push(match frame.direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        });
+        record.extend(frame.to_bytes());
+        // Flushed per record so the capture survives the process.
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = out.write_all(&record).and_then(|_| out.flush());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub elapsed: Duration,
+    pub connection: u64,
+    pub direction: Direction,
+    /// The raw frame, header included.
+    pub frame: Vec<u8>,
+}
+
+impl CaptureRecord {
+    pub fn traced_frame(&self) -> Option<TracedFrame<'_>> {
+        TracedFrame::parse(self.direction, &self.frame, None)
+    }
+}
+
+/// Reads a file written by `CaptureWriter`. A record cut short, by a process
+/// killed mid-write, is an `UnexpectedEof` error.
+pub fn read_capture<R: Read>(mut input: R) -> io::Result<Vec<CaptureRecord>> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    if bytes.len() < CAPTURE_MAGIC.len() + 2 || &bytes[..CAPTURE_MAGIC.len()] != CAPTURE_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a capture file",
+        ));
+    }
+    let version = u16::from_be_bytes([bytes[8], bytes[9]]);
+    if version != CAPTURE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported capture version {}", version),
+        ));
+    }
+    let mut records = Vec::new();
+    let mut rest = &bytes[CAPTURE_MAGIC.len() + 2..];
+    while !rest.is_empty() {
+        let frame_len = rest
+            .get(RECORD_HEADER_LEN..)
+            .and_then(frames::frame_len)
+            .filter(|len| RECORD_HEADER_LEN + len <= rest.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let direction = match rest[16] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid direction {}", other),
+                ))
+            }
+        };
+        records.push(CaptureRecord {
+            elapsed: Duration::from_micros(u64::from_be_bytes(rest[0..8].try_into().unwrap())),
+            connection: u64::from_be_bytes(rest[8..16].try_into().unwrap()),
+            direction,
+            frame: rest[RECORD_HEADER_LEN..RECORD_HEADER_LEN + frame_len].to_vec(),
+        });
+        rest = &rest[RECORD_HEADER_LEN + frame_len..];
+    }
+    Ok(records)
+}
+
+/// The ids of the connections in `records`, in the order they were opened.
+pub fn connections(records: &[CaptureRecord]) -> Vec<u64> {
+    let mut connections = Vec::new();
+    for record in records {
+        if !connections.contains(&record.connection) {
+            connections.push(record.connection);
+        }
+    }
+    connections
+}
+
+/// What the peer of `connection` sent, preface included, to be written to a
+/// server to replay the connection.
+pub fn replay_input(records: &[CaptureRecord], connection: u64) -> Vec<u8> {
+    let mut input = PREFACE.to_vec();
+    for record in records {
+        if record.connection == connection && record.direction == Direction::Inbound {
+            input.extend_from_slice(&record.frame);
+        }
+    }
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traced(direction: Direction, raw: &[u8]) -> TracedFrame<'_> {
+        TracedFrame::parse(direction, raw, None).unwrap()
+    }
+
+    #[test]
+    fn settings_are_listed() {
+        let raw = encode_frame(
+            frames::FRAME_SETTINGS,
+            0,
+            0,
+            &frames::settings_payload(&[(0x3, 100), (0x4, 65535)]),
+        );
+        assert_eq!(
+            format_frame(&traced(Direction::Inbound, &raw), Duration::from_millis(3)),
+            "[  0.003] recv SETTINGS frame <length=12, flags=0x00, stream_id=0>\n\
+             \x20         (niv=2)\n\
+             \x20         [SETTINGS_MAX_CONCURRENT_STREAMS(0x03):100]\n\
+             \x20         [SETTINGS_INITIAL_WINDOW_SIZE(0x04):65535]\n"
+        );
+    }
+
+    #[test]
+    fn headers_are_printed_with_their_flags() {
+        let raw = encode_frame(
+            frames::FRAME_HEADERS,
+            frames::FLAG_END_STREAM | frames::FLAG_END_HEADERS,
+            1,
+            &[0x88],
+        );
+        let headers = vec![(b":status".to_vec(), b"200".to_vec())];
+        let frame = TracedFrame::parse(Direction::Outbound, &raw, Some(&headers)).unwrap();
+        assert_eq!(
+            format_frame(&frame, Duration::from_millis(1250)),
+            "[  1.250] send HEADERS frame <length=1, flags=0x05, stream_id=1>\n\
+             \x20         ; END_STREAM | END_HEADERS\n\
+             \x20         :status: 200\n"
+        );
+    }
+
+    #[test]
+    fn goaway_and_rst_stream_name_their_error_code() {
+        let raw = encode_frame(
+            frames::FRAME_GOAWAY,
+            0,
+            0,
+            &frames::goaway_payload(3, frames::PROTOCOL_ERROR, b"bad"),
+        );
+        assert!(
+            format_frame(&traced(Direction::Outbound, &raw), Duration::ZERO).ends_with(
+                "(last_stream_id=3, error_code=PROTOCOL_ERROR(0x01), opaque_data(3)=[bad])\n"
+            )
+        );
+        let raw = encode_frame(
+            frames::FRAME_RST_STREAM,
+            0,
+            5,
+            &frames::rst_stream_payload(frames::CANCEL),
+        );
+        assert!(
+            format_frame(&traced(Direction::Inbound, &raw), Duration::ZERO)
+                .ends_with("(error_code=CANCEL(0x08))\n")
+        );
+    }
+
+    #[test]
+    fn truncated_frames_are_not_traced() {
+        let raw = encode_frame(frames::FRAME_PING, 0, 0, b"12345678");
+        assert!(TracedFrame::parse(Direction::Inbound, &raw[..12], None).is_none());
+        assert!(TracedFrame::parse(Direction::Inbound, &raw[..5], None).is_none());
+    }
+
+    #[test]
+    fn capture_round_trip() {
+        let capture = CaptureWriter::new(Vec::new()).unwrap();
+        let first = ConnectionState::new(1, None);
+        let second = ConnectionState::new(2, None);
+        let ping = encode_frame(frames::FRAME_PING, 0, 0, b"12345678");
+        let ack = encode_frame(frames::FRAME_PING, frames::FLAG_ACK, 0, b"12345678");
+        capture.on_frame(&first, &traced(Direction::Inbound, &ping));
+        capture.on_frame(&second, &traced(Direction::Outbound, &ack));
+
+        let bytes = capture.into_inner();
+        let records = read_capture(bytes.as_slice()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].connection, 1);
+        assert_eq!(records[0].direction, Direction::Inbound);
+        assert_eq!(records[0].frame, ping);
+        assert_eq!(records[1].connection, 2);
+        assert_eq!(records[1].direction, Direction::Outbound);
+        assert!(records[1]
+            .traced_frame()
+            .unwrap()
+            .has_flag(frames::FLAG_ACK));
+
+        let error = read_capture(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(read_capture(&b"not a capture"[..]).is_err());
+    }
+
+    #[test]
+    fn replay_input_is_what_the_peer_sent() {
+        let capture = CaptureWriter::new(Vec::new()).unwrap();
+        let first = ConnectionState::new(4, None);
+        let second = ConnectionState::new(2, None);
+        let settings = encode_frame(frames::FRAME_SETTINGS, 0, 0, &[]);
+        let ack = encode_frame(frames::FRAME_SETTINGS, frames::FLAG_ACK, 0, &[]);
+        let ping = encode_frame(frames::FRAME_PING, 0, 0, b"12345678");
+        capture.on_frame(&first, &traced(Direction::Inbound, &settings));
+        capture.on_frame(&second, &traced(Direction::Inbound, &settings));
+        capture.on_frame(&first, &traced(Direction::Outbound, &ack));
+        capture.on_frame(&first, &traced(Direction::Inbound, &ping));
+
+        let records = read_capture(capture.into_inner().as_slice()).unwrap();
+        assert_eq!(connections(&records), vec![4, 2]);
+        assert_eq!(
+            replay_input(&records, 4),
+            [PREFACE, &settings, &ping].concat()
+        );
+        assert_eq!(replay_input(&records, 2), [PREFACE, &settings].concat());
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr},
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+};
+
+use mio::{
+    event::Source,
+    net::{TcpStream, UnixStream},
+    unix::pipe,
+    Interest, Registry, Token,
+};
+
+/// A byte stream an `Http2Context` runs the protocol over.
+///
+/// Reads and writes are non-blocking, returning `WouldBlock` until the
+/// `Source` is ready again. Implementing it for a TLS session wrapping a
+/// `TcpStream` runs the same framing over TLS.
+pub trait Transport: Read + Write + Source {
+    /// The remote address, `None` when the transport isn't IP based.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Credentials of the process on the other end of a Unix socket.
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        None
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl Transport for UnixStream {
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        PeerCredentials::of(self.as_raw_fd()).ok()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+/// The peer of a Unix socket connection, as reported by the kernel when it
+/// connected. Requests carry it in their extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Not available on every platform.
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn of(fd: RawFd) -> io::Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            pid: Some(cred.pid),
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn of(fd: RawFd) -> io::Result<Self> {
+        let mut uid = 0;
+        let mut gid = 0;
+        if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            pid: None,
+            uid,
+            gid,
+        })
+    }
+}
+
+/// A connection accepted by a `Listener`.
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for Socket {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.register(registry, token, interests),
+            Socket::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.reregister(registry, token, interests),
+            Socket::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.deregister(registry),
+            Socket::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+impl Transport for Socket {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Socket::Tcp(stream) => Transport::peer_addr(stream),
+            Socket::Unix(stream) => Transport::peer_addr(stream),
+        }
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        match self {
+            Socket::Tcp(stream) => stream.peer_credentials(),
+            Socket::Unix(stream) => stream.peer_credentials(),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => Transport::shutdown(stream, how),
+            Socket::Unix(stream) => Transport::shutdown(stream, how),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Half {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One end of an in-memory byte stream, see `duplex`.
+///
+/// Bytes written to one end are read from the other, and each write wakes
+/// the other end through a pipe so both can be registered with a `Poll`.
+/// Without a poll loop it allows driving an `Http2Context` deterministically
+/// from tests.
+pub struct Duplex {
+    incoming: Arc<Mutex<Half>>,
+    outgoing: Arc<Mutex<Half>>,
+    readable: pipe::Receiver,
+    wake_peer: pipe::Sender,
+}
+
+/// A connected pair of in-memory transports.
+pub fn duplex() -> io::Result<(Duplex, Duplex)> {
+    let (wake_a, readable_a) = pipe::new()?;
+    let (wake_b, readable_b) = pipe::new()?;
+    let a_to_b = Arc::new(Mutex::new(Half::default()));
+    let b_to_a = Arc::new(Mutex::new(Half::default()));
+    let a = Duplex {
+        incoming: b_to_a.clone(),
+        outgoing: a_to_b.clone(),
+        readable: readable_a,
+        wake_peer: wake_b,
+    };
+    let b = Duplex {
+        incoming: a_to_b,
+        outgoing: b_to_a,
+        readable: readable_b,
+        wake_peer: wake_a,
+    };
+    Ok((a, b))
+}
+
+impl Duplex {
+    fn wake_peer(&self) {
+        // A full pipe already wakes the peer.
+        let _ = (&self.wake_peer).write(&[1]);
+    }
+
+    fn close(half: &Mutex<Half>) {
+        if let Ok(mut half) = half.lock() {
+            half.closed = true;
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self
+            .incoming
+            .lock()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        if incoming.buffer.is_empty() {
+            if incoming.closed {
+                return Ok(0);
+            }
+            let mut wakes = [0u8; 64];
+            while let Ok(read) = self.readable.read(&mut wakes) {
+                if read == 0 {
+                    break;
+                }
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = usize::min(buf.len(), incoming.buffer.len());
+        for (target, byte) in buf.iter_mut().zip(incoming.buffer.drain(..len)) {
+            *target = byte;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        {
+            let mut outgoing = self
+                .outgoing
+                .lock()
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            if outgoing.closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            outgoing.buffer.extend(buf);
+        }
+        self.wake_peer();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Source for Duplex {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.readable.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.readable.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.readable.deregister(registry)
+    }
+}
+
+impl Transport for Duplex {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            Self::close(&self.outgoing);
+        }
+        if how != Shutdown::Write {
+            Self::close(&self.incoming);
+        }
+        self.wake_peer();
+        Ok(())
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplex_carries_bytes_both_ways() {
+        let (mut a, mut b) = duplex().unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            b.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        a.write_all(b"ping").unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        b.write_all(b"pong").unwrap();
+        assert_eq!(a.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"pong");
+    }
+
+    #[test]
+    fn duplex_reports_closed_peer() {
+        let (mut a, mut b) = duplex().unwrap();
+        a.write_all(b"last").unwrap();
+        drop(a);
+        let mut buf = [0u8; 16];
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            b.write(b"lost").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -0,0 +1,607 @@
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+use http::{Method, Request, Response, StatusCode};
+use mio::{event::Source, net::TcpStream, unix::pipe, Interest, Registry, Token};
+
+use super::{BodyChunk, Handler, Middleware};
+
+/// A stream that stays open in both directions after the response headers,
+/// such as an extended CONNECT (RFC 8441) or a CONNECT tunnel.
+pub trait Tunnel: Send {
+    /// Payload of a DATA frame received on the stream. `end_stream` is set
+    /// when the peer half-closed its side.
+    ///
+    /// Returns how many of the received bytes are consumed, the stream's
+    /// flow-control window is only reopened by that much.
+    fn on_data(&mut self, data: &[u8], end_stream: bool) -> io::Result<usize>;
+
+    /// Bytes to send to the peer, at most `max_len`.
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk>;
+
+    /// The peer reset the stream or the connection went away.
+    fn on_reset(&mut self) {}
+
+    /// A socket the tunnel relays to. It is registered in the server's `Poll`
+    /// and its readiness is reported through `on_ready`.
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        None
+    }
+
+    /// Readiness of the socket returned by `source`, returns the number of
+    /// previously received bytes consumed since the last call.
+    fn on_ready(&mut self, _readable: bool, _writable: bool) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Response extension turning the stream into a `Tunnel` once the response
+/// headers are sent.
+#[derive(Clone)]
+pub struct Upgrade(Arc<Mutex<Option<Box<dyn Tunnel>>>>);
+
+impl Upgrade {
+    pub fn new<T: Tunnel + 'static>(tunnel: T) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(tunnel)))))
+    }
+
+    pub fn take(&self) -> Option<Box<dyn Tunnel>> {
+        match self.0.lock() {
+            Ok(mut tunnel) => tunnel.take(),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Debug for Upgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+enum UpstreamState {
+    /// The host name is looked up on a thread, which wakes the pipe once the
+    /// address is sent.
+    Resolving {
+        waker: pipe::Receiver,
+        address: mpsc::Receiver<io::Result<SocketAddr>>,
+    },
+    Stream(TcpStream),
+}
+
+/// The socket of a `TcpTunnel`. Until the upstream address is known its
+/// registration is held by the resolver's pipe, and moved to the stream once
+/// it connects.
+struct Upstream {
+    state: UpstreamState,
+    registration: Option<(Registry, Token, Interest)>,
+}
+
+impl Upstream {
+    fn stream(&mut self) -> Option<&mut TcpStream> {
+        match &mut self.state {
+            UpstreamState::Stream(stream) => Some(stream),
+            UpstreamState::Resolving { .. } => None,
+        }
+    }
+
+    fn connect(&mut self, addr: SocketAddr) -> io::Result<()> {
+        let mut stream = TcpStream::connect(addr)?;
+        if let Some((registry, token, interests)) = &self.registration {
+            if let UpstreamState::Resolving { waker, .. } = &mut self.state {
+                registry.deregister(waker)?;
+            }
+            registry.register(&mut stream, *token, *interests)?;
+        }
+        self.state = UpstreamState::Stream(stream);
+        Ok(())
+    }
+}
+
+impl Source for Upstream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.registration = Some((registry.try_clone()?, token, interests));
+        match &mut self.state {
+            UpstreamState::Resolving { waker, .. } => {
+                registry.register(waker, token, Interest::READABLE)
+            }
+            UpstreamState::Stream(stream) => registry.register(stream, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.registration = Some((registry.try_clone()?, token, interests));
+        match &mut self.state {
+            UpstreamState::Resolving { waker, .. } => {
+                registry.reregister(waker, token, Interest::READABLE)
+            }
+            UpstreamState::Stream(stream) => registry.reregister(stream, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.registration = None;
+        match &mut self.state {
+            UpstreamState::Resolving { waker, .. } => registry.deregister(waker),
+            UpstreamState::Stream(stream) => registry.deregister(stream),
+        }
+    }
+}
+
+/// Relays a CONNECT stream to an outbound TCP connection.
+pub struct TcpTunnel {
+    upstream: Upstream,
+    connected: bool,
+    to_upstream: Vec<u8>,
+    client_closed: bool,
+    upstream_closed: bool,
+}
+
+impl TcpTunnel {
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::new(UpstreamState::Stream(TcpStream::connect(addr)?)))
+    }
+
+    /// Looks `host` up without blocking the caller, then connects to the
+    /// first address `allow` accepts. A lookup that fails or finds no such
+    /// address is reported by `on_ready`.
+    pub fn resolve<F>(host: &str, port: u16, allow: F) -> io::Result<Self>
+    where
+        F: Fn(&SocketAddr) -> bool + Send + 'static,
+    {
+        let (mut waker, receiver) = pipe::new()?;
+        let (sender, address) = mpsc::channel();
+        let host = host.to_string();
+        thread::Builder::new()
+            .name("khttp-resolver".to_string())
+            .spawn(move || {
+                let result = (host.as_str(), port)
+                    .to_socket_addrs()
+                    .and_then(|mut addrs| {
+                        addrs
+                            .find(|addr| allow(addr))
+                            .ok_or_else(|| io::Error::from(io::ErrorKind::PermissionDenied))
+                    });
+                if sender.send(result).is_ok() {
+                    let _ = waker.write(&[1]);
+                }
+            })?;
+        Ok(Self::new(UpstreamState::Resolving {
+            waker: receiver,
+            address,
+        }))
+    }
+
+    fn new(state: UpstreamState) -> Self {
+        Self {
+            upstream: Upstream {
+                state,
+                registration: None,
+            },
+            connected: false,
+            to_upstream: Vec::new(),
+            client_closed: false,
+            upstream_closed: false,
+        }
+    }
+
+    /// Writes buffered client bytes to the upstream, half-closing it once the
+    /// client sent END_STREAM and everything was written.
+    fn flush(&mut self) -> io::Result<usize> {
+        let stream = match self.upstream.stream() {
+            Some(stream) if self.connected => stream,
+            _ => return Ok(0),
+        };
+        let mut written = 0;
+        while written < self.to_upstream.len() {
+            match stream.write(&self.to_upstream[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => written += len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.to_upstream.drain(0..written);
+        if self.client_closed && self.to_upstream.is_empty() {
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+        Ok(written)
+    }
+}
+
+impl Tunnel for TcpTunnel {
+    fn on_data(&mut self, data: &[u8], end_stream: bool) -> io::Result<usize> {
+        self.to_upstream.extend_from_slice(data);
+        self.client_closed |= end_stream;
+        self.flush()
+    }
+
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        let stream = match self.upstream.stream() {
+            Some(stream) if self.connected => stream,
+            _ => return Ok(BodyChunk::Pending),
+        };
+        if self.upstream_closed {
+            return Ok(BodyChunk::End);
+        }
+        // Only read as much as the flow-control windows allow, the rest stays
+        // in the socket until the peer sends WINDOW_UPDATE.
+        let mut buffer = vec![0u8; max_len];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.upstream_closed = true;
+                    return Ok(BodyChunk::End);
+                }
+                Ok(len) => {
+                    buffer.truncate(len);
+                    return Ok(BodyChunk::Data(buffer));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(BodyChunk::Pending),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn on_reset(&mut self) {
+        if let Some(stream) = self.upstream.stream() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        Some(&mut self.upstream)
+    }
+
+    fn on_ready(&mut self, _readable: bool, writable: bool) -> io::Result<usize> {
+        if let UpstreamState::Resolving { address, .. } = &self.upstream.state {
+            match address.try_recv() {
+                Ok(addr) => self.upstream.connect(addr?)?,
+                Err(mpsc::TryRecvError::Empty) => {}
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(io::ErrorKind::BrokenPipe.into())
+                }
+            }
+            // The stream reports writable once connected.
+            return Ok(0);
+        }
+        let stream = match self.upstream.stream() {
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        if !self.connected && writable {
+            // https://docs.rs/mio/latest/mio/net/struct.TcpStream.html#method.connect
+            if let Some(e) = stream.take_error()? {
+                return Err(e);
+            }
+            match stream.peer_addr() {
+                Ok(_) => self.connected = true,
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => return Ok(0),
+                Err(e) => return Err(e),
+            }
+        }
+        self.flush()
+    }
+}
+
+/// Loopback, private, link-local and other addresses that don't lead to the
+/// public internet.
+fn is_internal(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // Shared address space, RFC 6598.
+                || (a == 100 && (b & 0xc0) == 64)
+                || a == 0
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(&IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local fc00::/7 and link-local fe80::/10.
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+/// One host name lookup in flight, counted until it is dropped.
+struct Lookup(Arc<AtomicUsize>);
+
+impl Lookup {
+    /// `None` when `max` lookups are in flight already.
+    fn start(in_flight: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()?;
+        Some(Self(in_flight.clone()))
+    }
+}
+
+impl Drop for Lookup {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware answering classic `CONNECT host:port` requests by opening a
+/// `TcpTunnel`, which makes the server usable as a forward proxy. Other
+/// requests, including extended CONNECT, go to the next handler.
+///
+/// Only port 443 is allowed by default, and addresses of the server's own
+/// network are refused whatever the host name resolves to. Host names are
+/// looked up on a thread each, requests beyond `max_lookups` in flight get a
+/// `503 Service Unavailable`.
+pub struct ConnectProxy {
+    allowed_ports: Vec<u16>,
+    allow_internal: bool,
+    lookups: Arc<AtomicUsize>,
+    max_lookups: usize,
+}
+
+impl Default for ConnectProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectProxy {
+    pub fn new() -> Self {
+        Self {
+            allowed_ports: vec![443],
+            allow_internal: false,
+            lookups: Arc::new(AtomicUsize::new(0)),
+            max_lookups: 16,
+        }
+    }
+
+    pub fn allowed_ports(mut self, ports: &[u16]) -> Self {
+        self.allowed_ports = ports.to_vec();
+        self
+    }
+
+    /// Lets tunnels reach loopback, private and link-local addresses.
+    pub fn allow_internal_addresses(mut self, allow: bool) -> Self {
+        self.allow_internal = allow;
+        self
+    }
+
+    pub fn max_lookups(mut self, max_lookups: usize) -> Self {
+        self.max_lookups = max_lookups;
+        self
+    }
+
+    fn open(&self, authority: &str) -> Result<TcpTunnel, StatusCode> {
+        let (host, port) = authority.rsplit_once(':').ok_or(StatusCode::BAD_REQUEST)?;
+        let port: u16 = port.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        if !self.allowed_ports.contains(&port) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let allow_internal = self.allow_internal;
+        let tunnel = match host.parse::<IpAddr>() {
+            Ok(ip) if is_internal(&ip) && !allow_internal => return Err(StatusCode::FORBIDDEN),
+            Ok(ip) => TcpTunnel::connect(SocketAddr::new(ip, port)),
+            Err(_) => {
+                let lookup = Lookup::start(&self.lookups, self.max_lookups)
+                    .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+                TcpTunnel::resolve(host, port, move |addr| {
+                    // Counted until the resolver thread drops the closure.
+                    let _lookup = &lookup;
+                    allow_internal || !is_internal(&addr.ip())
+                })
+            }
+        };
+        tunnel.map_err(|_| StatusCode::BAD_GATEWAY)
+    }
+}
+
+impl Middleware for ConnectProxy {
+    fn handle(
+        &self,
+        token: Token,
+        request: Request<Vec<u8>>,
+        next: &dyn Handler,
+    ) -> Response<Vec<u8>> {
+        if request.method() != Method::CONNECT || request.headers().contains_key("protocol") {
+            return next.call(token, request);
+        }
+        let authority = request
+            .headers()
+            .get("authority")
+            .and_then(|authority| authority.to_str().ok())
+            .unwrap_or_default();
+
+        let (status, tunnel) = match self.open(authority) {
+            Ok(tunnel) => (StatusCode::OK, Some(tunnel)),
+            Err(status) => (status, None),
+        };
+        let mut response = Response::builder().status(status).body(Vec::new()).unwrap();
+        if let Some(tunnel) = tunnel {
+            response.extensions_mut().insert(Upgrade::new(tunnel));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        time::{Duration, Instant},
+    };
+
+    use mio::{Events, Poll};
+
+    use super::*;
+
+    /// Hands the readiness of the tunnel's socket to it until `done`.
+    fn run(
+        poll: &mut Poll,
+        tunnel: &mut TcpTunnel,
+        mut done: impl FnMut(&mut TcpTunnel) -> bool,
+    ) -> io::Result<()> {
+        let mut events = Events::with_capacity(8);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(tunnel) {
+            assert!(Instant::now() < deadline, "the tunnel is stuck");
+            poll.poll(&mut events, Some(Duration::from_millis(50)))?;
+            for event in events.iter() {
+                tunnel.on_ready(event.is_readable(), event.is_writable())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn register(tunnel: &mut TcpTunnel) -> Poll {
+        let poll = Poll::new().unwrap();
+        let source = tunnel.source().unwrap();
+        poll.registry()
+            .register(source, Token(1), Interest::READABLE | Interest::WRITABLE)
+            .unwrap();
+        poll
+    }
+
+    #[test]
+    fn only_public_addresses_on_allowed_ports_are_opened() {
+        let proxy = ConnectProxy::new();
+        let status = |authority| proxy.open(authority).err();
+        assert_eq!(status("example.com"), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status(":443"), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status("example.com:https"), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status("example.com:22"), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status("127.0.0.1:443"), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status("[::1]:443"), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status("10.1.2.3:443"), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn lookups_in_flight_are_limited() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proxy = ConnectProxy::new()
+            .allowed_ports(&[port])
+            .allow_internal_addresses(true)
+            .max_lookups(1);
+        let lookup = Lookup::start(&proxy.lookups, 1).unwrap();
+        let open = |host: &str| proxy.open(&format!("{}:{}", host, port)).err();
+        assert_eq!(open("localhost"), Some(StatusCode::SERVICE_UNAVAILABLE));
+        // Addresses don't need a lookup.
+        assert_eq!(open("127.0.0.1"), None);
+
+        drop(lookup);
+        assert_eq!(open("localhost"), None);
+    }
+
+    #[test]
+    fn internal_addresses_are_recognized() {
+        let internal = |ip: &str| is_internal(&ip.parse().unwrap());
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(internal(ip), "{}", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(!internal(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn tunnels_relay_both_ways() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut tunnel = TcpTunnel::resolve("localhost", port, |addr| addr.is_ipv4()).unwrap();
+        let mut poll = register(&mut tunnel);
+
+        // Sent before the name is even resolved.
+        assert_eq!(tunnel.on_data(b"ping", true).unwrap(), 0);
+        run(&mut poll, &mut tunnel, |tunnel| {
+            tunnel.to_upstream.is_empty()
+        })
+        .unwrap();
+
+        let (mut upstream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        upstream.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"ping");
+
+        upstream.write_all(b"pong").unwrap();
+        drop(upstream);
+        let mut received = Vec::new();
+        run(&mut poll, &mut tunnel, |tunnel| {
+            match tunnel.next_chunk(1024).unwrap() {
+                BodyChunk::Data(data) => received.extend(data),
+                BodyChunk::End => return true,
+                _ => {}
+            }
+            false
+        })
+        .unwrap();
+        assert_eq!(received, b"pong");
+    }
+
+    #[test]
+    fn addresses_are_checked_after_the_lookup() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proxy = ConnectProxy::new().allowed_ports(&[port]);
+        let mut tunnel = match proxy.open(&format!("localhost:{}", port)) {
+            Ok(tunnel) => tunnel,
+            Err(status) => panic!("{}", status),
+        };
+        let mut poll = register(&mut tunnel);
+        let err = run(&mut poll, &mut tunnel, |_| false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,684 @@
+use std::{io, sync::Arc};
+
+use http::{Method, Request, Response, StatusCode};
+use mio::{event::Source, Token};
+
+use super::{BodyChunk, Handler, Middleware, QueueReceiver, QueueSender, Queued, Tunnel, Upgrade};
+
+// https://datatracker.ietf.org/doc/html/rfc6455#section-5.2
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+/// Serializes a single, unmasked frame as sent by a server.
+pub fn encode_frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(payload.len() + 10);
+    result.push(if fin { 0x80 } else { 0 } | opcode);
+    if payload.len() < 126 {
+        result.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        result.push(126);
+        result.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        result.push(127);
+        result.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    result.extend_from_slice(payload);
+    result
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses one frame from the start of `buffer`, returning it with its size or
+/// `None` when more bytes are needed. Client frames have to be masked.
+fn decode_frame(buffer: &[u8], max_len: usize) -> Result<Option<(usize, Frame)>, u16> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    if buffer[0] & 0x70 != 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let opcode = buffer[0] & 0x0f;
+    if buffer[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let (len, mut offset) = match buffer[1] & 0x7f {
+        126 => {
+            if buffer.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4)
+        }
+        127 => {
+            if buffer.len() < 10 {
+                return Ok(None);
+            }
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        len => (len as u64, 2),
+    };
+    if len > max_len as u64 {
+        return Err(CLOSE_MESSAGE_TOO_BIG);
+    }
+    if opcode >= OPCODE_CLOSE && (len > 125 || !fin) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let len = len as usize;
+    if buffer.len() < offset + 4 + len {
+        return Ok(None);
+    }
+    let mask = [
+        buffer[offset],
+        buffer[offset + 1],
+        buffer[offset + 2],
+        buffer[offset + 3],
+    ];
+    offset += 4;
+    let payload = buffer[offset..offset + len]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        offset + len,
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+    )))
+}
+
+/// The frame carrying `message`, and whether it closes the socket.
+fn encode_message(message: &Message) -> (Vec<u8>, bool) {
+    let (opcode, payload) = match message {
+        Message::Text(text) => (OPCODE_TEXT, text.as_bytes().to_vec()),
+        Message::Binary(data) => (OPCODE_BINARY, data.clone()),
+        Message::Ping(data) => (OPCODE_PING, data.clone()),
+        Message::Pong(data) => (OPCODE_PONG, data.clone()),
+        Message::Close(reason) => {
+            let mut payload = Vec::new();
+            if let Some((code, reason)) = reason {
+                payload.extend_from_slice(&code.to_be_bytes());
+                payload.extend_from_slice(reason.as_bytes());
+            }
+            (OPCODE_CLOSE, payload)
+        }
+    };
+    let close = opcode == OPCODE_CLOSE;
+    (encode_frame(opcode, true, &payload), close)
+}
+
+/// Handle sending messages on a WebSocket from outside its callbacks, e.g.
+/// from another thread.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    queue: QueueSender<()>,
+}
+
+impl WebSocketSender {
+    pub fn send(&self, message: Message) -> io::Result<()> {
+        match encode_message(&message) {
+            (frame, true) => self.queue.send_last(frame, ()),
+            (frame, false) => self.queue.send(frame),
+        }
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.send(Message::Text(text.to_string()))
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> io::Result<()> {
+        self.send(Message::Binary(data.to_vec()))
+    }
+
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.send(Message::Close(Some((code, reason.to_string()))))
+    }
+
+    /// The socket was closed by the client or the stream went away.
+    pub fn is_disconnected(&self) -> bool {
+        self.queue.is_disconnected()
+    }
+}
+
+/// The server side of a WebSocket running over an HTTP/2 stream.
+pub struct WebSocket {
+    token: Token,
+    sender: WebSocketSender,
+}
+
+impl WebSocket {
+    /// The connection the socket belongs to.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// A handle to keep sending once the callback returned.
+    pub fn sender(&self) -> WebSocketSender {
+        self.sender.clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.queue.is_ended()
+    }
+
+    pub fn send(&mut self, message: Message) {
+        // Nothing is sent after a close frame.
+        let (frame, close) = encode_message(&message);
+        let _ = self.sender.queue.send_unbounded(frame, close.then_some(()));
+    }
+
+    pub fn send_text(&mut self, text: &str) {
+        self.send(Message::Text(text.to_string()));
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) {
+        self.send(Message::Binary(data.to_vec()));
+    }
+
+    pub fn ping(&mut self, data: &[u8]) {
+        self.send(Message::Ping(data.to_vec()));
+    }
+
+    pub fn close(&mut self, code: u16, reason: &str) {
+        self.send(Message::Close(Some((code, reason.to_string()))));
+    }
+}
+
+/// Application callbacks for an accepted WebSocket.
+pub trait WebSocketHandler: Send + Sync {
+    fn on_open(&self, _socket: &mut WebSocket, _request: &Request<Vec<u8>>) {}
+
+    fn on_message(&self, socket: &mut WebSocket, message: Message);
+
+    fn on_close(&self, _socket: &mut WebSocket, _code: Option<u16>, _reason: &str) {}
+}
+
+struct WebSocketTunnel {
+    handler: Arc<dyn WebSocketHandler>,
+    socket: WebSocket,
+    queue: QueueReceiver<()>,
+    read_buffer: Vec<u8>,
+    fragments: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+    /// `on_close` was called, nothing the peer sends is read anymore.
+    closed: bool,
+}
+
+impl WebSocketTunnel {
+    fn on_closed(&mut self, code: Option<u16>, reason: &str) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.read_buffer.clear();
+        self.fragments = None;
+        self.handler.on_close(&mut self.socket, code, reason);
+    }
+
+    fn fail(&mut self, code: u16) {
+        self.socket.close(code, "");
+        self.on_closed(Some(code), "");
+    }
+
+    fn handle_frame(&mut self, frame: Frame) {
+        match frame.opcode {
+            OPCODE_PING => {
+                self.socket.send(Message::Pong(frame.payload.clone()));
+                self.handler
+                    .on_message(&mut self.socket, Message::Ping(frame.payload));
+            }
+            OPCODE_PONG => {
+                self.handler
+                    .on_message(&mut self.socket, Message::Pong(frame.payload));
+            }
+            OPCODE_CLOSE => {
+                let (code, reason) = if frame.payload.len() >= 2 {
+                    let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                    let reason = String::from_utf8_lossy(&frame.payload[2..]).to_string();
+                    (Some(code), reason)
+                } else {
+                    (None, String::new())
+                };
+                // Echo the close frame to complete the closing handshake.
+                if !self.socket.is_closed() {
+                    self.socket
+                        .send(Message::Close(code.map(|code| (code, String::new()))));
+                }
+                self.on_closed(code, &reason);
+            }
+            OPCODE_TEXT | OPCODE_BINARY => {
+                if self.fragments.is_some() {
+                    return self.fail(CLOSE_PROTOCOL_ERROR);
+                }
+                if frame.fin {
+                    self.deliver(frame.opcode, frame.payload);
+                } else {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+            }
+            OPCODE_CONTINUATION => {
+                let (opcode, mut payload) = match self.fragments.take() {
+                    Some(fragments) => fragments,
+                    None => return self.fail(CLOSE_PROTOCOL_ERROR),
+                };
+                if payload.len() + frame.payload.len() > self.max_message_size {
+                    return self.fail(CLOSE_MESSAGE_TOO_BIG);
+                }
+                payload.extend(frame.payload);
+                if frame.fin {
+                    self.deliver(opcode, payload);
+                } else {
+                    self.fragments = Some((opcode, payload));
+                }
+            }
+            _ => self.fail(CLOSE_PROTOCOL_ERROR),
+        }
+    }
+
+    fn deliver(&mut self, opcode: u8, payload: Vec<u8>) {
+        let message = if opcode == OPCODE_TEXT {
+            match String::from_utf8(payload) {
+                Ok(text) => Message::Text(text),
+                Err(_) => return self.fail(CLOSE_INVALID_PAYLOAD),
+            }
+        } else {
+            Message::Binary(payload)
+        };
+        self.handler.on_message(&mut self.socket, message);
+    }
+}
+
+impl Tunnel for WebSocketTunnel {
+    fn on_data(&mut self, data: &[u8], end_stream: bool) -> io::Result<usize> {
+        if !self.closed {
+            self.read_buffer.extend_from_slice(data);
+        }
+        while !self.closed {
+            match decode_frame(&self.read_buffer, self.max_message_size) {
+                Ok(Some((size, frame))) => {
+                    self.read_buffer.drain(0..size);
+                    self.handle_frame(frame);
+                }
+                Ok(None) => break,
+                Err(code) => self.fail(code),
+            }
+        }
+        if end_stream {
+            // The peer won't send anything anymore, end our side as well.
+            if !self.socket.is_closed() {
+                self.socket.close(CLOSE_GOING_AWAY, "");
+            }
+            self.on_closed(Some(CLOSE_GOING_AWAY), "");
+        }
+        Ok(data.len())
+    }
+
+    fn next_chunk(&mut self, max_len: usize) -> io::Result<BodyChunk> {
+        Ok(match self.queue.read(max_len)? {
+            Queued::Data(data) => BodyChunk::Data(data),
+            Queued::Pending => BodyChunk::Pending,
+            Queued::End(_) => BodyChunk::End,
+        })
+    }
+
+    fn on_reset(&mut self) {
+        self.on_closed(Some(CLOSE_GOING_AWAY), "");
+    }
+
+    fn source(&mut self) -> Option<&mut dyn Source> {
+        Some(self.queue.source())
+    }
+
+    fn on_ready(&mut self, _readable: bool, _writable: bool) -> io::Result<usize> {
+        self.queue.drain()?;
+        Ok(0)
+    }
+}
+
+/// Middleware accepting RFC 8441 extended CONNECT requests with
+/// `:protocol = websocket` on `path`, other requests go to the next handler.
+pub struct WebSockets {
+    path: String,
+    handler: Arc<dyn WebSocketHandler>,
+    max_message_size: usize,
+}
+
+impl WebSockets {
+    pub fn new<H: WebSocketHandler + 'static>(path: &str, handler: H) -> Self {
+        Self {
+            path: path.to_string(),
+            handler: Arc::new(handler),
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+}
+
+impl Middleware for WebSockets {
+    fn handle(
+        &self,
+        token: Token,
+        request: Request<Vec<u8>>,
+        next: &dyn Handler,
+    ) -> Response<Vec<u8>> {
+        let is_websocket = request.method() == Method::CONNECT
+            && request
+                .headers()
+                .get("protocol")
+                .is_some_and(|protocol| protocol.as_bytes().eq_ignore_ascii_case(b"websocket"));
+        if !is_websocket || request.uri().path() != self.path {
+            return next.call(token, request);
+        }
+
+        let version = request
+            .headers()
+            .get("sec-websocket-version")
+            .map(|version| version.as_bytes());
+        if version != Some(b"13".as_slice()) {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("sec-websocket-version", "13")
+                .body(Vec::new())
+                .unwrap();
+        }
+
+        let (queue, receiver) = match QueueReceiver::new() {
+            Ok(pipe) => pipe,
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Vec::new())
+                    .unwrap()
+            }
+        };
+        let mut tunnel = WebSocketTunnel {
+            handler: self.handler.clone(),
+            socket: WebSocket {
+                token,
+                sender: WebSocketSender { queue },
+            },
+            queue: receiver,
+            read_buffer: Vec::new(),
+            fragments: None,
+            max_message_size: self.max_message_size,
+            closed: false,
+        };
+        self.handler.on_open(&mut tunnel.socket, &request);
+
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .body(Vec::new())
+            .unwrap();
+        response.extensions_mut().insert(Upgrade::new(tunnel));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::{super::MAX_QUEUED, *};
+
+    /// A client frame, masked with a fixed key.
+    fn client_frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = encode_frame(opcode, fin, payload);
+        let header_len = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        frame.truncate(header_len);
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl WebSocketHandler for Arc<Recorder> {
+        fn on_message(&self, socket: &mut WebSocket, message: Message) {
+            if let Message::Text(text) = &message {
+                socket.send_text(text);
+            }
+            self.0.lock().unwrap().push(format!("{:?}", message));
+        }
+
+        fn on_close(&self, _socket: &mut WebSocket, code: Option<u16>, _reason: &str) {
+            self.0.lock().unwrap().push(format!("close {:?}", code));
+        }
+    }
+
+    fn tunnel(max_message_size: usize) -> (Arc<Recorder>, WebSocketTunnel) {
+        let recorder = Arc::new(Recorder::default());
+        let (queue, receiver) = QueueReceiver::new().unwrap();
+        let tunnel = WebSocketTunnel {
+            handler: Arc::new(recorder.clone()),
+            socket: WebSocket {
+                token: Token(0),
+                sender: WebSocketSender { queue },
+            },
+            queue: receiver,
+            read_buffer: Vec::new(),
+            fragments: None,
+            max_message_size,
+            closed: false,
+        };
+        (recorder, tunnel)
+    }
+
+    fn output(tunnel: &mut WebSocketTunnel) -> Vec<u8> {
+        match tunnel.next_chunk(usize::MAX).unwrap() {
+            BodyChunk::Data(data) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn frames_are_unmasked() {
+        let frame = client_frame(OPCODE_TEXT, true, b"hello");
+        let (size, decoded) = decode_frame(&frame, 1024).unwrap().unwrap();
+        assert_eq!(size, frame.len());
+        assert!(decoded.fin);
+        assert_eq!(decoded.opcode, OPCODE_TEXT);
+        assert_eq!(decoded.payload, b"hello");
+
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload = vec![0xaa; len];
+            let frame = client_frame(OPCODE_BINARY, false, &payload);
+            let (size, decoded) = decode_frame(&frame, usize::MAX).unwrap().unwrap();
+            assert_eq!(size, frame.len());
+            assert!(!decoded.fin);
+            assert_eq!(decoded.payload, payload);
+            // Every prefix is incomplete.
+            for end in [1, 3, 9, frame.len() - 1]
+                .into_iter()
+                .filter(|&end| end < frame.len())
+            {
+                assert!(decode_frame(&frame[..end], usize::MAX).unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_frames_are_rejected() {
+        let unmasked = encode_frame(OPCODE_TEXT, true, b"hi");
+        assert_eq!(
+            decode_frame(&unmasked, 1024).err(),
+            Some(CLOSE_PROTOCOL_ERROR)
+        );
+
+        let mut reserved = client_frame(OPCODE_TEXT, true, b"hi");
+        reserved[0] |= 0x40;
+        assert_eq!(
+            decode_frame(&reserved, 1024).err(),
+            Some(CLOSE_PROTOCOL_ERROR)
+        );
+
+        let fragmented_ping = client_frame(OPCODE_PING, false, b"");
+        assert_eq!(
+            decode_frame(&fragmented_ping, 1024).err(),
+            Some(CLOSE_PROTOCOL_ERROR)
+        );
+        let long_ping = client_frame(OPCODE_PING, true, &[0; 126]);
+        assert_eq!(
+            decode_frame(&long_ping, 1024).err(),
+            Some(CLOSE_PROTOCOL_ERROR)
+        );
+
+        // The length is checked before the payload arrives.
+        let too_big = client_frame(OPCODE_BINARY, true, &[0; 200]);
+        assert_eq!(
+            decode_frame(&too_big[..8], 100).err(),
+            Some(CLOSE_MESSAGE_TOO_BIG)
+        );
+    }
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let (recorder, mut tunnel) = tunnel(1024);
+        let mut data = client_frame(OPCODE_TEXT, false, b"hel");
+        data.extend(client_frame(OPCODE_PING, true, b"p"));
+        data.extend(client_frame(OPCODE_CONTINUATION, false, b"l"));
+        data.extend(client_frame(OPCODE_CONTINUATION, true, b"o"));
+        // Split mid-frame, the rest arrives with the next DATA frame.
+        tunnel.on_data(&data[..7], false).unwrap();
+        tunnel.on_data(&data[7..], false).unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![r#"Ping([112])"#, r#"Text("hello")"#]
+        );
+
+        let mut expected = encode_frame(OPCODE_PONG, true, b"p");
+        expected.extend(encode_frame(OPCODE_TEXT, true, b"hello"));
+        assert_eq!(output(&mut tunnel), expected);
+    }
+
+    #[test]
+    fn fragmented_messages_are_limited() {
+        let (recorder, mut tunnel) = tunnel(4);
+        let mut data = client_frame(OPCODE_BINARY, false, b"abc");
+        data.extend(client_frame(OPCODE_CONTINUATION, true, b"de"));
+        tunnel.on_data(&data, false).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["close Some(1009)"]);
+    }
+
+    #[test]
+    fn nothing_is_read_after_a_failure() {
+        let (recorder, mut tunnel) = tunnel(1024);
+        let mut data = client_frame(OPCODE_CONTINUATION, true, b"x");
+        data.extend(client_frame(OPCODE_TEXT, true, b"ignored"));
+        tunnel.on_data(&data, false).unwrap();
+        tunnel
+            .on_data(&client_frame(OPCODE_TEXT, true, b"ignored"), true)
+            .unwrap();
+        tunnel.on_reset();
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["close Some(1002)"]);
+
+        let close = encode_frame(OPCODE_CLOSE, true, &CLOSE_PROTOCOL_ERROR.to_be_bytes());
+        assert_eq!(output(&mut tunnel), close);
+        assert!(matches!(tunnel.next_chunk(1024).unwrap(), BodyChunk::End));
+    }
+
+    #[test]
+    fn close_frames_are_echoed() {
+        let (recorder, mut tunnel) = tunnel(1024);
+        let mut payload = CLOSE_NORMAL.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        tunnel
+            .on_data(&client_frame(OPCODE_CLOSE, true, &payload), false)
+            .unwrap();
+        tunnel.on_data(&[], true).unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["close Some(1000)"]);
+        assert_eq!(
+            output(&mut tunnel),
+            encode_frame(OPCODE_CLOSE, true, &CLOSE_NORMAL.to_be_bytes())
+        );
+    }
+
+    #[test]
+    fn half_close_without_close_frame_ends_the_stream() {
+        let (recorder, mut tunnel) = tunnel(1024);
+        tunnel
+            .on_data(&client_frame(OPCODE_TEXT, true, b"hi"), true)
+            .unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![r#"Text("hi")"#, "close Some(1001)"]
+        );
+
+        let mut expected = encode_frame(OPCODE_TEXT, true, b"hi");
+        expected.extend(encode_frame(
+            OPCODE_CLOSE,
+            true,
+            &CLOSE_GOING_AWAY.to_be_bytes(),
+        ));
+        assert_eq!(output(&mut tunnel), expected);
+        assert!(matches!(tunnel.next_chunk(1024).unwrap(), BodyChunk::End));
+    }
+
+    #[test]
+    fn senders_queue_outside_callbacks() {
+        let (_, mut tunnel) = tunnel(1024);
+        let sender = tunnel.socket.sender();
+        assert!(tunnel.source().is_some());
+        assert!(matches!(
+            tunnel.next_chunk(1024).unwrap(),
+            BodyChunk::Pending
+        ));
+
+        sender.send_text("hi").unwrap();
+        assert_eq!(tunnel.on_ready(true, false).unwrap(), 0);
+        assert_eq!(output(&mut tunnel), encode_frame(OPCODE_TEXT, true, b"hi"));
+
+        // The limit counts the message being sent.
+        sender.send_binary(&vec![0; MAX_QUEUED / 2]).unwrap();
+        let err = sender.send_binary(&vec![0; MAX_QUEUED / 2]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        output(&mut tunnel);
+
+        sender.close(CLOSE_GOING_AWAY, "").unwrap();
+        let err = sender.send_text("late").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        assert!(!sender.is_disconnected());
+        drop(tunnel);
+        assert!(sender.is_disconnected());
+    }
+}
@@ -0,0 +1,368 @@
+//! A raw-frame HTTP/2 client and a server on an ephemeral port, shared by the
+//! integration tests.
+//!
+//! The server runs on a background thread until its `TestServer` is dropped,
+//! which shuts it down and waits for `serve` to return.
+
+#![allow(dead_code)]
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use khttp::http2::{frames, Handler, Http2Error, Http2Server, ShutdownHandle};
+use kparser::http2::{HpackContext, Payload};
+
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: Option<JoinHandle<Result<(), Http2Error>>>,
+}
+
+impl TestServer {
+    /// Serves `handler` on an ephemeral port of the loopback interface.
+    pub fn spawn<H: Handler + Send + 'static>(handler: H) -> Self {
+        Self::spawn_with(handler, |_| {})
+    }
+
+    /// Like `spawn`, with `configure` called on the server before it serves.
+    pub fn spawn_with<H, C>(handler: H, configure: C) -> Self
+    where
+        H: Handler + Send + 'static,
+        C: FnOnce(&mut Http2Server) + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (handle_sender, handle) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let listener = mio::net::TcpListener::from_std(listener);
+            let mut server = Http2Server::from_listener(listener)?;
+            configure(&mut server);
+            let _ = handle_sender.send(server.shutdown_handle());
+            server.serve(handler)
+        });
+        Self {
+            addr,
+            shutdown: handle.recv().unwrap(),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A client past the handshake.
+    pub fn client(&self) -> Client {
+        Client::handshake(self.addr, &[]).unwrap()
+    }
+
+    /// Shuts the server down, panicking if `serve` failed.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let result = thread.join();
+            if !thread::panicking() {
+                result.unwrap().unwrap();
+            }
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+
+    /// The error code of a RST_STREAM or GOAWAY.
+    pub fn error_code(&self) -> Option<u32> {
+        let offset = match self.kind {
+            frames::FRAME_RST_STREAM => 0,
+            frames::FRAME_GOAWAY => 4,
+            _ => return None,
+        };
+        let code = self.payload.get(offset..offset + 4)?;
+        Some(u32::from_be_bytes(code.try_into().ok()?))
+    }
+}
+
+#[derive(Debug)]
+pub enum Received {
+    Frame(Frame),
+    /// The server closed or reset the connection.
+    Closed,
+    Timeout,
+}
+
+/// A response read by `Client::response`.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.as_slice() == name.as_bytes())
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn status(&self) -> Option<&[u8]> {
+        self.header(":status")
+    }
+}
+
+/// The connection under a `Client`.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+pub struct Client {
+    stream: Box<dyn Stream>,
+    buffer: Vec<u8>,
+    decoder: HpackContext,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(Self::over(Box::new(stream)))
+    }
+
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        Ok(Self::over(Box::new(stream)))
+    }
+
+    fn over(stream: Box<dyn Stream>) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            decoder: HpackContext::new(128),
+        }
+    }
+
+    /// Connects, exchanges prefaces and acknowledges the server's SETTINGS.
+    pub fn handshake(addr: SocketAddr, settings: &[(u16, u32)]) -> io::Result<Self> {
+        Self::connect(addr)?.exchange_settings(settings)
+    }
+
+    /// Like `handshake`, over a Unix socket.
+    pub fn handshake_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::connect_unix(path)?.exchange_settings(&[])
+    }
+
+    fn exchange_settings(mut self, settings: &[(u16, u32)]) -> io::Result<Self> {
+        self.send(frames::PREFACE)?;
+        self.send_frame(
+            frames::FRAME_SETTINGS,
+            0,
+            0,
+            &frames::settings_payload(settings),
+        )?;
+        let (mut settings_received, mut ack_received) = (false, false);
+        while !(settings_received && ack_received) {
+            match self.recv() {
+                Received::Frame(frame) if frame.kind == frames::FRAME_SETTINGS => {
+                    if frame.has_flag(frames::FLAG_ACK) {
+                        ack_received = true;
+                    } else {
+                        settings_received = true;
+                        self.send_frame(frames::FRAME_SETTINGS, frames::FLAG_ACK, 0, &[])?;
+                    }
+                }
+                Received::Frame(_) => {}
+                Received::Closed => return Err(io::ErrorKind::ConnectionAborted.into()),
+                Received::Timeout => return Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(bytes)
+    }
+
+    pub fn send_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        self.send(&frames::encode_frame(kind, flags, stream_id, payload))
+    }
+
+    /// Sends a complete GET request on `stream_id`.
+    pub fn get(&mut self, stream_id: u32) -> io::Result<()> {
+        self.send_frame(
+            frames::FRAME_HEADERS,
+            frames::FLAG_END_STREAM | frames::FLAG_END_HEADERS,
+            stream_id,
+            &encode_headers(GET),
+        )
+    }
+
+    pub fn ping(&mut self, opaque: &[u8; 8]) -> io::Result<()> {
+        self.send_frame(frames::FRAME_PING, 0, 0, opaque)
+    }
+
+    pub fn recv(&mut self) -> Received {
+        loop {
+            if let Some(len) = frames::frame_len(&self.buffer) {
+                if len <= self.buffer.len() {
+                    let bytes: Vec<u8> = self.buffer.drain(..len).collect();
+                    return Received::Frame(Frame {
+                        kind: bytes[3],
+                        flags: bytes[4],
+                        stream_id: u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]])
+                            & 0x7fff_ffff,
+                        payload: bytes[9..].to_vec(),
+                    });
+                }
+            }
+            let mut chunk = [0u8; 16384];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Received::Closed,
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Received::Timeout
+                }
+                Err(_) => return Received::Closed,
+            }
+        }
+    }
+
+    /// Decodes the header block of a HEADERS frame, updating the dynamic table.
+    pub fn decode_headers(&mut self, frame: &Frame) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bytes = frames::encode_frame(frame.kind, frame.flags, frame.stream_id, &frame.payload);
+        let frame = <kparser::http2::Frame as TryFrom<&[u8]>>::try_from(bytes.as_slice()).ok()?;
+        match frame.payload {
+            Payload::Headers(headers) => headers
+                .HeaderBlockFragment
+                .decode(&mut self.decoder)
+                .ok()
+                .map(|(headers, _)| headers),
+            _ => None,
+        }
+    }
+
+    /// Reads the response of `stream_id` up to END_STREAM, skipping the frames
+    /// of other streams. `None` when the stream or connection fails first.
+    pub fn response(&mut self, stream_id: u32) -> Option<TestResponse> {
+        let mut response = TestResponse {
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        loop {
+            let frame = self.recv_until(|frame| {
+                frame.stream_id == stream_id || frame.kind == frames::FRAME_GOAWAY
+            })?;
+            match frame.kind {
+                frames::FRAME_HEADERS => {
+                    let headers = self.decode_headers(&frame)?;
+                    response.headers.extend(headers);
+                }
+                frames::FRAME_DATA => response.body.extend_from_slice(&frame.payload),
+                frames::FRAME_WINDOW_UPDATE => continue,
+                _ => return None,
+            }
+            if frame.has_flag(frames::FLAG_END_STREAM) {
+                return Some(response);
+            }
+        }
+    }
+
+    /// The next frame matching `predicate`, skipping the others.
+    pub fn recv_until(&mut self, mut predicate: impl FnMut(&Frame) -> bool) -> Option<Frame> {
+        loop {
+            match self.recv() {
+                Received::Frame(frame) if predicate(&frame) => return Some(frame),
+                Received::Frame(_) => {}
+                Received::Closed | Received::Timeout => return None,
+            }
+        }
+    }
+}
+
+pub const GET: &[(&str, &str)] = &[
+    (":method", "GET"),
+    (":scheme", "http"),
+    (":path", "/"),
+    (":authority", "localhost"),
+];
+
+pub const POST: &[(&str, &str)] = &[
+    (":method", "POST"),
+    (":scheme", "http"),
+    (":path", "/"),
+    (":authority", "localhost"),
+];
+
+/// Encodes a header block of literals without indexing, not Huffman coded.
+pub fn encode_headers(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in headers {
+        block.push(0x00);
+        encode_string(&mut block, name.as_bytes());
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+pub fn encode_string(block: &mut Vec<u8>, value: &[u8]) {
+    encode_integer(block, 0x00, 7, value.len());
+    block.extend_from_slice(value);
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc7541#section-5.1
+pub fn encode_integer(block: &mut Vec<u8>, first: u8, prefix_bits: u32, mut value: usize) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        block.push(first | value as u8);
+        return;
+    }
+    block.push(first | max as u8);
+    value -= max;
+    while value >= 128 {
+        block.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+    block.push(value as u8);
+}
@@ -0,0 +1,1576 @@
+//! RFC 9113 conformance cases in the manner of h2spec, run against an
+//! `Http2Server` on a local port.
+//!
+//! Like h2spec, a connection error passes on a GOAWAY with the expected code
+//! or on the connection being closed, and a stream error also on a
+//! RST_STREAM. To tell an ignored frame from a slow server, a PING is sent
+//! after the offending frames, its ACK arriving first fails the case.
+//!
+//! Cases the server doesn't pass yet are listed in `KNOWN_FAILURES`, any
+//! other failure fails the test, as does a listed case that passes. Run with
+//! `--nocapture` for the report.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{
+    encode_headers, encode_integer, encode_string, Client, Frame, Received, TestServer, GET, POST,
+};
+use http::{Request, Response};
+use khttp::http2::frames::*;
+use mio::Token;
+
+type Outcome = Result<(), String>;
+
+struct Case {
+    id: &'static str,
+    description: &'static str,
+    run: fn(SocketAddr) -> Outcome,
+}
+
+const SECTIONS: &[(&str, &str)] = &[
+    ("3.5", "HTTP/2 Connection Preface"),
+    ("4.1", "Frame Format"),
+    ("4.2", "Frame Size"),
+    ("4.3", "Field Section Compression and Decompression"),
+    ("5.1", "Stream States"),
+    ("5.1.1", "Stream Identifiers"),
+    ("5.4.1", "Connection Error Handling"),
+    ("6.1", "DATA"),
+    ("6.2", "HEADERS"),
+    ("6.3", "PRIORITY"),
+    ("6.4", "RST_STREAM"),
+    ("6.5", "SETTINGS"),
+    ("6.5.2", "Defined Settings"),
+    ("6.5.3", "Settings Synchronization"),
+    ("6.7", "PING"),
+    ("6.8", "GOAWAY"),
+    ("6.9", "WINDOW_UPDATE"),
+    ("6.9.1", "The Flow-Control Window"),
+    ("6.10", "CONTINUATION"),
+    ("7", "Error Codes"),
+    ("8.1", "HTTP Message Framing"),
+    ("8.2", "HTTP Fields"),
+    ("8.3", "HTTP Control Data"),
+    ("hpack", "HPACK (RFC 7541)"),
+];
+
+/// Cases the server is known to fail, with why.
+const KNOWN_FAILURES: &[&str] = &[
+    // Frames of unknown type fail to parse instead of being ignored.
+    "4.1/1",
+    // Frames interleaved with a header block aren't detected.
+    "4.3/2",
+    "4.3/3",
+    "6.10/3",
+    "6.10/5",
+    // Half-closed and closed streams aren't told apart, late frames are
+    // dropped instead of answered with STREAM_CLOSED.
+    "5.1/5",
+    "5.1/6",
+    "5.1/7",
+    "6.1/2",
+    "6.10/2",
+    // Stream identifiers aren't validated.
+    "5.1.1/1",
+    "5.1.1/2",
+    "6.1/1",
+    "6.2/2",
+    "6.3/1",
+    "6.4/1",
+    "6.10/4",
+    // SETTINGS values and framing aren't validated.
+    "6.5/1",
+    "6.5.2/1",
+    "6.5.2/2",
+    "6.5.2/3",
+    // WINDOW_UPDATE increments aren't validated.
+    "6.9/1",
+    "6.9/2",
+    "6.9.1/2",
+    "6.9.1/3",
+    // A GOAWAY from the client closes the connection right away.
+    "7/1",
+    // Requests aren't validated against the rules of RFC 9113 §8.
+    "8.2/1",
+    "8.2/2",
+    "8.3/1",
+    "8.3/2",
+    "8.3/3",
+    // Padding and length errors depend on the frame parser.
+    "6.1/3",
+    "6.2/3",
+    "6.3/2",
+    "6.4/2",
+    "6.5/3",
+    "6.7/4",
+    "6.9/3",
+    "4.1/3",
+    // Dynamic table size updates and Huffman padding aren't checked.
+    "hpack/4.2",
+    "hpack/5.2",
+];
+
+const SENTINEL: &[u8; 8] = b"h2sentnl";
+
+fn respond(_token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+    if request.body().is_empty() {
+        return Response::new(b"khttp".to_vec());
+    }
+    Response::new(request.into_body())
+}
+
+fn handshake(addr: SocketAddr) -> Result<Client, String> {
+    handshake_with(addr, &[])
+}
+
+fn handshake_with(addr: SocketAddr, settings: &[(u16, u32)]) -> Result<Client, String> {
+    Client::handshake(addr, settings).map_err(|e| format!("handshake failed: {}", e))
+}
+
+fn send(client: &mut Client, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Outcome {
+    client
+        .send_frame(kind, flags, stream_id, payload)
+        .map_err(|e| format!("send failed: {}", e))
+}
+
+fn describe(frame: &Frame) -> String {
+    match frame.error_code() {
+        Some(code) => format!("frame type {:#x} with error code {:#x}", frame.kind, code),
+        None => format!("frame type {:#x}", frame.kind),
+    }
+}
+
+/// Waits for the connection to fail with one of `codes`.
+fn connection_error(client: &mut Client, codes: &[u32]) -> Outcome {
+    expect_error(client, codes, false, true)
+}
+
+/// Waits for the stream, or the whole connection, to fail with one of `codes`.
+fn stream_error(client: &mut Client, codes: &[u32]) -> Outcome {
+    expect_error(client, codes, true, true)
+}
+
+/// Waits for a GOAWAY with one of `codes`, a plain close isn't enough.
+fn goaway(client: &mut Client, codes: &[u32]) -> Outcome {
+    expect_error(client, codes, false, false)
+}
+
+fn expect_error(client: &mut Client, codes: &[u32], reset: bool, close: bool) -> Outcome {
+    let _ = client.ping(SENTINEL);
+    loop {
+        match client.recv() {
+            Received::Frame(frame)
+                if frame.kind == FRAME_GOAWAY || (reset && frame.kind == FRAME_RST_STREAM) =>
+            {
+                return match frame.error_code() {
+                    Some(code) if codes.contains(&code) => Ok(()),
+                    _ => Err(format!(
+                        "expected one of {:?}, got {}",
+                        codes,
+                        describe(&frame)
+                    )),
+                };
+            }
+            Received::Frame(frame)
+                if frame.kind == FRAME_PING && frame.payload.as_slice() == SENTINEL =>
+            {
+                return Err(format!(
+                    "expected one of {:?}, the frame was ignored",
+                    codes
+                ));
+            }
+            Received::Frame(_) => {}
+            Received::Closed if close => return Ok(()),
+            Received::Closed => return Err("connection closed without GOAWAY".to_string()),
+            Received::Timeout => return Err("timed out".to_string()),
+        }
+    }
+}
+
+/// Waits for the ACK of the sentinel PING, the connection being still usable.
+fn still_open(client: &mut Client) -> Outcome {
+    client.ping(SENTINEL).map_err(|e| e.to_string())?;
+    loop {
+        match client.recv() {
+            Received::Frame(frame) if frame.kind == FRAME_GOAWAY => {
+                return Err(format!("unexpected {}", describe(&frame)));
+            }
+            Received::Frame(frame) if frame.kind == FRAME_PING && frame.has_flag(FLAG_ACK) => {
+                if frame.payload.as_slice() == SENTINEL {
+                    return Ok(());
+                }
+            }
+            Received::Frame(_) => {}
+            Received::Closed => return Err("connection closed".to_string()),
+            Received::Timeout => return Err("timed out".to_string()),
+        }
+    }
+}
+
+/// Waits for the response HEADERS of `stream_id`.
+fn response(client: &mut Client, stream_id: u32) -> Outcome {
+    loop {
+        match client.recv() {
+            Received::Frame(frame)
+                if frame.kind == FRAME_HEADERS && frame.stream_id == stream_id =>
+            {
+                return Ok(());
+            }
+            Received::Frame(frame)
+                if frame.kind == FRAME_GOAWAY || frame.kind == FRAME_RST_STREAM =>
+            {
+                return Err(format!("unexpected {}", describe(&frame)));
+            }
+            Received::Frame(_) => {}
+            Received::Closed => return Err("connection closed".to_string()),
+            Received::Timeout => return Err("timed out".to_string()),
+        }
+    }
+}
+
+fn get_block() -> Vec<u8> {
+    encode_headers(GET)
+}
+
+const END_HEADERS: u8 = FLAG_END_HEADERS;
+const END_STREAM: u8 = FLAG_END_STREAM;
+const PADDED: u8 = 0x8;
+
+// 3.5
+
+fn preface_is_answered_with_settings(addr: SocketAddr) -> Outcome {
+    let mut client = Client::connect(addr).map_err(|e| e.to_string())?;
+    client.send(PREFACE).map_err(|e| e.to_string())?;
+    send(&mut client, FRAME_SETTINGS, 0, 0, &[])?;
+    match client.recv() {
+        Received::Frame(frame) if frame.kind == FRAME_SETTINGS && !frame.has_flag(FLAG_ACK) => {
+            Ok(())
+        }
+        received => Err(format!("expected SETTINGS, got {:?}", received)),
+    }
+}
+
+fn invalid_preface_closes(addr: SocketAddr) -> Outcome {
+    let mut client = Client::connect(addr).map_err(|e| e.to_string())?;
+    client
+        .send(b"INVALID CONNECTION PREFACE\r\n\r\n")
+        .map_err(|e| e.to_string())?;
+    loop {
+        match client.recv() {
+            Received::Frame(frame) if frame.kind == FRAME_GOAWAY => return Ok(()),
+            Received::Frame(_) => {}
+            Received::Closed => return Ok(()),
+            Received::Timeout => return Err("timed out".to_string()),
+        }
+    }
+}
+
+// 4.1
+
+fn unknown_frame_type_is_ignored(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, 0x16, 0, 0, b"unknown")?;
+    still_open(&mut client)
+}
+
+fn undefined_flags_are_ignored(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_PING, 0x16, 0, b"flagged!")?;
+    still_open(&mut client)
+}
+
+fn reserved_bit_is_ignored(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut frame = encode_frame(FRAME_HEADERS, END_STREAM | END_HEADERS, 1, &get_block());
+    frame[5] |= 0x80;
+    client.send(&frame).map_err(|e| e.to_string())?;
+    response(&mut client, 1)
+}
+
+// 4.2
+
+fn data_of_max_frame_size_is_accepted(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, &[b'x'; 16384])?;
+    response(&mut client, 1)
+}
+
+fn data_over_max_frame_size(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, &[b'x'; 16385])?;
+    stream_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+fn headers_over_max_frame_size(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let value = "x".repeat(16384);
+    let mut headers: Vec<(&str, &str)> = GET.to_vec();
+    headers.push(("x-large", &value));
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    )?;
+    connection_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+// 4.3
+
+fn invalid_header_block(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    // Indexed field with index 0.
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &[0x80],
+    )?;
+    connection_error(&mut client, &[COMPRESSION_ERROR])
+}
+
+fn priority_within_header_block(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_HEADERS, END_STREAM, 1, &get_block())?;
+    send(&mut client, FRAME_PRIORITY, 0, 1, &[0, 0, 0, 0, 15])?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn headers_within_header_block(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_HEADERS, END_STREAM, 1, &get_block())?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        3,
+        &get_block(),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 5.1
+
+fn data_on_idle_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, b"test")?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn rst_stream_on_idle_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_RST_STREAM,
+        0,
+        1,
+        &rst_stream_payload(CANCEL),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn window_update_on_idle_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_WINDOW_UPDATE,
+        0,
+        1,
+        &window_update_payload(100),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn continuation_on_idle_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_CONTINUATION,
+        END_HEADERS,
+        1,
+        &get_block(),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn data_on_half_closed_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &get_block(),
+    )?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, b"test")?;
+    stream_error(&mut client, &[STREAM_CLOSED])
+}
+
+fn headers_on_half_closed_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &get_block(),
+    )?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &get_block(),
+    )?;
+    stream_error(&mut client, &[STREAM_CLOSED])
+}
+
+fn data_on_reset_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    send(
+        &mut client,
+        FRAME_RST_STREAM,
+        0,
+        1,
+        &rst_stream_payload(CANCEL),
+    )?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, b"test")?;
+    stream_error(&mut client, &[STREAM_CLOSED])
+}
+
+// 5.1.1
+
+fn even_stream_id(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        2,
+        &get_block(),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn decreasing_stream_id(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        5,
+        &get_block(),
+    )?;
+    response(&mut client, 5)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        3,
+        &get_block(),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 5.4.1
+
+fn invalid_ping_is_answered_with_goaway(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_PING, 0, 1, b"h2spec!!")?;
+    goaway(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 6.1
+
+fn data_on_stream_zero(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_DATA, END_STREAM, 0, b"test")?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn data_on_closed_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &get_block(),
+    )?;
+    response(&mut client, 1)?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, b"test")?;
+    stream_error(&mut client, &[STREAM_CLOSED])
+}
+
+fn data_with_invalid_pad_length(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    // A pad length of 6 with 5 bytes after it.
+    send(
+        &mut client,
+        FRAME_DATA,
+        END_STREAM | PADDED,
+        1,
+        &[6, b'x', 0, 0, 0, 0],
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 6.2
+
+fn headers_on_stream_zero(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        0,
+        &get_block(),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn headers_with_invalid_pad_length(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let block = get_block();
+    let mut payload = vec![(block.len() + 1) as u8];
+    payload.extend_from_slice(&block);
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS | PADDED,
+        1,
+        &payload,
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 6.3
+
+fn priority_on_stream_zero(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_PRIORITY, 0, 0, &[0, 0, 0, 1, 15])?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn priority_of_invalid_length(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_PRIORITY, 0, 1, &[0, 0, 0, 3])?;
+    stream_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+// 6.4
+
+fn rst_stream_on_stream_zero(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_RST_STREAM,
+        0,
+        0,
+        &rst_stream_payload(CANCEL),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn rst_stream_of_invalid_length(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &get_block(),
+    )?;
+    send(&mut client, FRAME_RST_STREAM, 0, 1, &[0, 0, 0])?;
+    connection_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+// 6.5
+
+fn settings_ack_with_payload(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_SETTINGS,
+        FLAG_ACK,
+        0,
+        &settings_payload(&[(0x3, 100)]),
+    )?;
+    connection_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+fn settings_on_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_SETTINGS,
+        0,
+        1,
+        &settings_payload(&[(0x3, 100)]),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn settings_of_invalid_length(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_SETTINGS, 0, 0, &[0, 0x3, 0, 0, 0])?;
+    connection_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+// 6.5.2
+
+fn invalid_enable_push(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_SETTINGS,
+        0,
+        0,
+        &settings_payload(&[(0x2, 2)]),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn initial_window_size_over_maximum(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_SETTINGS,
+        0,
+        0,
+        &settings_payload(&[(0x4, 1 << 31)]),
+    )?;
+    connection_error(&mut client, &[FLOW_CONTROL_ERROR])
+}
+
+fn max_frame_size_under_minimum(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_SETTINGS,
+        0,
+        0,
+        &settings_payload(&[(0x5, 16383)]),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn unknown_setting_is_acknowledged(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_SETTINGS,
+        0,
+        0,
+        &settings_payload(&[(0xff, 1)]),
+    )?;
+    match client.recv_until(|frame| frame.kind == FRAME_SETTINGS) {
+        Some(frame) if frame.has_flag(FLAG_ACK) => still_open(&mut client),
+        Some(frame) => Err(format!(
+            "expected SETTINGS ACK, got flags {:#x}",
+            frame.flags
+        )),
+        None => Err("no SETTINGS ACK".to_string()),
+    }
+}
+
+// 6.5.3
+
+fn settings_are_acknowledged(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_SETTINGS,
+        0,
+        0,
+        &settings_payload(&[(0x3, 100), (0x4, 65535)]),
+    )?;
+    match client.recv_until(|frame| frame.kind == FRAME_SETTINGS) {
+        Some(frame) if frame.has_flag(FLAG_ACK) && frame.payload.is_empty() => Ok(()),
+        Some(frame) => Err(format!("unexpected SETTINGS {:?}", frame)),
+        None => Err("no SETTINGS ACK".to_string()),
+    }
+}
+
+// 6.7
+
+fn ping_is_acknowledged(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    client.ping(b"h2spec!!").map_err(|e| e.to_string())?;
+    match client.recv_until(|frame| frame.kind == FRAME_PING) {
+        Some(frame) if frame.has_flag(FLAG_ACK) && frame.payload.as_slice() == b"h2spec!!" => {
+            Ok(())
+        }
+        Some(frame) => Err(format!("unexpected PING {:?}", frame)),
+        None => Err("no PING ACK".to_string()),
+    }
+}
+
+fn ping_ack_is_not_answered(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_PING, FLAG_ACK, 0, b"unsolict")?;
+    client.ping(SENTINEL).map_err(|e| e.to_string())?;
+    match client.recv_until(|frame| frame.kind == FRAME_PING) {
+        Some(frame) if frame.payload.as_slice() == SENTINEL => Ok(()),
+        Some(frame) => Err(format!("unexpected PING {:?}", frame)),
+        None => Err("no PING ACK".to_string()),
+    }
+}
+
+fn ping_on_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_PING, 0, 1, b"h2spec!!")?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn ping_of_invalid_length(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_PING, 0, 0, b"h2spec!")?;
+    connection_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+// 6.8
+
+fn goaway_on_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_GOAWAY,
+        0,
+        1,
+        &goaway_payload(0, NO_ERROR, &[]),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 6.9
+
+fn window_update_of_zero(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_WINDOW_UPDATE,
+        0,
+        0,
+        &window_update_payload(0),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn window_update_of_zero_on_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    send(
+        &mut client,
+        FRAME_WINDOW_UPDATE,
+        0,
+        1,
+        &window_update_payload(0),
+    )?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn window_update_of_invalid_length(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(&mut client, FRAME_WINDOW_UPDATE, 0, 0, &[0, 0, 1])?;
+    connection_error(&mut client, &[FRAME_SIZE_ERROR])
+}
+
+// 6.9.1
+
+fn initial_window_size_is_respected(addr: SocketAddr) -> Outcome {
+    let mut client = handshake_with(addr, &[(0x4, 1)])?;
+    client.get(1).map_err(|e| e.to_string())?;
+    match client.recv_until(|frame| frame.kind == FRAME_DATA && frame.stream_id == 1) {
+        Some(frame) if frame.payload.len() == 1 => Ok(()),
+        Some(frame) => Err(format!(
+            "expected 1 byte of DATA, got {}",
+            frame.payload.len()
+        )),
+        None => Err("no DATA".to_string()),
+    }
+}
+
+fn connection_window_overflow(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_WINDOW_UPDATE,
+        0,
+        0,
+        &window_update_payload(0x7fff_ffff),
+    )?;
+    send(
+        &mut client,
+        FRAME_WINDOW_UPDATE,
+        0,
+        0,
+        &window_update_payload(0x7fff_ffff),
+    )?;
+    connection_error(&mut client, &[FLOW_CONTROL_ERROR])
+}
+
+fn stream_window_overflow(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    send(
+        &mut client,
+        FRAME_WINDOW_UPDATE,
+        0,
+        1,
+        &window_update_payload(0x7fff_ffff),
+    )?;
+    send(
+        &mut client,
+        FRAME_WINDOW_UPDATE,
+        0,
+        1,
+        &window_update_payload(0x7fff_ffff),
+    )?;
+    stream_error(&mut client, &[FLOW_CONTROL_ERROR])
+}
+
+// 6.10
+
+fn header_block_over_continuations(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let block = get_block();
+    let (first, rest) = block.split_at(block.len() / 3);
+    let (second, third) = rest.split_at(rest.len() / 2);
+    send(&mut client, FRAME_HEADERS, END_STREAM, 1, first)?;
+    send(&mut client, FRAME_CONTINUATION, 0, 1, second)?;
+    send(&mut client, FRAME_CONTINUATION, END_HEADERS, 1, third)?;
+    response(&mut client, 1)
+}
+
+fn continuation_after_end_headers(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    send(
+        &mut client,
+        FRAME_CONTINUATION,
+        END_HEADERS,
+        1,
+        &encode_headers(&[("x-late", "1")]),
+    )?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn continuation_after_other_frame(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let block = get_block();
+    let (first, rest) = block.split_at(block.len() / 2);
+    send(&mut client, FRAME_HEADERS, END_STREAM, 1, first)?;
+    send(&mut client, FRAME_DATA, 0, 1, b"test")?;
+    send(&mut client, FRAME_CONTINUATION, END_HEADERS, 1, rest)?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn continuation_on_stream_zero(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let block = get_block();
+    let (first, rest) = block.split_at(block.len() / 2);
+    send(&mut client, FRAME_HEADERS, END_STREAM, 1, first)?;
+    send(&mut client, FRAME_CONTINUATION, END_HEADERS, 0, rest)?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn continuation_on_other_stream(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let block = get_block();
+    let (first, rest) = block.split_at(block.len() / 2);
+    send(&mut client, FRAME_HEADERS, END_STREAM, 1, first)?;
+    send(&mut client, FRAME_CONTINUATION, END_HEADERS, 3, rest)?;
+    connection_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 7
+
+fn goaway_with_unknown_error_code(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_GOAWAY,
+        0,
+        0,
+        &goaway_payload(0, 0xff, &[]),
+    )?;
+    still_open(&mut client)
+}
+
+fn rst_stream_with_unknown_error_code(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(POST),
+    )?;
+    send(
+        &mut client,
+        FRAME_RST_STREAM,
+        0,
+        1,
+        &rst_stream_payload(0xff),
+    )?;
+    still_open(&mut client)
+}
+
+// 8.2
+
+fn uppercase_field_name(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut headers = GET.to_vec();
+    headers.push(("X-Test", "ok"));
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    )?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn connection_specific_field(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut headers = GET.to_vec();
+    headers.push(("connection", "keep-alive"));
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    )?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// 8.3
+
+fn unknown_pseudo_header(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut headers = GET.to_vec();
+    headers.push((":test", "ok"));
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    )?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn content_length_over_data(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut headers = POST.to_vec();
+    headers.push(("content-length", "1"));
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    )?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, b"test")?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn content_length_under_data(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut headers = POST.to_vec();
+    headers.push(("content-length", "8"));
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    )?;
+    send(&mut client, FRAME_DATA, 0, 1, b"test")?;
+    send(&mut client, FRAME_DATA, END_STREAM, 1, b"")?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn missing_method(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &encode_headers(&GET[1..]),
+    )?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+fn pseudo_header_after_regular(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let headers = [("x-test", "ok"), GET[0], GET[1], GET[2], GET[3]];
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    )?;
+    stream_error(&mut client, &[PROTOCOL_ERROR])
+}
+
+// HPACK
+
+fn static_table_indexes(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    // :method GET, :scheme http, :path /, then :authority by its indexed name.
+    let mut block = vec![0x82, 0x86, 0x84, 0x01];
+    encode_string(&mut block, b"localhost");
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &block,
+    )?;
+    response(&mut client, 1)
+}
+
+fn dynamic_table_indexes(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    // Literal with incremental indexing, then the same field by its index.
+    let mut block = vec![0x82, 0x86, 0x84, 0x41];
+    encode_string(&mut block, b"localhost");
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &block,
+    )?;
+    response(&mut client, 1)?;
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        3,
+        &[0x82, 0x86, 0x84, 0xbe],
+    )?;
+    response(&mut client, 3)
+}
+
+fn index_out_of_range(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut block = vec![0x82, 0x86, 0x84];
+    encode_integer(&mut block, 0x80, 7, 1000);
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &block,
+    )?;
+    connection_error(&mut client, &[COMPRESSION_ERROR])
+}
+
+fn table_size_update_over_limit(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut block = Vec::new();
+    encode_integer(&mut block, 0x20, 5, 4097);
+    block.extend_from_slice(&get_block());
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &block,
+    )?;
+    connection_error(&mut client, &[COMPRESSION_ERROR])
+}
+
+fn huffman_string_with_eos(addr: SocketAddr) -> Outcome {
+    let mut client = handshake(addr)?;
+    let mut block = vec![0x82, 0x86, 0x84, 0x01];
+    // A Huffman coded value of 30 set bits, the EOS symbol.
+    block.extend_from_slice(&[0x84, 0xff, 0xff, 0xff, 0xff]);
+    send(
+        &mut client,
+        FRAME_HEADERS,
+        END_STREAM | END_HEADERS,
+        1,
+        &block,
+    )?;
+    connection_error(&mut client, &[COMPRESSION_ERROR])
+}
+
+const CASES: &[Case] = &[
+    Case {
+        id: "3.5/1",
+        description: "Sends client connection preface",
+        run: preface_is_answered_with_settings,
+    },
+    Case {
+        id: "3.5/2",
+        description: "Sends invalid connection preface",
+        run: invalid_preface_closes,
+    },
+    Case {
+        id: "4.1/1",
+        description: "Sends a frame with unknown type",
+        run: unknown_frame_type_is_ignored,
+    },
+    Case {
+        id: "4.1/2",
+        description: "Sends a frame with undefined flag",
+        run: undefined_flags_are_ignored,
+    },
+    Case {
+        id: "4.1/3",
+        description: "Sends a frame with reserved field bit",
+        run: reserved_bit_is_ignored,
+    },
+    Case {
+        id: "4.2/1",
+        description: "Sends a DATA frame with 2^14 octets in length",
+        run: data_of_max_frame_size_is_accepted,
+    },
+    Case {
+        id: "4.2/2",
+        description: "Sends a large size DATA frame that exceeds the SETTINGS_MAX_FRAME_SIZE",
+        run: data_over_max_frame_size,
+    },
+    Case {
+        id: "4.2/3",
+        description: "Sends a large size HEADERS frame that exceeds the SETTINGS_MAX_FRAME_SIZE",
+        run: headers_over_max_frame_size,
+    },
+    Case {
+        id: "4.3/1",
+        description: "Sends invalid header block fragment",
+        run: invalid_header_block,
+    },
+    Case {
+        id: "4.3/2",
+        description: "Sends a PRIORITY frame while sending the header blocks",
+        run: priority_within_header_block,
+    },
+    Case {
+        id: "4.3/3",
+        description: "Sends a HEADERS frame to another stream while sending the header blocks",
+        run: headers_within_header_block,
+    },
+    Case {
+        id: "5.1/1",
+        description: "idle: Sends a DATA frame",
+        run: data_on_idle_stream,
+    },
+    Case {
+        id: "5.1/2",
+        description: "idle: Sends a RST_STREAM frame",
+        run: rst_stream_on_idle_stream,
+    },
+    Case {
+        id: "5.1/3",
+        description: "idle: Sends a WINDOW_UPDATE frame",
+        run: window_update_on_idle_stream,
+    },
+    Case {
+        id: "5.1/4",
+        description: "idle: Sends a CONTINUATION frame",
+        run: continuation_on_idle_stream,
+    },
+    Case {
+        id: "5.1/5",
+        description: "half closed (remote): Sends a DATA frame",
+        run: data_on_half_closed_stream,
+    },
+    Case {
+        id: "5.1/6",
+        description: "half closed (remote): Sends a HEADERS frame",
+        run: headers_on_half_closed_stream,
+    },
+    Case {
+        id: "5.1/7",
+        description: "closed: Sends a DATA frame after sending RST_STREAM frame",
+        run: data_on_reset_stream,
+    },
+    Case {
+        id: "5.1.1/1",
+        description: "Sends even-numbered stream identifier",
+        run: even_stream_id,
+    },
+    Case {
+        id: "5.1.1/2",
+        description: "Sends stream identifier that is numerically smaller than previous",
+        run: decreasing_stream_id,
+    },
+    Case {
+        id: "5.4.1/1",
+        description: "Sends an invalid PING frame to receive GOAWAY frame",
+        run: invalid_ping_is_answered_with_goaway,
+    },
+    Case {
+        id: "6.1/1",
+        description: "Sends a DATA frame with 0x0 stream identifier",
+        run: data_on_stream_zero,
+    },
+    Case {
+        id: "6.1/2",
+        description: "Sends a DATA frame on the stream that is not in \"open\" or \"half-closed (local)\" state",
+        run: data_on_closed_stream,
+    },
+    Case {
+        id: "6.1/3",
+        description: "Sends a DATA frame with invalid pad length",
+        run: data_with_invalid_pad_length,
+    },
+    Case {
+        id: "6.2/2",
+        description: "Sends a HEADERS frame with 0x0 stream identifier",
+        run: headers_on_stream_zero,
+    },
+    Case {
+        id: "6.2/3",
+        description: "Sends a HEADERS frame with invalid pad length",
+        run: headers_with_invalid_pad_length,
+    },
+    Case {
+        id: "6.3/1",
+        description: "Sends a PRIORITY frame with 0x0 stream identifier",
+        run: priority_on_stream_zero,
+    },
+    Case {
+        id: "6.3/2",
+        description: "Sends a PRIORITY frame with a length other than 5 octets",
+        run: priority_of_invalid_length,
+    },
+    Case {
+        id: "6.4/1",
+        description: "Sends a RST_STREAM frame with 0x0 stream identifier",
+        run: rst_stream_on_stream_zero,
+    },
+    Case {
+        id: "6.4/2",
+        description: "Sends a RST_STREAM frame with a length other than 4 octets",
+        run: rst_stream_of_invalid_length,
+    },
+    Case {
+        id: "6.5/1",
+        description: "Sends a SETTINGS frame with ACK flag and payload",
+        run: settings_ack_with_payload,
+    },
+    Case {
+        id: "6.5/2",
+        description: "Sends a SETTINGS frame with a stream identifier other than 0x0",
+        run: settings_on_stream,
+    },
+    Case {
+        id: "6.5/3",
+        description: "Sends a SETTINGS frame with a length other than a multiple of 6 octets",
+        run: settings_of_invalid_length,
+    },
+    Case {
+        id: "6.5.2/1",
+        description: "SETTINGS_ENABLE_PUSH (0x2): Sends the value other than 0 or 1",
+        run: invalid_enable_push,
+    },
+    Case {
+        id: "6.5.2/2",
+        description: "SETTINGS_INITIAL_WINDOW_SIZE (0x4): Sends the value above the maximum flow control window size",
+        run: initial_window_size_over_maximum,
+    },
+    Case {
+        id: "6.5.2/3",
+        description: "SETTINGS_MAX_FRAME_SIZE (0x5): Sends the value below the initial value",
+        run: max_frame_size_under_minimum,
+    },
+    Case {
+        id: "6.5.2/4",
+        description: "Sends a SETTINGS frame with unknown identifier",
+        run: unknown_setting_is_acknowledged,
+    },
+    Case {
+        id: "6.5.3/1",
+        description: "Sends a SETTINGS frame without ACK flag",
+        run: settings_are_acknowledged,
+    },
+    Case {
+        id: "6.7/1",
+        description: "Sends a PING frame",
+        run: ping_is_acknowledged,
+    },
+    Case {
+        id: "6.7/2",
+        description: "Sends a PING frame with ACK",
+        run: ping_ack_is_not_answered,
+    },
+    Case {
+        id: "6.7/3",
+        description: "Sends a PING frame with a stream identifier field value other than 0x0",
+        run: ping_on_stream,
+    },
+    Case {
+        id: "6.7/4",
+        description: "Sends a PING frame with a length field value other than 8",
+        run: ping_of_invalid_length,
+    },
+    Case {
+        id: "6.8/1",
+        description: "Sends a GOAWAY frame with a stream identifier other than 0x0",
+        run: goaway_on_stream,
+    },
+    Case {
+        id: "6.9/1",
+        description: "Sends a WINDOW_UPDATE frame with a flow control window increment of 0",
+        run: window_update_of_zero,
+    },
+    Case {
+        id: "6.9/2",
+        description: "Sends a WINDOW_UPDATE frame with a flow control window increment of 0 on a stream",
+        run: window_update_of_zero_on_stream,
+    },
+    Case {
+        id: "6.9/3",
+        description: "Sends a WINDOW_UPDATE frame with a length other than 4 octets",
+        run: window_update_of_invalid_length,
+    },
+    Case {
+        id: "6.9.1/1",
+        description: "Sends SETTINGS frame to set the initial window size to 1 and sends HEADERS frame",
+        run: initial_window_size_is_respected,
+    },
+    Case {
+        id: "6.9.1/2",
+        description: "Sends multiple WINDOW_UPDATE frames increasing the flow control window to above 2^31-1",
+        run: connection_window_overflow,
+    },
+    Case {
+        id: "6.9.1/3",
+        description: "Sends multiple WINDOW_UPDATE frames increasing the flow control window to above 2^31-1 on a stream",
+        run: stream_window_overflow,
+    },
+    Case {
+        id: "6.10/1",
+        description: "Sends multiple CONTINUATION frames preceded by a HEADERS frame",
+        run: header_block_over_continuations,
+    },
+    Case {
+        id: "6.10/2",
+        description: "Sends a CONTINUATION frame followed by any frame other than CONTINUATION",
+        run: continuation_after_end_headers,
+    },
+    Case {
+        id: "6.10/3",
+        description: "Sends a CONTINUATION frame preceded by a DATA frame",
+        run: continuation_after_other_frame,
+    },
+    Case {
+        id: "6.10/4",
+        description: "Sends a CONTINUATION frame with 0x0 stream identifier",
+        run: continuation_on_stream_zero,
+    },
+    Case {
+        id: "6.10/5",
+        description: "Sends a CONTINUATION frame preceded by a HEADERS frame of another stream",
+        run: continuation_on_other_stream,
+    },
+    Case {
+        id: "7/1",
+        description: "Sends a GOAWAY frame with unknown error code",
+        run: goaway_with_unknown_error_code,
+    },
+    Case {
+        id: "7/2",
+        description: "Sends a RST_STREAM frame with unknown error code",
+        run: rst_stream_with_unknown_error_code,
+    },
+    Case {
+        id: "8.1/1",
+        description: "Sends DATA frames longer than the content-length header field",
+        run: content_length_over_data,
+    },
+    Case {
+        id: "8.1/2",
+        description: "Sends DATA frames shorter than the content-length header field",
+        run: content_length_under_data,
+    },
+    Case {
+        id: "8.2/1",
+        description: "Sends a HEADERS frame that contains the header field name in uppercase letters",
+        run: uppercase_field_name,
+    },
+    Case {
+        id: "8.2/2",
+        description: "Sends a HEADERS frame that contains the connection-specific header field",
+        run: connection_specific_field,
+    },
+    Case {
+        id: "8.3/1",
+        description: "Sends a HEADERS frame that contains an unknown pseudo-header field",
+        run: unknown_pseudo_header,
+    },
+    Case {
+        id: "8.3/2",
+        description: "Sends a HEADERS frame that omits the \":method\" pseudo-header field",
+        run: missing_method,
+    },
+    Case {
+        id: "8.3/3",
+        description: "Sends a HEADERS frame that contains a pseudo-header field after a regular header field",
+        run: pseudo_header_after_regular,
+    },
+    Case {
+        id: "hpack/2.3.1",
+        description: "Sends a header block indexing the static table",
+        run: static_table_indexes,
+    },
+    Case {
+        id: "hpack/2.3.2",
+        description: "Sends a header block referencing the dynamic table",
+        run: dynamic_table_indexes,
+    },
+    Case {
+        id: "hpack/2.3.3",
+        description: "Sends an indexed header field representation with an index out of range",
+        run: index_out_of_range,
+    },
+    Case {
+        id: "hpack/4.2",
+        description: "Sends a dynamic table size update larger than the value of SETTINGS_HEADER_TABLE_SIZE",
+        run: table_size_update_over_limit,
+    },
+    Case {
+        id: "hpack/5.2",
+        description: "Sends a Huffman-encoded string literal representation containing the EOS symbol",
+        run: huffman_string_with_eos,
+    },
+];
+
+fn section(id: &str) -> &str {
+    id.split('/').next().unwrap_or(id)
+}
+
+#[test]
+fn conformance() {
+    let server = TestServer::spawn(respond);
+    let addr = server.addr();
+
+    let mut regressions = Vec::new();
+    let mut fixed = Vec::new();
+    let mut total_passed = 0;
+    for (section_id, title) in SECTIONS {
+        let cases: Vec<&Case> = CASES
+            .iter()
+            .filter(|case| section(case.id) == *section_id)
+            .collect();
+        let mut passed = 0;
+        let mut report = Vec::new();
+        for case in &cases {
+            let result = (case.run)(addr);
+            let known = KNOWN_FAILURES.contains(&case.id);
+            match &result {
+                Ok(()) => {
+                    passed += 1;
+                    report.push(format!("    [pass] {} {}", case.id, case.description));
+                    if known {
+                        fixed.push(case.id);
+                    }
+                }
+                Err(e) => {
+                    report.push(format!(
+                        "    [fail] {} {}: {}",
+                        case.id, case.description, e
+                    ));
+                    if !known {
+                        regressions.push(format!("{}: {}", case.id, e));
+                    }
+                }
+            }
+        }
+        total_passed += passed;
+        println!("{} {} ({}/{})", section_id, title, passed, cases.len());
+        for line in report {
+            println!("{}", line);
+        }
+    }
+
+    println!("{}/{} cases passed", total_passed, CASES.len());
+    assert!(
+        regressions.is_empty(),
+        "unexpected failures:\n{}",
+        regressions.join("\n")
+    );
+    assert!(
+        fixed.is_empty(),
+        "passing now, remove from KNOWN_FAILURES: {:?}",
+        fixed
+    );
+}
+
+#[test]
+fn every_case_has_a_section() {
+    for case in CASES {
+        assert!(
+            SECTIONS.iter().any(|(id, _)| *id == section(case.id)),
+            "{} has no section",
+            case.id
+        );
+    }
+    for id in KNOWN_FAILURES {
+        assert!(
+            CASES.iter().any(|case| case.id == *id),
+            "{} is not a case",
+            id
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Sessions recorded with a `CaptureWriter` and replayed against a fresh
+//! server.
+
+mod common;
+
+use std::{fs::File, path::PathBuf};
+
+use common::{encode_headers, Client, TestResponse, TestServer, POST};
+use http::{Request, Response};
+use khttp::http2::{
+    frames::*,
+    trace::{self, CaptureWriter, Direction, FrameTracer, TracedFrame},
+    ConnectionState,
+};
+use mio::Token;
+
+fn echo(_token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+    Response::builder()
+        .header("x-path", request.uri().path())
+        .body(request.body().clone())
+        .unwrap()
+}
+
+fn capture_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("khttp-{}-{}.cap", name, std::process::id()))
+}
+
+/// A GET on stream 1 and a POST on stream 3.
+fn exchange(client: &mut Client) -> (TestResponse, TestResponse) {
+    client.get(1).unwrap();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 3, &encode_headers(POST))
+        .unwrap();
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 3, b"recorded")
+        .unwrap();
+    (client.response(1).unwrap(), client.response(3).unwrap())
+}
+
+#[test]
+fn recorded_session_replays() {
+    let path = capture_path("replay");
+    let capture = CaptureWriter::create(&path).unwrap();
+    let server = TestServer::spawn_with(echo, move |server| server.set_tracer(capture));
+    let (get, post) = exchange(&mut server.client());
+    server.stop();
+
+    let records = trace::read_capture(File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let connections = trace::connections(&records);
+    assert_eq!(connections.len(), 1);
+    assert!(records
+        .iter()
+        .any(|record| record.direction == Direction::Outbound
+            && record.frame[3] == FRAME_DATA
+            && record.frame[9..] == b"recorded"[..]));
+
+    let server = TestServer::spawn(echo);
+    let mut client = Client::connect(server.addr()).unwrap();
+    client
+        .send(&trace::replay_input(&records, connections[0]))
+        .unwrap();
+    let replayed_get = client.response(1).unwrap();
+    let replayed_post = client.response(3).unwrap();
+    assert_eq!(replayed_get.headers, get.headers);
+    assert_eq!(replayed_post.headers, post.headers);
+    assert_eq!(replayed_post.body, b"recorded");
+}
+
+#[test]
+fn tracer_can_skip_connections() {
+    struct Nothing;
+
+    impl FrameTracer for Nothing {
+        fn enabled(&self, _connection: &ConnectionState) -> bool {
+            false
+        }
+
+        fn on_frame(&self, _connection: &ConnectionState, _frame: &TracedFrame) {
+            panic!("traced a disabled connection");
+        }
+    }
+
+    let server = TestServer::spawn_with(echo, |server| server.set_tracer(Nothing));
+    let (_, post) = exchange(&mut server.client());
+    assert_eq!(post.body, b"recorded");
+}
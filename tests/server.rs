@@ -0,0 +1,730 @@
+//! Requests over a real socket to an `Http2Server` on an ephemeral port.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{encode_headers, Client, Received, TestServer, POST, TIMEOUT};
+use http::{Request, Response};
+use khttp::http2::{
+    connection::{CloseReason, ConnectionHooks, ConnectionState, ConnectionStats},
+    frames::*,
+    limits::{BodyLimitAction, BodyLimits, Limits, RateLimit, RateLimitAction},
+    middleware::Middleware,
+    proxy::{ReverseProxy, UpstreamProtocol},
+    transport::PeerCredentials,
+    tunnel::ConnectProxy,
+    Http2Server, Trailers,
+};
+use mio::Token;
+
+/// Echoes the request body, with the method and path as headers.
+fn echo(_token: Token, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+    Response::builder()
+        .header("x-method", request.method().as_str())
+        .header("x-path", request.uri().path())
+        .body(request.body().clone())
+        .unwrap()
+}
+
+#[test]
+fn preface_is_answered_with_settings() {
+    let server = TestServer::spawn(echo);
+    let mut client = Client::connect(server.addr()).unwrap();
+    client.send(PREFACE).unwrap();
+    client
+        .send_frame(FRAME_SETTINGS, 0, 0, &settings_payload(&[(0x3, 100)]))
+        .unwrap();
+    match client.recv() {
+        Received::Frame(frame) => {
+            assert_eq!(frame.kind, FRAME_SETTINGS);
+            assert!(!frame.has_flag(FLAG_ACK));
+            assert_eq!(frame.stream_id, 0);
+        }
+        received => panic!("expected SETTINGS, got {:?}", received),
+    }
+    let ack = client
+        .recv_until(|frame| frame.kind == FRAME_SETTINGS)
+        .unwrap();
+    assert!(ack.has_flag(FLAG_ACK));
+    assert!(ack.payload.is_empty());
+}
+
+#[test]
+fn invalid_preface_closes_the_connection() {
+    let server = TestServer::spawn(echo);
+    let mut client = Client::connect(server.addr()).unwrap();
+    client
+        .send(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    assert!(client
+        .recv_until(|frame| frame.kind == FRAME_HEADERS)
+        .is_none());
+}
+
+#[test]
+fn settings_are_acknowledged() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_SETTINGS, 0, 0, &settings_payload(&[(0x4, 1 << 20)]))
+        .unwrap();
+    let ack = client
+        .recv_until(|frame| frame.kind == FRAME_SETTINGS)
+        .unwrap();
+    assert!(ack.has_flag(FLAG_ACK));
+}
+
+#[test]
+fn ping_is_acknowledged_with_its_data() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client.ping(b"12345678").unwrap();
+    let ack = client.recv_until(|frame| frame.kind == FRAME_PING).unwrap();
+    assert!(ack.has_flag(FLAG_ACK));
+    assert_eq!(ack.payload, b"12345678");
+}
+
+#[test]
+fn get_request_gets_a_response() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client.get(1).unwrap();
+    let response = client.response(1).unwrap();
+    assert_eq!(response.status(), Some(&b"200"[..]));
+    assert_eq!(response.header("x-method"), Some(&b"GET"[..]));
+    assert_eq!(response.header("x-path"), Some(&b"/"[..]));
+    assert!(response.body.is_empty());
+}
+
+#[test]
+fn request_body_is_received_over_data_frames() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &encode_headers(POST))
+        .unwrap();
+    client.send_frame(FRAME_DATA, 0, 1, b"hello ").unwrap();
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"world")
+        .unwrap();
+    let response = client.response(1).unwrap();
+    assert_eq!(response.header("x-method"), Some(&b"POST"[..]));
+    assert_eq!(response.body, b"hello world");
+}
+
+#[test]
+fn request_trailers_reach_the_handler() {
+    let server = TestServer::spawn(|_token, request: Request<Vec<u8>>| {
+        let mut response = Response::builder();
+        if let Some(Trailers(trailers)) = request.extensions().get::<Trailers>() {
+            for (name, value) in trailers {
+                response = response.header(format!("x-trailer-{}", name), value);
+            }
+        }
+        response.body(request.into_body()).unwrap()
+    });
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &encode_headers(POST))
+        .unwrap();
+    client.send_frame(FRAME_DATA, 0, 1, b"hello").unwrap();
+    client
+        .send_frame(
+            FRAME_HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            1,
+            &encode_headers(&[("checksum", "abc")]),
+        )
+        .unwrap();
+    let response = client.response(1).unwrap();
+    assert_eq!(response.header("x-trailer-checksum"), Some(&b"abc"[..]));
+    assert_eq!(response.body, b"hello");
+}
+
+#[test]
+fn response_trailers_follow_the_body() {
+    let server = TestServer::spawn(|_token, _request| {
+        let mut trailers = Trailers::default();
+        trailers.0.insert("checksum", "abc".parse().unwrap());
+        let mut response = Response::new(b"hello".to_vec());
+        response.extensions_mut().insert(trailers);
+        response
+    });
+    let mut client = server.client();
+    client.get(1).unwrap();
+
+    let mut frames = Vec::new();
+    while let Some(frame) = client.recv_until(|frame| frame.stream_id == 1) {
+        let end = frame.has_flag(FLAG_END_STREAM);
+        frames.push(frame);
+        if end {
+            break;
+        }
+    }
+    let kinds: Vec<u8> = frames.iter().map(|frame| frame.kind).collect();
+    assert_eq!(kinds, [FRAME_HEADERS, FRAME_DATA, FRAME_HEADERS]);
+    assert!(!frames[1].has_flag(FLAG_END_STREAM));
+    assert_eq!(frames[1].payload, b"hello");
+    let trailers = client.decode_headers(&frames[2]).unwrap();
+    assert_eq!(trailers, [(b"checksum".to_vec(), b"abc".to_vec())]);
+}
+
+#[test]
+fn received_data_is_credited_back() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &encode_headers(POST))
+        .unwrap();
+    client.send_frame(FRAME_DATA, 0, 1, b"hello").unwrap();
+    let update = client
+        .recv_until(|frame| frame.kind == FRAME_WINDOW_UPDATE && frame.stream_id == 0)
+        .unwrap();
+    assert_eq!(update.payload, window_update_payload(5));
+    let update = client
+        .recv_until(|frame| frame.kind == FRAME_WINDOW_UPDATE && frame.stream_id == 1)
+        .unwrap();
+    assert_eq!(update.payload, window_update_payload(5));
+}
+
+#[test]
+fn streams_are_answered_independently() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &encode_headers(POST))
+        .unwrap();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 3, &encode_headers(POST))
+        .unwrap();
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 3, b"three")
+        .unwrap();
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"one")
+        .unwrap();
+    assert_eq!(client.response(3).unwrap().body, b"three");
+    assert_eq!(client.response(1).unwrap().body, b"one");
+}
+
+#[test]
+fn reset_stream_is_not_answered() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &encode_headers(POST))
+        .unwrap();
+    client
+        .send_frame(FRAME_RST_STREAM, 0, 1, &rst_stream_payload(CANCEL))
+        .unwrap();
+    client.ping(b"after rs").unwrap();
+    let frame = client
+        .recv_until(|frame| frame.stream_id == 1 || frame.kind == FRAME_PING)
+        .unwrap();
+    assert_eq!(frame.kind, FRAME_PING);
+    assert_eq!(frame.payload, b"after rs");
+
+    client.get(3).unwrap();
+    assert_eq!(client.response(3).unwrap().status(), Some(&b"200"[..]));
+}
+
+#[test]
+fn goaway_from_the_client_closes_the_connection() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_GOAWAY, 0, 0, &goaway_payload(0, NO_ERROR, &[]))
+        .unwrap();
+    loop {
+        match client.recv() {
+            Received::Frame(frame) => assert_ne!(frame.kind, FRAME_HEADERS),
+            Received::Closed => break,
+            Received::Timeout => panic!("the connection was left open"),
+        }
+    }
+}
+
+#[test]
+fn shutdown_sends_goaway_and_closes() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    client.get(1).unwrap();
+    client.response(1).unwrap();
+    server.stop();
+
+    let goaway = client
+        .recv_until(|frame| frame.kind == FRAME_GOAWAY)
+        .unwrap();
+    assert_eq!(goaway.error_code(), Some(NO_ERROR));
+    assert_eq!(&goaway.payload[..4], &1u32.to_be_bytes());
+    assert!(matches!(client.recv(), Received::Closed));
+}
+
+#[test]
+fn shutdown_without_connections() {
+    TestServer::spawn(echo).stop();
+}
+
+/// Hooks recording what they were called with.
+#[derive(Clone)]
+struct Recorder {
+    accept: bool,
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl Recorder {
+    fn spawn(accept: bool) -> (TestServer, Self) {
+        let recorder = Self {
+            accept,
+            events: Arc::default(),
+        };
+        let hooks = recorder.clone();
+        let server = TestServer::spawn_with(echo, move |server| server.set_hooks(hooks));
+        (server, recorder)
+    }
+
+    /// The events so far, once there are `count` of them or on timeout.
+    fn events(&self, count: usize) -> Vec<String> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let events = self.events.lock().unwrap().clone();
+            if events.len() >= count || Instant::now() > deadline {
+                return events;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn push(&self, event: String) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl ConnectionHooks for Recorder {
+    fn on_connect(&self, connection: &ConnectionState) -> bool {
+        self.push(format!("connect {}", connection.id()));
+        self.accept
+    }
+
+    fn on_settings(&self, _connection: &ConnectionState, settings: &[(u16, u32)]) {
+        self.push(format!("settings {:?}", settings));
+    }
+
+    fn on_close(
+        &self,
+        connection: &ConnectionState,
+        reason: &CloseReason,
+        stats: &ConnectionStats,
+    ) {
+        self.push(format!(
+            "close {}: {}, {} streams",
+            connection.id(),
+            reason,
+            stats.streams
+        ));
+    }
+}
+
+#[test]
+fn hooks_follow_the_connection() {
+    let (server, recorder) = Recorder::spawn(true);
+    let mut client = Client::handshake(server.addr(), &[(0x3, 100)]).unwrap();
+    client.get(1).unwrap();
+    client.response(1).unwrap();
+    drop(client);
+    assert_eq!(
+        recorder.events(3),
+        [
+            "connect 1",
+            "settings [(3, 100)]",
+            "close 1: closed by the peer, 1 streams"
+        ]
+    );
+}
+
+#[test]
+fn connection_refused_on_connect_gets_a_goaway() {
+    let (server, recorder) = Recorder::spawn(false);
+    let mut client = Client::connect(server.addr()).unwrap();
+    let goaway = client
+        .recv_until(|frame| frame.kind == FRAME_GOAWAY)
+        .unwrap();
+    assert_eq!(&goaway.payload[8..], b"connection refused");
+    assert!(matches!(client.recv(), Received::Closed));
+    assert_eq!(
+        recorder.events(2),
+        ["connect 1", "close 1: refused on connect, 0 streams"]
+    );
+}
+
+#[test]
+fn connection_over_the_limit_gets_a_goaway() {
+    let server = TestServer::spawn_with(echo, |server| {
+        server.set_limits(Limits::new().max_connections(0))
+    });
+    let mut client = Client::connect(server.addr()).unwrap();
+    client.send(PREFACE).unwrap();
+    let goaway = client
+        .recv_until(|frame| frame.kind == FRAME_GOAWAY)
+        .unwrap();
+    assert_eq!(goaway.error_code(), Some(NO_ERROR));
+    assert_eq!(&goaway.payload[8..], b"connection limit reached");
+}
+
+#[test]
+fn stream_rate_limit_applies_before_the_body() {
+    let server = TestServer::spawn_with(echo, |server| {
+        server.set_limits(
+            Limits::new()
+                .streams_per_connection(RateLimit::new(0.0, 1.0))
+                .action(RateLimitAction::TooManyRequests),
+        )
+    });
+    let mut client = server.client();
+    client.get(1).unwrap();
+    client.response(1).unwrap();
+    post(&mut client, 3, "/", None);
+    assert_eq!(
+        rejection(&mut client, 3),
+        (Some(b"429".to_vec()), Some(NO_ERROR))
+    );
+}
+
+fn limited(body: BodyLimits) -> TestServer {
+    TestServer::spawn_with(echo, |server| server.set_limits(Limits::new().body(body)))
+}
+
+/// Sends the headers of a POST to `path`, the body left to follow.
+fn post(client: &mut Client, stream_id: u32, path: &str, content_length: Option<&str>) {
+    let mut headers = POST.to_vec();
+    headers.retain(|(name, _)| *name != ":path");
+    headers.push((":path", path));
+    if let Some(len) = content_length {
+        headers.push(("content-length", len));
+    }
+    client
+        .send_frame(
+            FRAME_HEADERS,
+            FLAG_END_HEADERS,
+            stream_id,
+            &encode_headers(&headers),
+        )
+        .unwrap();
+}
+
+/// The status of the response to `stream_id` followed by the error code of
+/// the RST_STREAM ending it, if any. A PING sent first tells when the server
+/// is done with what was sent before.
+fn rejection(client: &mut Client, stream_id: u32) -> (Option<Vec<u8>>, Option<u32>) {
+    client.ping(b"rejected").unwrap();
+    let mut status = None;
+    loop {
+        let frame = client
+            .recv_until(|frame| frame.stream_id == stream_id || frame.kind == FRAME_PING)
+            .unwrap();
+        match frame.kind {
+            FRAME_HEADERS => {
+                let headers = client.decode_headers(&frame).unwrap();
+                status = headers
+                    .into_iter()
+                    .find(|(name, _)| name == b":status")
+                    .map(|(_, value)| value);
+            }
+            FRAME_RST_STREAM => return (status, frame.error_code()),
+            FRAME_PING => return (status, None),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn content_length_over_the_limit_is_rejected_before_the_body() {
+    let server = limited(BodyLimits::new().max(10));
+    let mut client = server.client();
+    post(&mut client, 1, "/", Some("100"));
+    assert_eq!(
+        rejection(&mut client, 1),
+        (Some(b"413".to_vec()), Some(NO_ERROR))
+    );
+
+    // The body sent anyway is dropped and the connection stays usable.
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, &[0; 100])
+        .unwrap();
+    client.get(3).unwrap();
+    assert_eq!(client.response(3).unwrap().status(), Some(&b"200"[..]));
+}
+
+#[test]
+fn body_over_the_limit_is_rejected_mid_upload() {
+    let server = limited(BodyLimits::new().max(10));
+    let mut client = server.client();
+    post(&mut client, 1, "/", None);
+    client.send_frame(FRAME_DATA, 0, 1, b"12345678").unwrap();
+    client.send_frame(FRAME_DATA, 0, 1, b"12345678").unwrap();
+    assert_eq!(
+        rejection(&mut client, 1),
+        (Some(b"413".to_vec()), Some(NO_ERROR))
+    );
+}
+
+#[test]
+fn body_limit_can_reset_the_stream() {
+    let server = limited(BodyLimits::new().max(10).action(BodyLimitAction::Reset));
+    let mut client = server.client();
+    post(&mut client, 1, "/", None);
+    client.send_frame(FRAME_DATA, 0, 1, &[0; 20]).unwrap();
+    assert_eq!(rejection(&mut client, 1), (None, Some(CANCEL)));
+}
+
+#[test]
+fn body_limits_follow_routes() {
+    let server = limited(BodyLimits::new().max(4).route("/upload", Some(100)));
+    let mut client = server.client();
+    post(&mut client, 1, "/upload", None);
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"within the route")
+        .unwrap();
+    assert_eq!(client.response(1).unwrap().body, b"within the route");
+
+    post(&mut client, 3, "/", None);
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 3, b"over the default")
+        .unwrap();
+    assert_eq!(rejection(&mut client, 3), (Some(b"413".to_vec()), None));
+}
+
+#[test]
+fn body_shorter_than_content_length_is_malformed() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    post(&mut client, 1, "/", Some("10"));
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"short")
+        .unwrap();
+    assert_eq!(rejection(&mut client, 1), (None, Some(PROTOCOL_ERROR)));
+}
+
+#[test]
+fn body_longer_than_content_length_is_malformed() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    post(&mut client, 1, "/", Some("2"));
+    client.send_frame(FRAME_DATA, 0, 1, b"longer").unwrap();
+    assert_eq!(rejection(&mut client, 1), (None, Some(PROTOCOL_ERROR)));
+}
+
+#[test]
+fn differing_content_lengths_are_malformed() {
+    let server = TestServer::spawn(echo);
+    let mut client = server.client();
+    let mut headers = POST.to_vec();
+    headers.extend([("content-length", "4"), ("content-length", "5")]);
+    client
+        .send_frame(
+            FRAME_HEADERS,
+            FLAG_END_HEADERS,
+            1,
+            &encode_headers(&headers),
+        )
+        .unwrap();
+    assert_eq!(rejection(&mut client, 1), (None, Some(PROTOCOL_ERROR)));
+}
+
+#[test]
+fn matching_content_length_is_accepted() {
+    let server = limited(BodyLimits::new().max(5));
+    let mut client = server.client();
+    post(&mut client, 1, "/", Some("5"));
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"exact")
+        .unwrap();
+    assert_eq!(client.response(1).unwrap().body, b"exact");
+}
+
+#[test]
+fn connect_relays_data_sent_with_the_headers() {
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    let proxy = ConnectProxy::new()
+        .allowed_ports(&[port])
+        .allow_internal_addresses(true);
+    let server = TestServer::spawn(move |token, request| proxy.handle(token, request, &echo));
+    let mut client = server.client();
+
+    // The DATA frame arrives in the same read as the request, before the
+    // tunnel is opened.
+    let authority = format!("127.0.0.1:{}", port);
+    let headers = [(":method", "CONNECT"), (":authority", authority.as_str())];
+    let mut request = encode_frame(
+        FRAME_HEADERS,
+        FLAG_END_HEADERS,
+        1,
+        &encode_headers(&headers),
+    );
+    request.extend(encode_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"hello"));
+    client.send(&request).unwrap();
+
+    let (mut upstream, _) = upstream.accept().unwrap();
+    upstream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut received = Vec::new();
+    upstream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"hello");
+    upstream.write_all(b"world").unwrap();
+    drop(upstream);
+
+    let response = client.response(1).unwrap();
+    assert_eq!(response.status(), Some(&b"200"[..]));
+    assert_eq!(response.body, b"world");
+}
+
+#[test]
+fn reverse_proxy_relays_to_an_http1_upstream() {
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy =
+        ReverseProxy::new().upstream(upstream.local_addr().unwrap(), UpstreamProtocol::Http1);
+    let server = TestServer::spawn(proxy);
+    let mut client = server.client();
+    client
+        .send_frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &encode_headers(POST))
+        .unwrap();
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"hello")
+        .unwrap();
+
+    let (mut upstream, _) = upstream.accept().unwrap();
+    upstream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut received = Vec::new();
+    let mut chunk = [0u8; 4096];
+    while !received.ends_with(b"\r\n\r\nhello") {
+        let len = upstream.read(&mut chunk).unwrap();
+        assert_ne!(len, 0);
+        received.extend_from_slice(&chunk[..len]);
+    }
+    assert!(received.starts_with(b"POST / HTTP/1.1\r\n"));
+
+    // The server keeps serving while the upstream is silent.
+    client.ping(b"12345678").unwrap();
+    let ack = client.recv_until(|frame| frame.kind == FRAME_PING).unwrap();
+    assert!(ack.has_flag(FLAG_ACK));
+
+    upstream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nx-upstream: 1\r\n\r\n\
+              5\r\nworld\r\n0\r\n\r\n",
+        )
+        .unwrap();
+    drop(upstream);
+    let response = client.response(1).unwrap();
+    assert_eq!(response.status(), Some(&b"200"[..]));
+    assert_eq!(response.header("x-upstream"), Some(&b"1"[..]));
+    assert_eq!(response.body, b"world");
+}
+
+#[test]
+fn reverse_proxy_relays_to_an_http2_upstream() {
+    let upstream = TestServer::spawn(|token, request: Request<Vec<u8>>| {
+        let te = request.headers().get("te").cloned();
+        let mut response = echo(token, request);
+        if let Some(te) = te {
+            response.headers_mut().insert("x-te", te);
+        }
+        response
+    });
+    let proxy = ReverseProxy::new().upstream(upstream.addr(), UpstreamProtocol::Http2);
+    let server = TestServer::spawn(proxy);
+    let mut client = server.client();
+    let mut headers = POST.to_vec();
+    headers.push(("te", "trailers"));
+    client
+        .send_frame(
+            FRAME_HEADERS,
+            FLAG_END_HEADERS,
+            1,
+            &encode_headers(&headers),
+        )
+        .unwrap();
+    client.send_frame(FRAME_DATA, 0, 1, b"hello ").unwrap();
+    client
+        .send_frame(FRAME_DATA, FLAG_END_STREAM, 1, b"world")
+        .unwrap();
+    let response = client.response(1).unwrap();
+    assert_eq!(response.status(), Some(&b"200"[..]));
+    assert_eq!(response.header("x-method"), Some(&b"POST"[..]));
+    assert_eq!(response.header("x-te"), Some(&b"trailers"[..]));
+    assert_eq!(response.body, b"hello world");
+}
+
+#[test]
+fn unix_socket_requests_carry_peer_credentials() {
+    let path = std::env::temp_dir().join(format!("khttp-unix-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (handle_sender, handle) = mpsc::channel();
+    let server_path = path.clone();
+    let thread = thread::spawn(move || {
+        let mut server = Http2Server::bind_unix(server_path)?;
+        let _ = handle_sender.send(server.shutdown_handle());
+        server.serve(|_token, request: Request<Vec<u8>>| {
+            let credentials = request.extensions().get::<PeerCredentials>().copied();
+            let mut response = Response::builder();
+            if let Some(credentials) = credentials {
+                response = response
+                    .header("x-uid", credentials.uid)
+                    .header("x-gid", credentials.gid);
+            }
+            response.body(Vec::new()).unwrap()
+        })
+    });
+
+    let shutdown = handle.recv().unwrap();
+    let mut client = Client::handshake_unix(&path).unwrap();
+    client.get(1).unwrap();
+    let response = client.response(1).unwrap();
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    assert_eq!(response.header("x-uid"), Some(uid.to_string().as_bytes()));
+    assert_eq!(response.header("x-gid"), Some(gid.to_string().as_bytes()));
+
+    shutdown.shutdown();
+    thread.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn each_listener_is_served_by_its_own_handler() {
+    let admin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    admin.set_nonblocking(true).unwrap();
+    let admin_addr = admin.local_addr().unwrap();
+    let server = TestServer::spawn_with(echo, move |server| {
+        let admin = mio::net::TcpListener::from_std(admin);
+        let index = server
+            .add_listener_with_handler(admin, |token, _request| {
+                Response::builder()
+                    .header("x-admin", "true")
+                    .header("x-listener", Http2Server::listener_index(token).unwrap())
+                    .body(Vec::new())
+                    .unwrap()
+            })
+            .unwrap();
+        assert_eq!(index, 1);
+    });
+
+    let mut client = server.client();
+    client.get(1).unwrap();
+    let response = client.response(1).unwrap();
+    assert_eq!(response.header("x-method"), Some(&b"GET"[..]));
+    assert_eq!(response.header("x-admin"), None);
+
+    let mut client = Client::handshake(admin_addr, &[]).unwrap();
+    client.get(1).unwrap();
+    let response = client.response(1).unwrap();
+    assert_eq!(response.header("x-admin"), Some(&b"true"[..]));
+    assert_eq!(response.header("x-listener"), Some(&b"1"[..]));
+    assert_eq!(response.header("x-method"), None);
+}